@@ -1,15 +1,35 @@
 use anyhow::Error;
-use clap::Parser;
-use cln::cln;
+use clap::{Parser, Subcommand};
+use cln::{
+    cat_file, cln_all, cln_locked, dedup_stats, doctor, ensure_git, evict_store, export_store,
+    import_store, migrate_store, prune_repo, read_metadata, store_path, verify_store, warm_store,
+    warm_store_all_branches, Builder, CloneProgress, DirObjectCache, Error as ClnError,
+    FilesystemDiagnosis, HttpObjectCache, OutputFormat, OverwritePolicy, Reference, STORE_VERSION,
+};
+use std::io::{stdin, stdout, BufRead, Write};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 /// Git clone client with a little bit of linking
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct ClnArgs {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Repo to clone
-    #[arg()]
-    repo: String,
+    #[arg(required_unless_present = "stdin")]
+    repo: Option<String>,
 
     /// Directory to clone into
     #[arg()]
@@ -18,23 +38,713 @@ struct ClnArgs {
     /// Branch to checkout
     #[arg(short, long)]
     branch: Option<String>,
+
+    /// Treat `--branch` as a glob, e.g. `v1.*`, and checkout the matching branch or tag with the highest version
+    #[arg(long, requires = "branch")]
+    glob: bool,
+
+    /// Experimental: also write the objects of the checkout to `objects/` in the store as git loose objects, so it can be used as a git alternate
+    #[arg(long)]
+    loose_objects: bool,
+
+    /// Fail on files larger than this many bytes, before they're fetched
+    #[arg(long, value_name = "BYTES")]
+    max_file_size: Option<u64>,
+
+    /// With `--max-file-size`, leave files that are too large out of the directory with a warning, rather than failing
+    #[arg(long, requires = "max_file_size")]
+    skip_large: bool,
+
+    /// Don't use the store at all, writing files straight into the directory, for one-off clones where caching objects would waste disk
+    #[arg(long, conflicts_with_all = ["store_read_only", "namespace_store", "max_store_bytes", "loose_objects"])]
+    no_store: bool,
+
+    /// Leave files and directories matching this glob out of the directory, e.g. `test/fixtures/**` or `*.mp4`. Can be given more than once
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Full reference to checkout, matched exactly, e.g. `refs/pull/123/head` for a pull request
+    #[arg(long = "ref", value_name = "REF", conflicts_with = "branch")]
+    git_ref: Option<String>,
+
+    /// Read newline-delimited `repo [dir] [branch]` lines from stdin, and clone them all concurrently
+    #[arg(long, conflicts_with_all = ["repo", "dir", "branch", "git_ref"])]
+    stdin: bool,
+
+    /// Create directories for subtrees even if they contain no files
+    #[arg(long)]
+    preserve_empty_dirs: bool,
+
+    /// Keep going when a file fails to check out, reporting every failure at the end
+    #[arg(long)]
+    continue_on_error: bool,
+
+    /// Local clone of the repo to read objects from instead of cloning over the network
+    #[arg(long)]
+    reference: Option<PathBuf>,
+
+    /// Another cln-store to seed the store from before reading from the repo. Can be given more than once
+    #[arg(long)]
+    reference_store: Vec<PathBuf>,
+
+    /// Re-hash every file in the store before linking it, to detect a corrupted store
+    #[arg(long)]
+    verify_on_link: bool,
+
+    /// Write a CSV manifest of every checked out file, with its blob hash and mode, to this path
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Directory to create the temporary bare clone in, instead of the system temp directory
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+
+    /// Base directory that a relative (or derived) clone directory is resolved against, e.g. `vendor` for `vendor/<repo>`
+    #[arg(long, visible_alias = "prefix")]
+    output_dir: Option<PathBuf>,
+
+    /// Check out into a temporary directory and only move it into place once it's complete
+    #[arg(long)]
+    atomic: bool,
+
+    /// With `--atomic`, replace the directory if it already exists
+    #[arg(long, requires = "atomic")]
+    force: bool,
+
+    /// Drop this many leading directories from the path of every file, like `tar --strip-components`
+    #[arg(long, default_value_t = 0)]
+    strip_components: usize,
+
+    /// Also check the files out into this directory, in the same walk as the directory. Can be given more than once
+    #[arg(long, value_name = "DIR", conflicts_with = "stdin")]
+    mirror_to: Vec<PathBuf>,
+
+    /// Only check out the files under this directory of the repository, as the root of the directory, e.g. `src`
+    #[arg(long, value_name = "PATH")]
+    strip_prefix: Option<PathBuf>,
+
+    /// Check the files out under this subdirectory of the directory, e.g. `vendor/cln`
+    #[arg(long, value_name = "PATH")]
+    add_prefix: Option<PathBuf>,
+
+    /// Without `--branch`, check out the default branch of the repo by name, rather than `HEAD`
+    #[arg(long, conflicts_with = "branch")]
+    follow_default_branch: bool,
+
+    /// Keep the objects of the repo in its own namespace of the store, trading deduplication across repos for isolation
+    #[arg(long)]
+    namespace_store: bool,
+
+    /// Record the repo, ref and resolved commit in `.cln/HEAD` in the directory, to be read back with `cln info`
+    #[arg(long)]
+    write_metadata: bool,
+
+    /// Copy files into the directory writable, rather than hard linking them read-only from the store. Editing a hard linked file in place edits every checkout of it
+    #[arg(long, visible_alias = "no-hardlink", conflicts_with = "readonly")]
+    writable: bool,
+
+    /// Copy files with this extension into the directory writable, e.g. `toml`, and hard link the rest. Can be given more than once
+    #[arg(long, value_name = "EXTENSION", conflicts_with = "writable")]
+    writable_ext: Vec<String>,
+
+    /// Hard link files read-only from the store, sharing them with every other checkout. This is the default
+    #[arg(long)]
+    readonly: bool,
+
+    /// Record the repo and the commit it resolved to in this lockfile, e.g. `cln.lock`, to be checked out again with `cln locked`
+    #[arg(long, value_name = "LOCKFILE")]
+    write_lock: Option<PathBuf>,
+
+    /// Shell command to run in the directory after a successful checkout
+    #[arg(long)]
+    post_checkout: Option<String>,
+
+    /// Remove files in the directory that aren't in the checked out tree
+    #[arg(long)]
+    sync: bool,
+
+    /// What to write into the directory: `tree` for just the files, or `git` to also write a shallow `.git`
+    #[arg(long, default_value = "tree")]
+    output_format: OutputFormat,
+
+    /// Octal mode to set on directories created in the directory, e.g. 755, rather than leaving it to the umask
+    #[arg(long, value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Partial clone filter for the temporary clone, e.g. `blob:none`, so that only the blobs being checked out are downloaded
+    #[arg(long)]
+    filter: Option<String>,
+
+    /// Never write to the store, e.g. a cache mounted read-only. Objects missing from it are fetched without being cached
+    #[arg(long)]
+    store_read_only: bool,
+
+    /// Evict the least recently used objects from the store after the checkout, until it's at most this many bytes (defaults to `$CLN_MAX_STORE_BYTES`)
+    #[arg(long)]
+    max_store_bytes: Option<u64>,
+
+    /// Object cache shared between machines to read missing blobs from before the repo, and write them to: an `http(s)://` URL or a directory. Can be given more than once
+    #[arg(long)]
+    object_cache: Vec<String>,
+
+    /// Mirror of the repo to clone from if it, and the mirrors before it, can't be reached. Can be given more than once, or as a comma-separated list
+    #[arg(long, value_delimiter = ',')]
+    mirror: Vec<String>,
+
+    /// Show the progress of the clone on stderr, when the checkout isn't already in the store
+    #[arg(long, conflicts_with = "stdin")]
+    progress: bool,
+
+    /// Clone every branch of the repo, rather than only the one being checked out
+    #[arg(long)]
+    no_single_branch: bool,
+
+    /// Number of objects to write, and threads to parse git output with, at once
+    #[arg(short, long)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Check out one object at a time in path order, so that every run does and logs the same steps in the same order
+    #[arg(long, conflicts_with = "jobs")]
+    deterministic: bool,
+
+    /// Leave out files and directories marked `export-ignore` in `.gitattributes`, like `git archive`
+    #[arg(long)]
+    export_ignore: bool,
+
+    /// Git config to pass to every git command as `-c key=value`, e.g. to tune transfers. Can be given more than once
+    #[arg(short = 'c', long = "config", value_name = "KEY=VALUE", value_parser = parse_git_config)]
+    git_config: Vec<(String, String)>,
+
+    /// Path to the git binary to use (defaults to `$GIT_BINARY`, then `git` on the `PATH`)
+    #[arg(long, global = true)]
+    git_binary: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Populate the cln-store with a repo without checking it out
+    Warm {
+        /// Repo to warm the store with
+        #[arg()]
+        repo: String,
+
+        /// Branch to warm the store with
+        #[arg(short, long)]
+        branch: Option<String>,
+
+        /// Warm the store with every branch in one clone, downloading the latest commit of each of them
+        #[arg(long, conflicts_with = "branch")]
+        all_branches: bool,
+    },
+    /// Print the contents of a file in a repo without checking it out
+    Cat {
+        /// Repo to read the file from
+        #[arg()]
+        repo: String,
+
+        /// Path of the file in the repo
+        #[arg()]
+        path: PathBuf,
+
+        /// Branch to read the file from
+        #[arg(short, long)]
+        branch: Option<String>,
+    },
+    /// Diagnose common problems with the cln-store
+    Doctor,
+    /// Check out every repo in a lockfile written with `--write-lock` at the commit it's pinned to
+    Locked {
+        /// Lockfile to read, e.g. `cln.lock`
+        #[arg()]
+        lockfile: PathBuf,
+    },
+    /// Print what was checked out into a directory cloned with `--write-metadata`
+    Info {
+        /// Directory to read the metadata of
+        #[arg()]
+        dir: PathBuf,
+    },
+    /// Inspect the cln-store
+    Store {
+        #[command(subcommand)]
+        command: StoreCommands,
+    },
 }
 
+#[derive(Subcommand)]
+enum StoreCommands {
+    /// Print the directory used as the cln-store
+    Path,
+    /// Report how much disk is saved by hard linking objects in the cln-store into checkouts
+    DedupStats,
+    /// Re-hash every object in the cln-store, printing those that don't match their hash
+    Verify,
+    /// Write the cln-store to stdout as a tar archive
+    Export,
+    /// Add the objects in a tar archive written by `cln store export` on stdin to the cln-store
+    Import,
+    /// Remove the least recently used objects from the cln-store until it's at most `max_bytes`
+    Evict {
+        /// Size in bytes to shrink the cln-store to
+        max_bytes: u64,
+    },
+    /// Upgrade a cln-store written by an older version of cln to the current layout
+    Migrate,
+    /// Remove the objects of one repo from the cln-store, keeping those also reachable from other commits
+    Prune {
+        /// Commit of the checkout to prune, as printed by `cln info`
+        hash: String,
+        /// Commits whose objects are kept, e.g. of other projects sharing the store
+        #[arg(long, value_name = "HASH")]
+        keep: Vec<String>,
+    },
+}
+
+// Exit codes for each kind of failure, so that scripts can tell them apart.
+// Clap exits with 2 on invalid arguments, and anything uncategorized exits with 1.
+const EXIT_GIT_NOT_FOUND: u8 = 3;
+const EXIT_CLONE_FAILED: u8 = 4;
+const EXIT_REFERENCE_NOT_FOUND: u8 = 5;
+const EXIT_STORE_FAILED: u8 = 6;
+const EXIT_TARGET_FAILED: u8 = 7;
+const EXIT_PARTIAL_CHECKOUT: u8 = 8;
+const EXIT_POST_CHECKOUT_FAILED: u8 = 9;
+
 #[tokio::main]
-async fn main() -> Result<(), Error> {
-    env_logger::init();
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn exit_code(e: &Error) -> u8 {
+    let Some(e) = e.downcast_ref::<ClnError>() else {
+        return 1;
+    };
+
+    match e {
+        ClnError::GitNotFound(_) | ClnError::CurlNotFound => EXIT_GIT_NOT_FOUND,
+        ClnError::CommandSpawnError(_)
+        | ClnError::GitCloneError(_)
+        | ClnError::LsRemoteError(_)
+        | ClnError::ReadObjectError(_) => EXIT_CLONE_FAILED,
+        ClnError::InvalidRefError(_)
+        | ClnError::AbbreviatedCommitError(_)
+        | ClnError::NoMatchingReferenceError
+        | ClnError::NoMatchingPatternError(_)
+        | ClnError::UnknownReferenceError { .. }
+        | ClnError::PathNotFoundError(_) => EXIT_REFERENCE_NOT_FOUND,
+        ClnError::CreateDirError(_)
+        | ClnError::HomeDirError
+        | ClnError::WriteToStoreError(..)
+        | ClnError::ReadStoreError(_)
+        | ClnError::StoreCorruption { .. }
+        | ClnError::NotInStoreError(_)
+        | ClnError::ExportStoreError(_)
+        | ClnError::ImportStoreError(_)
+        | ClnError::EvictError(_)
+        | ClnError::PruneError(_)
+        | ClnError::OutdatedStoreError { .. }
+        | ClnError::UnsupportedStoreError { .. }
+        | ClnError::InvalidStoreVersionError(..)
+        | ClnError::MigrateStoreError(_)
+        | ClnError::LooseObjectError(_) => EXIT_STORE_FAILED,
+        ClnError::CreateDirAllError(_)
+        | ClnError::HardLinkError(_)
+        | ClnError::CopyObjectError(_)
+        | ClnError::TargetExistsError(_)
+        | ClnError::TargetMismatchError { .. }
+        | ClnError::ReplaceTargetError(_)
+        | ClnError::CleanError(_)
+        | ClnError::FileTooLargeError { .. }
+        | ClnError::CaseCollisionError { .. } => EXIT_TARGET_FAILED,
+        ClnError::PartialCheckoutError(_) => EXIT_PARTIAL_CHECKOUT,
+        ClnError::PostCheckoutFailed(_) => EXIT_POST_CHECKOUT_FAILED,
+        _ => 1,
+    }
+}
+
+async fn run() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
 
     let args = ClnArgs::parse();
 
-    let dir = args.dir;
-    let branch = args.branch;
-    let repo = args.repo;
+    if let Some(command) = args.command {
+        return run_command(command, args.git_binary).await;
+    }
+
+    ensure_git(args.git_binary.clone()).await?;
+
+    if args.stdin {
+        return cln_stdin(&args).await;
+    }
+
+    let mut builder = builder(
+        &args,
+        &args
+            .repo
+            .clone()
+            .expect("Repo is required when no subcommand is given"),
+    );
+    if let Some(dir) = args.dir {
+        builder = builder.dir(dir);
+    }
+    if let Some(branch) = args.branch {
+        builder = builder.branch(&branch);
+    }
+    if let Some(git_ref) = args.git_ref {
+        builder = builder.reference(Reference::Ref(git_ref));
+    }
+
+    if !args.progress {
+        builder.cln().await?;
+        return Ok(());
+    }
+
+    let (sender, receiver) = unbounded_channel();
+    let (result, ()) = tokio::join!(builder.progress(sender).cln(), print_progress(receiver));
+    result?;
+
+    Ok(())
+}
+
+// Prints each phase of the clone on one line of stderr, redrawn as it progresses, until the clone is done.
+async fn print_progress(mut receiver: UnboundedReceiver<CloneProgress>) {
+    let mut last = None;
+    while let Some(progress) = receiver.recv().await {
+        if last.as_ref() == Some(&progress) {
+            continue;
+        }
+        eprint!("\r{}: {}%", progress.phase, progress.percent);
+        if progress.percent == 100 {
+            eprintln!();
+        }
+        last = Some(progress);
+    }
+}
+
+// Builder for a repo with every option that's shared between the repos of a `--stdin` batch.
+fn builder(args: &ClnArgs, repo: &str) -> Builder {
+    let mut builder = Builder::new(repo)
+        .preserve_empty_dirs(args.preserve_empty_dirs)
+        .output_format(args.output_format)
+        .continue_on_error(args.continue_on_error)
+        .verify_on_link(args.verify_on_link)
+        .atomic(args.atomic)
+        .force(args.force)
+        .strip_components(args.strip_components)
+        .write_metadata(args.write_metadata)
+        .branch_glob(args.glob)
+        .loose_objects(args.loose_objects)
+        .skip_large_files(args.skip_large)
+        .no_store(args.no_store)
+        .writable(args.writable)
+        .deterministic(args.deterministic)
+        .export_ignore(args.export_ignore)
+        .store_read_only(args.store_read_only)
+        .follow_default_branch(args.follow_default_branch)
+        .namespace_store(args.namespace_store)
+        .single_branch(!args.no_single_branch);
+    if let Some(reference) = &args.reference {
+        builder = builder.reference_repo(reference.clone());
+    }
+    for reference_store in &args.reference_store {
+        builder = builder.reference_store(reference_store.clone());
+    }
+    if let Some(manifest) = &args.manifest {
+        builder = builder.manifest(manifest.clone());
+    }
+    if !args.writable_ext.is_empty() {
+        let extensions = args.writable_ext.clone();
+        builder = builder.writable_if(move |entry| {
+            entry.path().extension().is_some_and(|extension| {
+                extensions
+                    .iter()
+                    .any(|writable| extension == writable.trim_start_matches('.'))
+            })
+        });
+    }
+    if let Some(write_lock) = &args.write_lock {
+        builder = builder.write_lock(write_lock.clone());
+    }
+    if let Some(temp_dir) = &args.temp_dir {
+        builder = builder.temp_dir(temp_dir.clone());
+    }
+    if let Some(output_dir) = &args.output_dir {
+        builder = builder.output_base(output_dir.clone());
+    }
+    if let Some(post_checkout) = &args.post_checkout {
+        builder = builder.post_checkout(post_checkout);
+    }
+    if let Some(dir_mode) = args.dir_mode {
+        builder = builder.dir_mode(dir_mode);
+    }
+    if let Some(jobs) = args.jobs {
+        builder = builder.concurrency(jobs.get());
+    }
+    for mirror in &args.mirror {
+        builder = builder.mirror(mirror);
+    }
+    for object_cache in &args.object_cache {
+        builder = if object_cache.starts_with("http://") || object_cache.starts_with("https://") {
+            builder.object_cache(HttpObjectCache::new(object_cache))
+        } else {
+            builder.object_cache(DirObjectCache::new(PathBuf::from(object_cache)))
+        };
+    }
+    for mirror_to in &args.mirror_to {
+        builder = builder.mirror_to(mirror_to.clone());
+    }
+    if let Some(strip_prefix) = &args.strip_prefix {
+        builder = builder.strip_prefix(strip_prefix.clone());
+    }
+    if let Some(add_prefix) = &args.add_prefix {
+        builder = builder.add_prefix(add_prefix.clone());
+    }
+    for pattern in &args.exclude {
+        builder = builder.exclude(pattern);
+    }
+    if let Some(max_file_size) = args.max_file_size {
+        builder = builder.max_file_size(max_file_size);
+    }
+    if let Some(max_store_bytes) = args.max_store_bytes {
+        builder = builder.max_store_bytes(max_store_bytes);
+    }
+    if let Some(filter) = &args.filter {
+        builder = builder.clone_filter(filter);
+    }
+    for (key, value) in &args.git_config {
+        builder = builder.git_config(key, value);
+    }
+    if args.sync {
+        builder = builder.overwrite_policy(OverwritePolicy::Sync);
+    }
+
+    builder
+}
+
+// Clones every `repo [dir] [branch]` line on stdin, printing a result per line.
+// Malformed lines and failed clones are reported without stopping the others.
+async fn cln_stdin(args: &ClnArgs) -> Result<(), Error> {
+    let mut lines = Vec::new();
+    let mut builders = Vec::new();
+    let mut failures = 0;
+    for line in stdin().lock().lines() {
+        let line = line?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields.as_slice() {
+            [] => {}
+            [repo, rest @ ..] if rest.len() <= 2 => {
+                let mut builder = builder(args, repo);
+                if let Some(dir) = rest.first() {
+                    builder = builder.dir(PathBuf::from(dir));
+                }
+                if let Some(branch) = rest.get(1) {
+                    builder = builder.branch(branch);
+                }
+                lines.push(line);
+                builders.push(builder);
+            }
+            _ => {
+                failures += 1;
+                println!("{line}: error: expected `repo [dir] [branch]`");
+            }
+        }
+    }
+
+    for (line, result) in lines.iter().zip(cln_all(builders).await) {
+        match result {
+            Ok(()) => println!("{line}: ok"),
+            Err(e) => {
+                failures += 1;
+                println!("{line}: error: {e}");
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(Error::msg(format!("{failures} lines failed")));
+    }
+
+    Ok(())
+}
+
+async fn run_command(command: Commands, git_binary: Option<PathBuf>) -> Result<(), Error> {
+    // The doctor reports on git rather than failing without it.
+    if !matches!(command, Commands::Doctor) {
+        ensure_git(git_binary.clone()).await?;
+    }
+
+    match command {
+        Commands::Warm {
+            repo,
+            all_branches: true,
+            ..
+        } => {
+            warm_store_all_branches(&repo, None).await?;
+        }
+        Commands::Warm { repo, branch, .. } => {
+            warm_store(&repo, branch.as_deref(), None).await?;
+        }
+        Commands::Cat { repo, path, branch } => {
+            let content = cat_file(&repo, branch.as_deref(), &path, None).await?;
+            stdout().write_all(&content)?;
+        }
+        Commands::Store { command } => run_store_command(command).await?,
+        Commands::Locked { lockfile } => cln_locked(&lockfile, None).await?,
+        Commands::Info { dir } => {
+            let metadata = read_metadata(&dir).await?;
+
+            println!("repo: {}", metadata.repo);
+            println!("ref: {}", metadata.reference);
+            println!("hash: {}", metadata.hash);
+            if let Some(default_branch) = &metadata.default_branch {
+                println!("default branch: {default_branch}");
+            }
+            if let Some(mirror) = &metadata.mirror {
+                println!("mirror: {mirror}");
+            }
+        }
+        Commands::Doctor => {
+            let diagnosis = doctor(None, git_binary).await?;
+
+            match &diagnosis.git_version {
+                Some(version) if diagnosis.git_supported() => println!("git: ok, {version}"),
+                Some(version) => {
+                    println!("git: fail, {version} is older than the oldest git cln supports");
+                }
+                None => println!(
+                    "git: fail, git cannot be run. Install git, or point cln at it with --git-binary or GIT_BINARY"
+                ),
+            }
+            match &diagnosis.curl_version {
+                Some(version) => println!("curl: ok, {version}"),
+                None => println!(
+                    "curl: fail, curl cannot be run. Install curl to use an HTTP object cache"
+                ),
+            }
+            print_filesystem("store", &diagnosis.store);
+            match diagnosis.store_writable {
+                Some(true) => println!("store writable: ok"),
+                Some(false) => println!("store writable: fail, files cannot be created in the store. Set CLN_STORE_PATH to a writable directory, or use --store-read-only"),
+                None => println!("store writable: ok, the store will be created on the first cln"),
+            }
+            print_filesystem("current dir", &diagnosis.current_dir);
+            if diagnosis.same_device() {
+                println!("hard links: ok, the store and current dir are on the same filesystem");
+            } else {
+                println!("hard links: fail, the store and current dir are on different filesystems, so files are copied rather than linked. Set CLN_STORE_PATH to a directory on the same filesystem");
+            }
+            println!("store objects: {}", diagnosis.objects);
+            println!(
+                "spot-checked objects: {}, corrupt: {}",
+                diagnosis.checked_objects,
+                diagnosis.corrupt_objects.len()
+            );
+            for hash in &diagnosis.corrupt_objects {
+                println!("corrupt object: {hash}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_store_command(command: StoreCommands) -> Result<(), Error> {
+    match command {
+        StoreCommands::Path => {
+            println!("{}", store_path(None).await?.display());
+        }
+        StoreCommands::DedupStats => {
+            let stats = dedup_stats(None).await?;
+
+            println!("objects: {}", stats.objects);
+            println!("stored bytes: {}", stats.stored_bytes);
+            println!("bytes as full copies: {}", stats.checkout_bytes);
+            println!("bytes saved: {}", stats.saved_bytes());
+            for (checkouts, (objects, bytes)) in &stats.by_checkouts {
+                println!("linked into {checkouts} checkouts: {objects} objects, {bytes} bytes");
+            }
+        }
+        StoreCommands::Verify => {
+            let (checked, corrupt) = verify_store(None, usize::MAX).await?;
+
+            println!("checked objects: {checked}");
+            for hash in &corrupt {
+                println!("corrupt object: {hash}");
+            }
+            if let Some(hash) = corrupt.into_iter().next() {
+                return Err(ClnError::StoreCorruption { hash }.into());
+            }
+        }
+        StoreCommands::Export => {
+            export_store(None, stdout()).await?;
+        }
+        StoreCommands::Import => {
+            let (imported, skipped) = import_store(None, stdin()).await?;
+
+            println!("imported objects: {imported}");
+            for path in &skipped {
+                println!("skipped invalid object: {path}");
+            }
+        }
+        StoreCommands::Evict { max_bytes } => {
+            let (evicted, evicted_bytes) = evict_store(None, max_bytes).await?;
 
-    cln(&repo, dir, branch.as_deref(), None).await?;
+            println!("evicted objects: {evicted}");
+            println!("evicted bytes: {evicted_bytes}");
+        }
+        StoreCommands::Migrate => {
+            let migrated = migrate_store(None).await?;
+
+            println!("migrated objects: {migrated}");
+            println!("store version: {STORE_VERSION}");
+        }
+        StoreCommands::Prune { hash, keep } => {
+            let keep = keep.iter().map(String::as_str).collect::<Vec<_>>();
+            let (pruned, pruned_bytes) = prune_repo(&hash, &keep, None).await?;
+
+            println!("pruned objects: {pruned}");
+            println!("pruned bytes: {pruned_bytes}");
+        }
+    }
 
     Ok(())
 }
 
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("expected an octal mode like 755, got `{mode}`"))
+}
+
+fn parse_git_config(config: &str) -> Result<(String, String), String> {
+    match config.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected `key=value`, got `{config}`")),
+    }
+}
+
+fn print_filesystem(name: &str, filesystem: &FilesystemDiagnosis) {
+    println!("{name}: {}", filesystem.path.display());
+    if filesystem.device.is_none() {
+        println!("{name} exists: no");
+    }
+    match filesystem.available_bytes {
+        Some(available_bytes) => println!("{name} free space: {available_bytes} bytes"),
+        None => println!("{name} free space: unknown"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate assert_cmd;
@@ -42,6 +752,9 @@ mod tests {
 
     use self::assert_cmd::Command;
     use self::tempfile::{Builder, TempDir};
+    use std::fs::Permissions;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
 
     fn create_temp_dir() -> TempDir {
         Builder::new()
@@ -54,14 +767,76 @@ mod tests {
         Command::cargo_bin("cln").expect("Error invoking cln")
     }
 
+    fn path_str(path: &Path) -> &str {
+        path.to_str()
+            .expect("Failed to convert path to string. Check the test setup.")
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        Command::new("git")
+            .args(["-c", "user.name=cln", "-c", "user.email=cln@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .assert()
+            .success();
+    }
+
+    // A bare repo with a nested tree, an executable and a symlink, so tests can clone it without the network.
+    fn create_fixture_repo() -> TempDir {
+        let work = create_temp_dir();
+        let work_path = work.path();
+        std::fs::create_dir_all(work_path.join("src").join("nested"))
+            .expect("Failed to create fixture directories");
+        std::fs::write(work_path.join("README.md"), "# Fixture\n").expect("Failed to write README");
+        std::fs::write(work_path.join("src").join("main.rs"), "fn main() {}\n")
+            .expect("Failed to write main.rs");
+        std::fs::write(
+            work_path.join("src").join("nested").join("file.txt"),
+            "nested\n",
+        )
+        .expect("Failed to write nested file");
+        std::fs::write(work_path.join("run.sh"), "#!/bin/sh\n").expect("Failed to write run.sh");
+        std::fs::set_permissions(work_path.join("run.sh"), Permissions::from_mode(0o755))
+            .expect("Failed to make run.sh executable");
+        symlink("README.md", work_path.join("LINK.md")).expect("Failed to create symlink");
+        git(work_path, &["init", "--quiet", "--initial-branch", "main"]);
+        git(work_path, &["add", "."]);
+        git(
+            work_path,
+            &["commit", "--quiet", "--message", "Initial commit"],
+        );
+
+        let bare = create_temp_dir();
+        git(
+            bare.path(),
+            &["clone", "--quiet", "--bare", path_str(work_path), "."],
+        );
+        bare
+    }
+
+    #[test]
+    fn test_exit_code_git_not_found() {
+        cln()
+            .args([
+                "--git-binary",
+                "/nonexistent/git",
+                "https://github.com/lua/lua.git",
+            ])
+            .assert()
+            .code(3);
+    }
+
     #[test]
     fn test_cln_and_git_clone_are_equivalent() {
-        let repo = "https://github.com/lua/lua.git";
+        let fixture = create_fixture_repo();
+        let repo = path_str(fixture.path());
 
+        let store = create_temp_dir();
         let cln_dir = create_temp_dir();
         let git_dir = create_temp_dir();
 
         cln()
+            .env("CLN_STORE_PATH", store.path())
             .args([
                 repo,
                 cln_dir
@@ -113,4 +888,34 @@ mod tests {
         cln_dir.close().expect("Failed to close cln_dir");
         git_dir.close().expect("Failed to close git_dir");
     }
+
+    #[test]
+    fn test_store_verify() {
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir();
+        let dir = create_temp_dir();
+
+        cln()
+            .env("CLN_STORE_PATH", store.path())
+            .args([path_str(fixture.path()), path_str(dir.path())])
+            .assert()
+            .success();
+        cln()
+            .env("CLN_STORE_PATH", store.path())
+            .args(["store", "verify"])
+            .assert()
+            .success();
+
+        let corrupt = "1d6a3d1b4e0e31cd4e37e4a4b3d1c3cbd1b0a9f5";
+        std::fs::write(store.path().join(corrupt), "tampered\n").expect("Failed to write object");
+        let output = cln()
+            .env("CLN_STORE_PATH", store.path())
+            .args(["store", "verify"])
+            .assert()
+            .code(6)
+            .get_output()
+            .stdout
+            .clone();
+        assert!(String::from_utf8_lossy(&output).contains(&format!("corrupt object: {corrupt}\n")));
+    }
 }
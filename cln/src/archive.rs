@@ -0,0 +1,258 @@
+use crate::git::ensure_git;
+use crate::store::{
+    ensure_cln_store_path, hash_blob, is_tree_listing, stored_files, NAMESPACES_DIR,
+};
+use crate::{close_temp_dir, Error};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Builder as TarBuilder};
+use tempfile::Builder as TempBuilder;
+use tokio::fs::{create_dir_all, read, rename};
+use tokio::task::spawn_blocking;
+
+/// Writes the cln-store to `writer` as a tar archive, to be restored with `import_store`, e.g. on another machine.
+///
+/// Objects keep their read-only permissions in the archive, and objects that are still being written are left out.
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be read, or the archive cannot be written.
+pub async fn export_store<W>(store_path: Option<PathBuf>, writer: W) -> Result<(), Error>
+where
+    W: Write + Send + 'static,
+{
+    let store_path = ensure_cln_store_path(store_path).await?;
+
+    spawn_blocking(move || {
+        let mut archive = TarBuilder::new(writer);
+        archive.follow_symlinks(false);
+        for path in stored_files(&store_path)? {
+            let name = path.strip_prefix(&store_path).unwrap_or(&path);
+            archive.append_path_with_name(&path, name)?;
+        }
+        archive.into_inner()?.flush()
+    })
+    .await
+    .map_err(|e| Error::ExportStoreError(io::Error::other(e)))?
+    .map_err(Error::ExportStoreError)
+}
+
+/// Restores a cln-store archived by `export_store` from `reader` into the cln-store.
+///
+/// Every object is checked before it's added: blobs must hash to their name, and tree listings must be well formed.
+/// Objects that fail the check, or aren't cln-store objects at all, are skipped.
+/// Objects that are already in the cln-store are left as they are.
+///
+/// Returns how many objects were imported, and the paths in the archive of those that were skipped.
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run to check the objects.
+/// - The archive cannot be read or unpacked.
+/// - An object cannot be moved into the cln-store.
+pub async fn import_store<R>(
+    store_path: Option<PathBuf>,
+    reader: R,
+) -> Result<(u64, Vec<String>), Error>
+where
+    R: Read + Send + 'static,
+{
+    ensure_git(None).await?;
+    let store_path = ensure_cln_store_path(store_path).await?;
+    create_dir_all(&store_path)
+        .await
+        .map_err(Error::CreateDirError)?;
+
+    // Unpacked into the cln-store itself, so that objects can be renamed into place.
+    let staging = TempBuilder::new()
+        .prefix(".cln-import")
+        .tempdir_in(&store_path)
+        .map_err(Error::TempDirError)?;
+    let staging_path = staging.path().to_path_buf();
+
+    let result = async {
+        let unpack_path = staging_path.clone();
+        spawn_blocking(move || Archive::new(reader).unpack(unpack_path))
+            .await
+            .map_err(|e| Error::ImportStoreError(io::Error::other(e)))?
+            .map_err(Error::ImportStoreError)?;
+
+        let mut imported = 0;
+        let mut skipped = vec![];
+        for path in stored_files(&staging_path).map_err(Error::ImportStoreError)? {
+            let name = path.strip_prefix(&staging_path).unwrap_or(&path);
+            if !is_object_path(name) || !is_valid_object(&path).await? {
+                skipped.push(name.display().to_string());
+                continue;
+            }
+
+            let content_path = store_path.join(name);
+            if content_path.exists() {
+                continue;
+            }
+            if let Some(parent) = content_path.parent() {
+                create_dir_all(parent)
+                    .await
+                    .map_err(Error::CreateDirError)?;
+            }
+            rename(&path, &content_path)
+                .await
+                .map_err(Error::ImportStoreError)?;
+            imported += 1;
+        }
+
+        Ok((imported, skipped))
+    }
+    .await;
+
+    close_temp_dir(staging, result).await
+}
+
+// Whether a path relative to the cln-store is where an object would be, at the root or in a namespace.
+fn is_object_path(path: &Path) -> bool {
+    let names = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+
+    match names.as_deref() {
+        Some([hash]) => is_hash(hash),
+        Some([NAMESPACES_DIR, namespace, hash]) => {
+            namespace.len() == 16 && is_hex(namespace) && is_hash(hash)
+        }
+        _ => false,
+    }
+}
+
+fn is_hash(name: &str) -> bool {
+    matches!(name.len(), 40 | 64) && is_hex(name)
+}
+
+fn is_hex(name: &str) -> bool {
+    name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Whether a file is a blob that hashes to its name, or a tree listing.
+async fn is_valid_object(path: &Path) -> Result<bool, Error> {
+    let hash = path.file_name().map(|name| name.to_string_lossy());
+    if hash_blob(path).await?.as_deref() == hash.as_deref() {
+        return Ok(true);
+    }
+
+    let content = read(path).await.map_err(Error::ImportStoreError)?;
+    Ok(is_tree_listing(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{format_tree_listing, STORE_TEST_LOCK};
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::Builder;
+
+    // `git hash-object` of "hello\n".
+    const HELLO_BLOB: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+    const TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+    fn create_store() -> tempfile::TempDir {
+        Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir")
+    }
+
+    #[tokio::test]
+    async fn test_export_import_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = create_store();
+        let blob_path = store.path().join(HELLO_BLOB);
+        std::fs::write(&blob_path, "hello\n").expect("Failed to write blob");
+        std::fs::set_permissions(&blob_path, std::fs::Permissions::from_mode(0o444))
+            .expect("Failed to make blob read-only");
+        std::fs::write(
+            store.path().join(TREE),
+            format_tree_listing(&format!("100644 blob {HELLO_BLOB}\thello.txt")),
+        )
+        .expect("Failed to write tree listing");
+        std::fs::write(store.path().join(".cln-tmp1234"), "partial").expect("Failed to write");
+
+        let archives = create_store();
+        let archive_path = archives.path().join("store.tar");
+        export_store(
+            Some(store.path().to_path_buf()),
+            File::create(&archive_path).expect("Failed to create archive"),
+        )
+        .await
+        .expect("Failed to export store");
+
+        let imported_store = create_store();
+        let (imported, skipped) = import_store(
+            Some(imported_store.path().to_path_buf()),
+            File::open(&archive_path).expect("Failed to open archive"),
+        )
+        .await
+        .expect("Failed to import store");
+
+        assert_eq!(imported, 2);
+        assert!(skipped.is_empty(), "{skipped:?}");
+        let imported_blob = imported_store.path().join(HELLO_BLOB);
+        assert_eq!(
+            std::fs::read_to_string(&imported_blob).expect("Failed to read imported blob"),
+            "hello\n"
+        );
+        assert!(imported_blob
+            .metadata()
+            .expect("Failed to read imported blob metadata")
+            .permissions()
+            .readonly());
+        assert!(imported_store.path().join(TREE).exists());
+        assert!(!imported_store.path().join(".cln-tmp1234").exists());
+    }
+
+    #[tokio::test]
+    async fn test_import_store_skips_invalid_objects() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let mut archive = TarBuilder::new(vec![]);
+        for (name, content) in [
+            (HELLO_BLOB.to_string(), "tampered\n"),
+            ("not-an-object".to_string(), "hello\n"),
+            (
+                format!("{NAMESPACES_DIR}/0123456789abcdef/{HELLO_BLOB}"),
+                "hello\n",
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o444);
+            archive
+                .append_data(&mut header, name, content.as_bytes())
+                .expect("Failed to append to archive");
+        }
+        let archive = archive.into_inner().expect("Failed to finish archive");
+
+        let store = create_store();
+        let (imported, mut skipped) =
+            import_store(Some(store.path().to_path_buf()), io::Cursor::new(archive))
+                .await
+                .expect("Failed to import store");
+        skipped.sort();
+
+        assert_eq!(imported, 1);
+        assert_eq!(
+            skipped,
+            vec![HELLO_BLOB.to_string(), "not-an-object".to_string()]
+        );
+        assert!(!store.path().join(HELLO_BLOB).exists());
+        assert!(store
+            .path()
+            .join(NAMESPACES_DIR)
+            .join("0123456789abcdef")
+            .join(HELLO_BLOB)
+            .exists());
+    }
+}
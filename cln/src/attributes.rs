@@ -0,0 +1,179 @@
+use globset::{GlobBuilder, GlobMatcher};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+// The file that attributes are read from, in any directory of a tree.
+pub const ATTRIBUTES_FILE: &str = ".gitattributes";
+
+const EXPORT_IGNORE: &str = "export-ignore";
+
+// A pattern in a `.gitattributes` file that sets or unsets `export-ignore`.
+#[derive(Debug, Clone)]
+struct Pattern {
+    // The directory the `.gitattributes` file is in, relative to the root of the tree.
+    base: PathBuf,
+    matcher: GlobMatcher,
+    // Patterns without a slash match the name of a path at any depth below `base`, rather than its whole path.
+    name_only: bool,
+    // Patterns with a trailing slash only match directories.
+    dir_only: bool,
+    set: bool,
+}
+
+impl Pattern {
+    fn parse(base: &Path, line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let mut fields = line.split_whitespace();
+        let pattern = fields.next()?;
+        // The last mention of the attribute on the line wins, `!` leaving it unspecified.
+        let set = fields.fold(None, |set, attribute| match attribute {
+            EXPORT_IGNORE => Some(true),
+            _ if attribute.strip_prefix(['-', '!']) == Some(EXPORT_IGNORE) => Some(false),
+            _ => set,
+        })?;
+        // Negative patterns aren't allowed in `.gitattributes`, and quoted ones aren't supported.
+        if pattern.starts_with(['!', '"']) {
+            debug!("Ignoring unsupported attributes pattern {pattern}");
+            return None;
+        }
+
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let name_only = !pattern.contains('/');
+        let matcher = GlobBuilder::new(pattern.trim_start_matches('/'))
+            .literal_separator(true)
+            .build()
+            .map_err(|e| debug!("Ignoring invalid attributes pattern {pattern}: {e}"))
+            .ok()?
+            .compile_matcher();
+
+        Some(Self {
+            base: base.to_path_buf(),
+            matcher,
+            name_only,
+            dir_only,
+            set,
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative_path) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+
+        if self.name_only {
+            relative_path
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        } else {
+            self.matcher.is_match(relative_path)
+        }
+    }
+}
+
+// The paths of a tree marked `export-ignore` by its `.gitattributes` files, which `git archive` leaves out.
+#[derive(Debug, Clone, Default)]
+pub struct ExportIgnore {
+    patterns: Vec<Pattern>,
+}
+
+impl ExportIgnore {
+    // Adds the patterns of the `.gitattributes` file in `base`, which take precedence over those already added,
+    // as files deeper in the tree do over those above them.
+    pub fn with_attributes(&self, base: &Path, attributes: &str) -> Self {
+        let mut patterns = self.patterns.clone();
+        patterns.extend(
+            attributes
+                .lines()
+                .filter_map(|line| Pattern::parse(base, line)),
+        );
+
+        Self { patterns }
+    }
+
+    // Whether `path`, relative to the root of the tree, is export-ignored, the last matching pattern winning.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+            .is_some_and(|pattern| pattern.set)
+    }
+}
+
+// Attributes that make git change the content of a file as it's checked out, which cln doesn't do.
+// Attributes with a value are matched by their name, e.g. `eol=crlf` by `eol`.
+const CHECKOUT_FILTERS: [&str; 4] = ["eol", "ident", "filter", "working-tree-encoding"];
+
+// The attributes set in a `.gitattributes` file that make git change the content of files as they're checked out,
+// e.g. `eol=crlf`, `ident` or `filter=lfs`, in the order they're first set.
+pub fn checkout_filters(attributes: &str) -> Vec<&str> {
+    let mut filters = vec![];
+    for attribute in attributes
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split_whitespace().skip(1))
+    {
+        let name = attribute
+            .split_once('=')
+            .map_or(attribute, |(name, _)| name);
+        if CHECKOUT_FILTERS.contains(&name) && !filters.contains(&attribute) {
+            filters.push(attribute);
+        }
+    }
+
+    filters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkout_filters() {
+        assert_eq!(
+            checkout_filters(
+                "# eol=crlf\n*.sh text eol=lf\n*.png filter=lfs diff=lfs merge=lfs -text\n*.c ident\n*.bat eol=crlf\n*.md -ident\n"
+            ),
+            vec!["eol=lf", "filter=lfs", "ident", "eol=crlf"]
+        );
+        assert!(checkout_filters("*.rs text diff=rust\n*.md export-ignore\n").is_empty());
+    }
+
+    #[test]
+    fn test_export_ignore() {
+        let export_ignore = ExportIgnore::default()
+            .with_attributes(
+                Path::new(""),
+                "# Not in releases\n*.md export-ignore\n/tests export-ignore\nbuild/ export-ignore\ndocs/** export-ignore\n*.rs text\n",
+            )
+            .with_attributes(Path::new("src"), "README.md -export-ignore\n");
+
+        for (path, is_dir, ignored) in [
+            ("README.md", false, true),
+            ("guide/intro.md", false, true),
+            ("src/README.md", false, false),
+            ("tests", true, true),
+            ("src/tests", true, false),
+            ("build", true, true),
+            ("build", false, false),
+            ("docs/api/index.html", false, true),
+            ("docs", true, false),
+            ("src/main.rs", false, false),
+        ] {
+            assert_eq!(
+                export_ignore.is_ignored(Path::new(path), is_dir),
+                ignored,
+                "{path}"
+            );
+        }
+    }
+}
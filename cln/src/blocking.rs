@@ -0,0 +1,107 @@
+use crate::{cln, Error};
+use std::path::PathBuf;
+use tokio::runtime::{Builder, Handle};
+
+/// Clns a git repository into a given directory, like `cln`, blocking until it's done.
+///
+/// This starts a current-thread tokio runtime for the checkout, for callers that don't run one of their own.
+///
+/// # Examples
+/// ```rust,no_run
+/// use cln::cln_blocking;
+///
+/// cln_blocking("https://github.com/yhakbar/cln.git", None, None, None).unwrap();
+/// ```
+///
+/// # Errors
+/// Will return an error if:
+/// - It's called from inside a tokio runtime, which can't be blocked. Await `cln` there instead.
+/// - The runtime cannot be started.
+/// - The repository cannot be clned, for any of the reasons `cln` can fail.
+pub fn cln_blocking(
+    repo: &str,
+    dir: Option<PathBuf>,
+    branch: Option<&str>,
+    store_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    if Handle::try_current().is_ok() {
+        return Err(Error::BlockingInRuntimeError);
+    }
+
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::RuntimeError)?
+        .block_on(cln(repo, dir, branch, store_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::STORE_TEST_LOCK;
+    use std::process::Command;
+    use tempfile::Builder as TempBuilder;
+
+    #[test]
+    fn test_cln_blocking() {
+        let _lock = STORE_TEST_LOCK.blocking_lock();
+
+        let repo = TempBuilder::new()
+            .prefix("cln-repo")
+            .tempdir()
+            .expect("Failed to create repo tempdir");
+        std::fs::write(repo.path().join("README.md"), "# Blocking\n")
+            .expect("Failed to write README");
+        for args in [
+            vec!["init", "--quiet"],
+            vec!["add", "."],
+            vec![
+                "-c",
+                "user.name=cln",
+                "-c",
+                "user.email=cln@example.com",
+                "commit",
+                "--quiet",
+                "--message",
+                "Initial commit",
+            ],
+        ] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(repo.path())
+                .status()
+                .expect("Failed to run git");
+            assert!(status.success());
+        }
+        let store = TempBuilder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        let target = TempBuilder::new()
+            .prefix("cln")
+            .tempdir()
+            .expect("Failed to create target tempdir");
+
+        cln_blocking(
+            repo.path().to_str().expect("Failed to convert repo path"),
+            Some(target.path().join("checkout")),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .expect("Failed to cln");
+
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("checkout").join("README.md"))
+                .expect("Failed to read README"),
+            "# Blocking\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cln_blocking_in_runtime() {
+        let err = cln_blocking("unused", None, None, None)
+            .expect_err("Blocking inside a runtime should fail");
+
+        assert!(matches!(err, Error::BlockingInRuntimeError));
+    }
+}
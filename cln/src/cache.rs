@@ -0,0 +1,444 @@
+use crate::git::git;
+use crate::Error;
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::fmt::{self, Debug};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use tempfile::{Builder as TempBuilder, NamedTempFile};
+use tokio::fs::{copy, create_dir_all, File};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+/// A second tier of the cln-store, shared between machines, e.g. by CI runners.
+///
+/// Blobs missing from the cln-store are read from object caches, in the order they're added with `Builder::object_cache`,
+/// before they're read from the repository. Blobs read from the repository are then put in every object cache,
+/// so that the first checkout of a blob populates the cache for the rest.
+///
+/// Objects are keyed by their git hash, and are checked against it when they're read, so a cache doesn't need to be trusted.
+/// They're streamed in and out of the cache, rather than read into memory, as they may be larger than memory.
+#[async_trait]
+pub trait ObjectCache: Debug + Send + Sync {
+    /// Writes the contents of a blob to `writer`, returning `false` if it isn't in the cache.
+    ///
+    /// Whatever was written is thrown away if it fails, or the blob isn't in the cache.
+    async fn get(
+        &self,
+        hash: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<bool, Error>;
+    /// Adds the blob in the file at `content_path` to the cache.
+    async fn put(&self, hash: &str, content_path: &Path) -> Result<(), Error>;
+    /// Checks that the cache can be used at all, e.g. that the tools it runs are installed, before a checkout starts.
+    ///
+    /// Unlike a failure to read or write a blob, a failure here fails the checkout,
+    /// as it would otherwise fail, and be logged, once for every blob.
+    async fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// The first line of `curl --version`, once curl has been run.
+static CURL_VERSION: OnceCell<String> = OnceCell::const_new();
+
+/// Checks that the `curl` CLI that `HttpObjectCache` makes its requests with can be run, running `curl --version` once.
+///
+/// Returns the first line of its output, e.g. `curl 8.5.0 (x86_64-pc-linux-gnu) libcurl/8.5.0`.
+///
+/// # Errors
+/// Will return `Error::CurlNotFound` if curl cannot be run.
+pub async fn ensure_curl() -> Result<String, Error> {
+    CURL_VERSION
+        .get_or_try_init(|| async {
+            let out = Command::new("curl")
+                .arg("--version")
+                .output()
+                .await
+                .map_err(|_| Error::CurlNotFound)?;
+            if !out.status.success() {
+                return Err(Error::CurlNotFound);
+            }
+
+            Ok(String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string())
+        })
+        .await
+        .cloned()
+}
+
+/// An object cache in a directory, e.g. on a network mount shared by every machine.
+///
+/// Unlike a reference store, objects in it are copied into the cln-store rather than hard linked,
+/// so it can be on a different filesystem, and it's written to.
+#[derive(Debug, Clone)]
+pub struct DirObjectCache {
+    path: PathBuf,
+}
+
+impl DirObjectCache {
+    #[must_use]
+    pub const fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl ObjectCache for DirObjectCache {
+    async fn get(
+        &self,
+        hash: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<bool, Error> {
+        let cache_error = |e: std::io::Error| Error::ObjectCacheError(e.to_string());
+        let mut file = match File::open(self.path.join(hash)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(cache_error(e)),
+        };
+        tokio::io::copy(&mut file, writer)
+            .await
+            .map_err(cache_error)?;
+
+        Ok(true)
+    }
+    // Written to a temporary file that's renamed into place, so that other machines never read a partial object.
+    async fn put(&self, hash: &str, content_path: &Path) -> Result<(), Error> {
+        let cache_error = |e: std::io::Error| Error::ObjectCacheError(e.to_string());
+        create_dir_all(&self.path).await.map_err(cache_error)?;
+        let temp_file = TempBuilder::new()
+            .prefix(".cln-tmp")
+            .tempfile_in(&self.path)
+            .map_err(cache_error)?;
+        copy(content_path, temp_file.path())
+            .await
+            .map_err(cache_error)?;
+        temp_file
+            .persist(self.path.join(hash))
+            .map_err(|e| cache_error(e.error))?;
+
+        Ok(())
+    }
+}
+
+/// An object cache served over HTTP, e.g. by an S3 bucket, read with `GET <base_url>/<hash>` and written with `PUT`.
+///
+/// Requests are made with the `curl` CLI, as git requests are made with the `git` CLI, so curl has to be installed.
+/// `404` and `403` responses are treated as a miss, as S3 answers `403` for missing objects without list permission.
+#[derive(Clone)]
+pub struct HttpObjectCache {
+    base_url: String,
+    headers: Vec<String>,
+}
+
+impl HttpObjectCache {
+    #[must_use]
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: vec![],
+        }
+    }
+
+    /// Send this header with every request, e.g. `Authorization: Bearer <token>`.
+    #[must_use]
+    pub fn header(mut self, header: &str) -> Self {
+        self.headers.push(header.to_string());
+        self
+    }
+
+    // The headers are read by curl from a private temporary file, rather than passed as arguments,
+    // as they often carry credentials, and the arguments of a process can be read by other users, e.g. with `ps`.
+    // The file is returned with the command, and has to be kept until the request is done.
+    fn curl(&self, hash: &str) -> Result<(Command, Option<NamedTempFile>), Error> {
+        let mut cmd = Command::new("curl");
+        cmd.args(["--silent", "--show-error", "--location"]);
+        let headers_file = if self.headers.is_empty() {
+            None
+        } else {
+            let cache_error = |e: std::io::Error| Error::ObjectCacheError(e.to_string());
+            let mut headers_file = TempBuilder::new()
+                .prefix(".cln-headers")
+                .tempfile()
+                .map_err(cache_error)?;
+            for header in &self.headers {
+                writeln!(headers_file, "{header}").map_err(cache_error)?;
+            }
+            headers_file.flush().map_err(cache_error)?;
+            cmd.arg("--header")
+                .arg(format!("@{}", headers_file.path().display()));
+            Some(headers_file)
+        };
+        cmd.arg(format!("{}/{}", self.base_url, hash));
+
+        Ok((cmd, headers_file))
+    }
+}
+
+// Only the names of headers are shown, as their values often carry credentials, e.g. `Authorization: Bearer <token>`.
+impl Debug for HttpObjectCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers = self
+            .headers
+            .iter()
+            .map(|header| {
+                let name = header
+                    .split_once(':')
+                    .map_or(header.as_str(), |(name, _)| name);
+                format!("{}: <redacted>", name.trim())
+            })
+            .collect::<Vec<_>>();
+        f.debug_struct("HttpObjectCache")
+            .field("base_url", &self.base_url)
+            .field("headers", &headers)
+            .finish()
+    }
+}
+
+// Splits the stderr of `curl --write-out "%{stderr}%{http_code}"` into the error message, if there is one, and the status code.
+fn split_status(stderr: &str) -> (&str, &str) {
+    stderr.rsplit_once('\n').unwrap_or(("", stderr))
+}
+
+#[async_trait]
+impl ObjectCache for HttpObjectCache {
+    async fn check(&self) -> Result<(), Error> {
+        ensure_curl().await.map(|_| ())
+    }
+    // The body is streamed to `writer` as it's downloaded. With `--fail`, curl doesn't write the body of an error response,
+    // so nothing is written for a miss.
+    // The `%{...}` of `--write-out` are curl's variables, rather than formatting arguments.
+    #[allow(clippy::literal_string_with_formatting_args)]
+    async fn get(
+        &self,
+        hash: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<bool, Error> {
+        let (mut curl, _headers_file) = self.curl(hash)?;
+        let mut child = curl
+            .args([
+                "--fail",
+                "--output",
+                "-",
+                "--write-out",
+                "%{stderr}%{http_code}",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::CommandSpawnError)?;
+        if let Some(mut stdout) = child.stdout.take() {
+            tokio::io::copy(&mut stdout, writer)
+                .await
+                .map_err(|e| Error::ObjectCacheError(e.to_string()))?;
+        }
+
+        let out = child
+            .wait_with_output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        match split_status(stderr.trim_end()) {
+            (_, "200") if out.status.success() => Ok(true),
+            (_, "403" | "404") => Ok(false),
+            ("", status) => Err(Error::ObjectCacheError(format!(
+                "{}/{} responded with {}",
+                self.base_url, hash, status
+            ))),
+            (message, _) => Err(Error::ObjectCacheError(message.to_string())),
+        }
+    }
+    async fn put(&self, hash: &str, content_path: &Path) -> Result<(), Error> {
+        let (mut curl, _headers_file) = self.curl(hash)?;
+        let out = curl
+            .args(["--fail", "--output", "/dev/null", "--upload-file"])
+            .arg(content_path)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+        if !out.status.success() {
+            return Err(Error::ObjectCacheError(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Hashes the contents of `file` as a git blob, returning `None` if git fails to hash it.
+async fn hash_file(file: &mut File) -> Result<Option<String>, Error> {
+    let io_error = |e: std::io::Error| Error::ObjectCacheError(e.to_string());
+    file.flush().await.map_err(io_error)?;
+    file.rewind().await.map_err(io_error)?;
+    let mut child = git()
+        .await
+        .args(["hash-object", "--no-filters", "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(Error::CommandSpawnError)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::io::copy(file, &mut stdin)
+            .await
+            .map_err(Error::CommandSpawnError)?;
+    }
+
+    let out = child
+        .wait_with_output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(out.stdout)?.trim_end().to_string()))
+}
+
+// Writes a blob from the first object cache that has it with the right content to `file`, returning whether one did.
+// The file is emptied before each cache is read, so nothing is left in it from a cache that's skipped.
+// Object caches are an optimization, so a cache that fails is logged and skipped, rather than failing the checkout.
+pub async fn get_cached(caches: &[Arc<dyn ObjectCache>], hash: &str, file: &mut File) -> bool {
+    for cache in caches {
+        if let Err(e) = empty_file(file).await {
+            warn!("Failed to prepare a file to read {hash} from object caches into: {e}");
+            return false;
+        }
+        match cache.get(hash, file).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                warn!("Failed to read {hash} from object cache {cache:?}: {e}");
+                continue;
+            }
+        }
+
+        match hash_file(file).await {
+            Ok(Some(content_hash)) if content_hash == hash => {
+                debug!("Read {hash} from object cache {cache:?}");
+                return true;
+            }
+            Ok(_) => warn!("Object cache {cache:?} has the wrong content for {hash}, skipping it"),
+            Err(e) => warn!("Failed to check {hash} from object cache: {e}"),
+        }
+    }
+
+    false
+}
+
+async fn empty_file(file: &mut File) -> std::io::Result<()> {
+    file.flush().await?;
+    file.set_len(0).await?;
+    file.rewind().await?;
+
+    Ok(())
+}
+
+// Adds a blob in the cln-store to every object cache, logging caches that fail.
+pub async fn put_cached(caches: &[Arc<dyn ObjectCache>], hash: &str, content_path: &Path) {
+    for cache in caches {
+        if let Err(e) = cache.put(hash, content_path).await {
+            warn!("Failed to write {hash} to object cache {cache:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    // `git hash-object` of "hello\n".
+    const HELLO_BLOB: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+
+    // Reads a blob from `caches` into a new file, returning its contents if one of them has it.
+    async fn read_cached(caches: &[Arc<dyn ObjectCache>], hash: &str) -> Option<Vec<u8>> {
+        let temp_file = tempfile::NamedTempFile::new().expect("Failed to create tempfile");
+        let mut file = File::from_std(temp_file.reopen().expect("Failed to open tempfile"));
+        if !get_cached(caches, hash, &mut file).await {
+            return None;
+        }
+
+        Some(std::fs::read(temp_file.path()).expect("Failed to read tempfile"))
+    }
+
+    #[tokio::test]
+    async fn test_dir_object_cache() {
+        let dir = Builder::new()
+            .prefix("cln-cache")
+            .tempdir()
+            .expect("Failed to create cache tempdir");
+        let cache: Arc<dyn ObjectCache> = Arc::new(DirObjectCache::new(dir.path().join("cache")));
+        let put = |name: &str, content: &str| {
+            let content_path = dir.path().join(name);
+            std::fs::write(&content_path, content).expect("Failed to write object");
+            let cache = Arc::clone(&cache);
+            async move {
+                cache
+                    .put(HELLO_BLOB, &content_path)
+                    .await
+                    .expect("Failed to put object");
+            }
+        };
+
+        assert_eq!(read_cached(&[Arc::clone(&cache)], HELLO_BLOB).await, None);
+
+        put("hello", "hello\n").await;
+        assert_eq!(
+            read_cached(&[Arc::clone(&cache)], HELLO_BLOB).await,
+            Some(b"hello\n".to_vec())
+        );
+
+        // Content that doesn't match its hash is never read, and nothing of it is left for the next cache.
+        let tampered: Arc<dyn ObjectCache> =
+            Arc::new(DirObjectCache::new(dir.path().join("tampered")));
+        std::fs::create_dir(dir.path().join("tampered")).expect("Failed to create cache");
+        std::fs::write(
+            dir.path().join("tampered").join(HELLO_BLOB),
+            "tampered, and longer\n",
+        )
+        .expect("Failed to write object");
+        assert_eq!(
+            read_cached(&[Arc::clone(&tampered), Arc::clone(&cache)], HELLO_BLOB).await,
+            Some(b"hello\n".to_vec())
+        );
+        assert_eq!(read_cached(&[tampered], HELLO_BLOB).await, None);
+    }
+
+    #[test]
+    fn test_http_object_cache_hides_headers() {
+        let cache = HttpObjectCache::new("https://cache.example.com/")
+            .header("Authorization: Bearer secret-token");
+
+        assert_eq!(
+            format!("{cache:?}"),
+            r#"HttpObjectCache { base_url: "https://cache.example.com", headers: ["Authorization: <redacted>"] }"#
+        );
+
+        let (curl, headers_file) = cache.curl(HELLO_BLOB).expect("Failed to build request");
+        assert!(curl
+            .as_std()
+            .get_args()
+            .all(|arg| !arg.to_string_lossy().contains("secret-token")));
+        let headers_file = headers_file.expect("Failed to write headers file");
+        assert_eq!(
+            std::fs::read_to_string(headers_file.path()).expect("Failed to read headers file"),
+            "Authorization: Bearer secret-token\n"
+        );
+    }
+
+    #[test]
+    fn test_split_status() {
+        assert_eq!(split_status("200"), ("", "200"));
+        assert_eq!(
+            split_status("curl: (22) The requested URL returned error: 404\n404"),
+            ("curl: (22) The requested URL returned error: 404", "404")
+        );
+    }
+}
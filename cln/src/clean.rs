@@ -0,0 +1,163 @@
+use crate::store::ensure_cln_store_path;
+use crate::{flatten_stored_tree, Error, ParsePool};
+use log::debug;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{metadata, read_dir, remove_dir_all, remove_file};
+
+/// How to treat files that are already in the target directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Leave existing files alone, only linking files that are missing.
+    #[default]
+    Keep,
+    /// Make the target directory exactly match the reference,
+    /// removing files and directories that aren't in its tree.
+    Sync,
+}
+
+/// Removes files and directories in `target_dir` that aren't in the tree of `hash`, read from the cln-store at `store_path`.
+///
+/// The paths in the tree are compared after mapping them with `checkout_path`, as they were checked out,
+/// and the directories they were checked out into are kept along with them.
+/// The tree must already be in the cln-store, e.g. from a previous `cln` of the same hash.
+/// The `.git` and `.cln` directories at the root of the target are left alone.
+pub async fn clean(
+    store_path: &Path,
+    target_dir: &Path,
+    hash: &str,
+    parse_pool: &ParsePool,
+    checkout_path: impl Fn(&Path) -> Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut paths = HashSet::new();
+    for (path, _) in flatten_stored_tree(store_path, hash, parse_pool).await? {
+        if let Some(path) = checkout_path(&path) {
+            paths.extend(
+                path.ancestors()
+                    .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                    .map(Path::to_path_buf),
+            );
+        }
+    }
+    let mut pending = vec![target_dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = read_dir(&dir).await.map_err(Error::CleanError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(Error::CleanError)? {
+            let entry_path = entry.path();
+            let relative_path = entry_path.strip_prefix(target_dir).unwrap_or(&entry_path);
+            if relative_path == Path::new(".git") || relative_path == Path::new(".cln") {
+                continue;
+            }
+
+            let is_dir = entry.file_type().await.map_err(Error::CleanError)?.is_dir();
+            if paths.contains(relative_path) {
+                if is_dir {
+                    pending.push(entry_path);
+                }
+                continue;
+            }
+
+            debug!("Removing {}", entry_path.display());
+            if is_dir {
+                remove_dir_all(&entry_path).await
+            } else {
+                remove_file(&entry_path).await
+            }
+            .map_err(Error::CleanError)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes files and directories in `target_dir` that aren't in the tree of `hash`,
+/// so that the directory exactly matches a previous `cln` of that hash.
+///
+/// The tree must already be in the cln-store at `store_path`.
+/// The `.git` and `.cln` directories at the root of the target are left alone.
+///
+/// # Errors
+/// Will return an error if:
+/// - The tree of `hash` cannot be read from the cln-store.
+/// - The target directory cannot be read, or a file or directory in it cannot be removed.
+pub async fn clean_target(
+    target_dir: &Path,
+    hash: &str,
+    store_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    let store_path = ensure_cln_store_path(store_path).await?;
+
+    clean(
+        &store_path,
+        target_dir,
+        hash,
+        &ParsePool::default(),
+        |path| Some(path.to_path_buf()),
+    )
+    .await
+}
+
+// The hashes of every object in the cln-store reachable from a root: its own tree listing, and every subtree and blob in it.
+async fn reachable_objects(store_path: &Path, root_hash: &str) -> Result<HashSet<String>, Error> {
+    let mut objects = flatten_stored_tree(store_path, root_hash, &ParsePool::default())
+        .await?
+        .into_iter()
+        .map(|(_, row)| row.name)
+        .collect::<HashSet<String>>();
+    objects.insert(root_hash.to_string());
+
+    Ok(objects)
+}
+
+/// Removes the objects of one repo from the cln-store, e.g. of a decommissioned project in a store shared with others.
+///
+/// Every object reachable from `root_hash`, the commit a checkout resolved to (as in its `Metadata`), is removed,
+/// unless it's also reachable from one of `keep_roots`, e.g. the commits of the other projects using the store.
+/// Only the trees of those roots are read, so the rest of the store isn't scanned.
+/// Objects that are already missing, e.g. evicted, are skipped.
+///
+/// Returns how many objects were pruned, and how many bytes they added up to.
+///
+/// # Errors
+/// Will return an error if:
+/// - The tree of `root_hash` or of one of `keep_roots` cannot be read from the cln-store.
+/// - An object cannot be removed.
+pub async fn prune_repo(
+    root_hash: &str,
+    keep_roots: &[&str],
+    store_path: Option<PathBuf>,
+) -> Result<(u64, u64), Error> {
+    let store_path = ensure_cln_store_path(store_path).await?;
+
+    let mut objects = reachable_objects(&store_path, root_hash).await?;
+    for keep_root in keep_roots {
+        for hash in reachable_objects(&store_path, keep_root).await? {
+            objects.remove(&hash);
+        }
+    }
+
+    let mut pruned = 0;
+    let mut pruned_bytes = 0;
+    for hash in objects {
+        let object_path = store_path.join(&hash);
+        let size = match metadata(&object_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::PruneError(e)),
+        };
+
+        debug!("Pruning {hash} from the store");
+        match remove_file(&object_path).await {
+            Ok(()) => {
+                pruned += 1;
+                pruned_bytes += size;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::PruneError(e)),
+        }
+    }
+
+    Ok((pruned, pruned_bytes))
+}
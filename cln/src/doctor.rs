@@ -0,0 +1,190 @@
+use crate::cache::ensure_curl;
+use crate::git::{ensure_git, git_version, parse_version, MIN_GIT_VERSION};
+use crate::store::{dedup_stats, store_path, verify_store};
+use crate::Error;
+use log::debug;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tempfile::Builder as TempBuilder;
+
+// How many objects in the cln-store are re-hashed when diagnosing it.
+const SPOT_CHECK_OBJECTS: usize = 64;
+
+/// A filesystem that cln reads from or writes to, as seen by `doctor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemDiagnosis {
+    /// The directory that was checked.
+    pub path: PathBuf,
+    /// The device the directory is on, or `None` if it doesn't exist.
+    pub device: Option<u64>,
+    /// Bytes available to unprivileged users on the filesystem, or `None` if it cannot be checked.
+    pub available_bytes: Option<u64>,
+}
+
+impl FilesystemDiagnosis {
+    fn new(path: PathBuf) -> Self {
+        let device = path.metadata().ok().map(|metadata| metadata.dev());
+        let available_bytes = rustix::fs::statvfs(&path)
+            .ok()
+            .map(|statvfs| statvfs.f_bavail * statvfs.f_frsize);
+
+        Self {
+            path,
+            device,
+            available_bytes,
+        }
+    }
+}
+
+/// What `doctor` found out about the cln-store, and the directory cln would check out into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// The output of `git --version`, or `None` if git cannot be run.
+    pub git_version: Option<String>,
+    /// The first line of `curl --version`, or `None` if curl cannot be run. It's only needed for `HttpObjectCache`.
+    pub curl_version: Option<String>,
+    /// The cln-store.
+    pub store: FilesystemDiagnosis,
+    /// The current directory, where relative checkouts go.
+    pub current_dir: FilesystemDiagnosis,
+    /// Whether files can be created in the cln-store, or `None` if it doesn't exist yet.
+    pub store_writable: Option<bool>,
+    /// Number of objects in the cln-store.
+    pub objects: u64,
+    /// Number of objects in the cln-store that were re-hashed.
+    pub checked_objects: u64,
+    /// Hashes of the re-hashed objects that don't match their content.
+    pub corrupt_objects: Vec<String>,
+}
+
+impl Diagnosis {
+    /// Whether the cln-store and the current directory are on the same device.
+    /// If they aren't, objects are copied into checkouts rather than hard linked, using disk for every checkout.
+    #[must_use]
+    pub fn same_device(&self) -> bool {
+        self.store.device.is_some() && self.store.device == self.current_dir.device
+    }
+
+    /// Whether git can be run, and is at least the oldest version cln supports.
+    #[must_use]
+    pub fn git_supported(&self) -> bool {
+        self.git_version
+            .as_deref()
+            .and_then(parse_version)
+            .is_some_and(|version| version >= MIN_GIT_VERSION)
+    }
+}
+
+/// Diagnoses common problems with git and the cln-store, without changing anything.
+///
+/// This checks which versions of git and curl are installed, where the cln-store is, whether it's writable,
+/// how much space is left on it and in the current directory, whether files can be hard linked between them,
+/// and re-hashes a sample of the objects in the cln-store.
+/// Checking whether the cln-store is writable creates a temporary file in it, which is removed straight away.
+///
+/// If `git_binary` is given, that's the git that's checked, as with `ensure_git`.
+///
+/// # Errors
+/// Will return an error if the cln-store path cannot be resolved, or the cln-store exists but cannot be read.
+pub async fn doctor(
+    store_path_arg: Option<PathBuf>,
+    git_binary: Option<PathBuf>,
+) -> Result<Diagnosis, Error> {
+    let resolved_store_path = store_path(store_path_arg).await?;
+    let current_dir = std::env::current_dir().map_err(Error::CurrentDirError)?;
+
+    let git_version = match ensure_git(git_binary).await {
+        Ok(()) => git_version().await.ok(),
+        Err(e) => {
+            debug!("{e}");
+            None
+        }
+    };
+
+    let curl_version = match ensure_curl().await {
+        Ok(version) => Some(version),
+        Err(e) => {
+            debug!("{e}");
+            None
+        }
+    };
+
+    let mut diagnosis = Diagnosis {
+        git_version,
+        curl_version,
+        store: FilesystemDiagnosis::new(resolved_store_path.clone()),
+        current_dir: FilesystemDiagnosis::new(current_dir),
+        store_writable: None,
+        objects: 0,
+        checked_objects: 0,
+        corrupt_objects: vec![],
+    };
+
+    if !Path::new(&resolved_store_path).is_dir() {
+        debug!(
+            "cln-store {} doesn't exist yet",
+            resolved_store_path.display()
+        );
+        return Ok(diagnosis);
+    }
+
+    diagnosis.store_writable = Some(
+        TempBuilder::new()
+            .prefix(".cln-doctor")
+            .tempfile_in(&resolved_store_path)
+            .is_ok(),
+    );
+    diagnosis.objects = dedup_stats(Some(resolved_store_path.clone()))
+        .await?
+        .objects;
+    let (checked_objects, corrupt_objects) =
+        verify_store(Some(resolved_store_path), SPOT_CHECK_OBJECTS).await?;
+    diagnosis.checked_objects = checked_objects;
+    diagnosis.corrupt_objects = corrupt_objects;
+
+    Ok(diagnosis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::STORE_TEST_LOCK;
+    use tempfile::Builder;
+
+    #[tokio::test]
+    async fn test_doctor() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        std::fs::write(store.path().join("object"), "object").expect("Failed to write object");
+
+        let diagnosis = doctor(Some(store.path().to_path_buf()), None)
+            .await
+            .expect("Failed to diagnose store");
+
+        assert!(diagnosis.git_supported(), "{:?}", diagnosis.git_version);
+        assert!(diagnosis
+            .curl_version
+            .as_deref()
+            .is_some_and(|version| version.starts_with("curl ")));
+        assert_eq!(diagnosis.store_writable, Some(true));
+        assert!(diagnosis.store.device.is_some());
+        assert!(diagnosis.store.available_bytes.is_some());
+        assert_eq!(diagnosis.objects, 1);
+        assert_eq!(diagnosis.checked_objects, 1);
+        assert_eq!(diagnosis.corrupt_objects, vec!["object".to_string()]);
+
+        let missing = store.path().join("missing");
+        let diagnosis = doctor(Some(missing.clone()), None)
+            .await
+            .expect("Failed to diagnose missing store");
+
+        assert_eq!(diagnosis.store.device, None);
+        assert_eq!(diagnosis.store_writable, None);
+        assert_eq!(diagnosis.objects, 0);
+        assert!(!missing.exists());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{Error, TreeRow};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::metadata;
+
+/// A file, directory or submodule in a tree, as returned by `resolve_tree` and `list_tree`, and seen by a checkout filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    path: PathBuf,
+    mode: String,
+    object_type: String,
+    hash: String,
+    size: Option<u64>,
+}
+
+impl TreeEntry {
+    pub(crate) fn from_row(path: PathBuf, row: &TreeRow) -> Self {
+        Self {
+            path,
+            mode: row.mode.clone(),
+            object_type: row.otype.clone(),
+            hash: row.name.clone(),
+            size: None,
+        }
+    }
+
+    // The size of the blob is already known, e.g. from `git cat-file -s`, so it doesn't need to be in the cln-store.
+    pub(crate) fn from_sized_row(path: PathBuf, row: &TreeRow, size: u64) -> Self {
+        Self {
+            size: Some(size),
+            ..Self::from_row(path, row)
+        }
+    }
+
+    // The blob must already be in the cln-store at `store_path`, as that's where its size is read from.
+    pub(crate) async fn from_stored_row(
+        store_path: &Path,
+        path: PathBuf,
+        row: &TreeRow,
+    ) -> Result<Self, Error> {
+        let content_path = store_path.join(&row.name);
+        let size = metadata(&content_path)
+            .await
+            .map_err(|e| Error::ReadFileError(content_path.display().to_string(), e))?
+            .len();
+
+        Ok(Self {
+            size: Some(size),
+            ..Self::from_row(path, row)
+        })
+    }
+
+    /// Path of the entry relative to the root of the tree.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Mode of the entry as git lists it, e.g. `100644`, `100755`, `120000` for a symlink or `040000` for a directory.
+    #[must_use]
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
+
+    /// Type of the object as git lists it: `blob` for a file or symlink, `tree` for a directory or `commit` for a submodule.
+    #[must_use]
+    pub fn object_type(&self) -> &str {
+        &self.object_type
+    }
+
+    /// Hash of the object.
+    #[must_use]
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Size of the file in bytes, if its contents have been read.
+    ///
+    /// This is always known for the entries returned by `list_tree` and passed to a checkout filter.
+    #[must_use]
+    pub const fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+// Predicate deciding which files get linked into the target directory.
+#[derive(Clone)]
+pub struct Filter(Arc<dyn Fn(&TreeEntry) -> bool + Send + Sync>);
+
+impl Filter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&TreeEntry) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    pub fn matches(&self, entry: &TreeEntry) -> bool {
+        (self.0)(entry)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filter").finish_non_exhaustive()
+    }
+}
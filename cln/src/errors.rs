@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use thiserror::Error as ThisError;
 
 #[derive(ThisError, Debug)]
@@ -6,6 +7,10 @@ pub enum Error {
     TempDirError(std::io::Error),
     #[error("Failed to close tempdir: {0}")]
     TempDirCloseError(std::io::Error),
+    #[error("Failed to run git at `{0}`. Install git, or point cln at it with --git-binary or the GIT_BINARY environment variable")]
+    GitNotFound(String),
+    #[error("Failed to run curl, which HTTP object caches are read and written with. Install curl, or leave out the HTTP object cache")]
+    CurlNotFound,
     #[error("Failed to spawn git command: {0}")]
     CommandSpawnError(std::io::Error),
     #[error("Failed to complete git clone: {0}")]
@@ -14,20 +19,156 @@ pub enum Error {
     Utf8Error(#[from] std::string::FromUtf8Error),
     #[error("Failed to create cln-store directory: {0}")]
     CreateDirError(std::io::Error),
+    #[error("Failed to find current directory: {0}")]
+    CurrentDirError(std::io::Error),
     #[error("Failed to find home directory")]
     HomeDirError,
+    #[error("Invalid reference `{0}`, expected a branch, tag or other git ref name")]
+    InvalidRefError(String),
+    #[error("Invalid commit `{0}`, expected a full commit hash rather than an abbreviated one")]
+    AbbreviatedCommitError(String),
+    #[error("Invalid reference pattern: {0}")]
+    InvalidPatternError(globset::Error),
+    #[error("Invalid prefix `{0}`, expected a relative path inside the target directory")]
+    InvalidPrefixError(String),
     #[error("No matching reference found")]
     NoMatchingReferenceError,
+    #[error("No branch or tag in the repository matches `{0}`")]
+    NoMatchingPatternError(String),
+    #[error("No reference `{reference}` found in the repository. {}", format_available(.available, .default_branch.as_deref()))]
+    UnknownReferenceError {
+        reference: String,
+        available: Vec<String>,
+        default_branch: Option<String>,
+    },
+    #[error("Failed to list references: {0}")]
+    LsRemoteError(String),
     #[error("Failed to write {0} to cln-store: {1}")]
     WriteToStoreError(String, std::io::Error),
     #[error("Failed to create directory: {0}")]
     CreateDirAllError(std::io::Error),
+    #[error("Failed to read object: {0}")]
+    ReadObjectError(String),
     #[error("Failed to hard link: {0}")]
     HardLinkError(std::io::Error),
+    #[error("Failed to copy object from the cln-store: {0}")]
+    CopyObjectError(std::io::Error),
     #[error("Failed to read tree: {0}")]
     ReadTreeError(std::io::Error),
     #[error("Parse mode error: {0}")]
     ParseModeError(std::num::ParseIntError),
+    #[error("Failed to complete walk task: {0}")]
+    WalkTaskError(tokio::task::JoinError),
+    #[error("Failed to complete clone task: {0}")]
+    CloneTaskError(tokio::task::JoinError),
+    #[error("Failed to build thread pool: {0}")]
+    ThreadPoolError(String),
+    #[error("Invalid output format `{0}`, expected `tree` or `git`")]
+    InvalidOutputFormat(String),
+    #[error("Failed to write .git directory: {0}")]
+    GitDirError(String),
     #[error("Failed to read file {0}: {1}")]
     ReadFileError(String, std::io::Error),
+    #[error("Store object {hash} doesn't match its hash, the cln-store may be corrupt")]
+    StoreCorruption { hash: String },
+    #[error("Failed to write git loose objects to the store: {0}")]
+    LooseObjectError(String),
+    #[error("Failed to write manifest {0}: {1}")]
+    WriteManifestError(String, std::io::Error),
+    #[error("Failed to write metadata {0}: {1}")]
+    WriteMetadataError(String, std::io::Error),
+    #[error("Invalid metadata in {0}")]
+    InvalidMetadataError(String),
+    #[error("Object {0} isn't in the cln-store")]
+    NotInStoreError(String),
+    #[error("No file {0} in the tree")]
+    PathNotFoundError(String),
+    #[error("{path} is {size} bytes, more than the maximum file size of {max_file_size} bytes")]
+    FileTooLargeError {
+        path: String,
+        size: u64,
+        max_file_size: u64,
+    },
+    #[error("{a} and {b} only differ in case, so they can't both be checked out on this case-insensitive filesystem")]
+    CaseCollisionError { a: String, b: String },
+    #[error("Failed to export cln-store: {0}")]
+    ExportStoreError(std::io::Error),
+    #[error("Failed to import cln-store: {0}")]
+    ImportStoreError(std::io::Error),
+    #[error("Failed to read cln-store: {0}")]
+    ReadStoreError(std::io::Error),
+    #[error("Target directory {0} already exists and isn't empty, use force to replace it")]
+    TargetExistsError(String),
+    #[error("Target directory {dir} has {existing} checked out rather than {hash}, sync it to replace the checkout")]
+    TargetMismatchError {
+        dir: String,
+        existing: String,
+        hash: String,
+    },
+    #[error("Failed to move checkout into place: {0}")]
+    ReplaceTargetError(std::io::Error),
+    #[error("Post-checkout command failed with {0}")]
+    PostCheckoutFailed(String),
+    #[error("Failed to clean target directory: {0}")]
+    CleanError(std::io::Error),
+    #[error("Failed to check out {} paths:{}", .0.len(), format_failures(.0))]
+    PartialCheckoutError(Vec<(PathBuf, Self)>),
+    #[error("Cannot block on a checkout from inside a tokio runtime. Await `cln` instead")]
+    BlockingInRuntimeError,
+    #[error("Failed to start a tokio runtime: {0}")]
+    RuntimeError(std::io::Error),
+    #[error("Invalid maximum store size {0}. Expected a number of bytes")]
+    InvalidMaxStoreBytesError(String),
+    #[error("Failed to evict object from store: {0}")]
+    EvictError(std::io::Error),
+    #[error("Failed to prune object from store: {0}")]
+    PruneError(std::io::Error),
+    #[error("The cln-store {path} has an older layout (version {version}). Run `cln store migrate` to upgrade it")]
+    OutdatedStoreError { path: String, version: u32 },
+    #[error("The cln-store {path} has a newer layout (version {version}) than this version of cln supports. Upgrade cln to use it")]
+    UnsupportedStoreError { path: String, version: u32 },
+    #[error("Invalid cln-store version in {0}: {1}")]
+    InvalidStoreVersionError(String, String),
+    #[error("Failed to migrate cln-store: {0}")]
+    MigrateStoreError(std::io::Error),
+    #[error("Failed to read lockfile {0}: {1}")]
+    ReadLockfileError(String, std::io::Error),
+    #[error("Failed to write lockfile {0}: {1}")]
+    WriteLockfileError(String, std::io::Error),
+    #[error("Invalid lockfile {0}, {1}")]
+    InvalidLockfileError(String, String),
+    #[error("Object cache failed: {0}")]
+    ObjectCacheError(String),
+}
+
+// Only the first few references are listed, as repositories can have thousands of tags.
+const MAX_LISTED_REFERENCES: usize = 20;
+
+fn format_available(available: &[String], default_branch: Option<&str>) -> String {
+    let listed = available
+        .iter()
+        .take(MAX_LISTED_REFERENCES)
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let more = match available.len().saturating_sub(MAX_LISTED_REFERENCES) {
+        0 => String::new(),
+        more => format!(" and {more} more"),
+    };
+    let default_branch =
+        default_branch.map_or_else(String::new, |branch| format!(" (default branch: {branch})"));
+
+    if available.is_empty() {
+        format!("It has no branches or tags{default_branch}")
+    } else {
+        format!("Available branches and tags: {listed}{more}{default_branch}")
+    }
+}
+
+fn format_failures(failures: &[(PathBuf, Error)]) -> String {
+    failures
+        .iter()
+        .map(|(path, e)| format!("\n- {}: {e}", path.display()))
+        .collect::<Vec<String>>()
+        .concat()
 }
@@ -0,0 +1,106 @@
+use crate::Error;
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::Path;
+
+// A glob of paths to leave out of a checkout.
+#[derive(Debug, Clone)]
+struct Pattern {
+    matcher: GlobMatcher,
+    // Patterns without a slash, e.g. `*.mp4`, match the name of a path at any depth, rather than its whole path.
+    name_only: bool,
+    // Patterns ending in `/**` also match the directory itself, so it's pruned rather than walked.
+    dir: Option<GlobMatcher>,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self, Error> {
+        // A leading slash anchors a pattern to the root of the tree, e.g. `/docs`.
+        let name_only = !pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        let matcher = |pattern: &str| {
+            GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map(|glob| glob.compile_matcher())
+                .map_err(Error::InvalidPatternError)
+        };
+
+        Ok(Self {
+            matcher: matcher(pattern)?,
+            name_only,
+            dir: pattern.strip_suffix("/**").map(matcher).transpose()?,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if self.name_only {
+            return path
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name));
+        }
+
+        self.matcher.is_match(path) || self.dir.as_ref().is_some_and(|dir| dir.is_match(path))
+    }
+}
+
+// The globs set with `Builder::exclude`, matched against the path of each entry relative to the root of the tree.
+#[derive(Debug, Clone, Default)]
+pub struct Exclude {
+    patterns: Vec<Pattern>,
+}
+
+impl Exclude {
+    pub fn new(patterns: &[String]) -> Result<Self, Error> {
+        Ok(Self {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // Whether the file or directory at `path` is left out, along with everything below it.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.patterns.iter().any(|pattern| pattern.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude() {
+        let exclude = Exclude::new(&[
+            "test/fixtures/**".to_string(),
+            "*.mp4".to_string(),
+            "/docs".to_string(),
+        ])
+        .expect("Failed to parse excludes");
+
+        for excluded in [
+            "test/fixtures",
+            "test/fixtures/a.txt",
+            "test/fixtures/nested/b.txt",
+            "video.mp4",
+            "media/clips/video.mp4",
+            "docs",
+        ] {
+            assert!(exclude.is_excluded(Path::new(excluded)), "{excluded}");
+        }
+        for included in [
+            "test",
+            "test/unit.rs",
+            "src/test/fixtures/a.txt",
+            "video.mp4.txt",
+            "src/docs",
+        ] {
+            assert!(!exclude.is_excluded(Path::new(included)), "{included}");
+        }
+
+        assert!(matches!(
+            Exclude::new(&["a[".to_string()]),
+            Err(Error::InvalidPatternError(_))
+        ));
+    }
+}
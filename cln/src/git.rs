@@ -0,0 +1,182 @@
+use crate::Error;
+use log::warn;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+pub const GIT_BINARY_ENV: &str = "GIT_BINARY";
+
+// The oldest git that supports every flag cln passes, the newest of which is `--end-of-options`.
+pub const MIN_GIT_VERSION: (u32, u32) = (2, 24);
+
+// The git binary that has been verified to run, if any.
+static GIT_BINARY: LazyLock<Arc<Mutex<Option<PathBuf>>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(None)));
+
+/// Ensures that a working git binary is available, running `git --version` once per binary.
+///
+/// If `git_binary` is given, it is used.
+/// Otherwise the previously verified binary is kept, falling back to the `GIT_BINARY` environment variable, then `git` on the `PATH`.
+///
+/// # Errors
+/// Will return `Error::GitNotFound` if the git binary cannot be run.
+pub async fn ensure_git(git_binary: Option<PathBuf>) -> Result<(), Error> {
+    let verified = GIT_BINARY.lock().await.clone();
+
+    let git_binary = match (git_binary, verified.as_ref()) {
+        (Some(git_binary), _) => git_binary,
+        (None, Some(_)) => return Ok(()),
+        (None, None) => {
+            std::env::var_os(GIT_BINARY_ENV).map_or_else(|| PathBuf::from("git"), PathBuf::from)
+        }
+    };
+
+    if verified.as_ref() == Some(&git_binary) {
+        return Ok(());
+    }
+
+    let out = Command::new(&git_binary)
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|_| Error::GitNotFound(git_binary.display().to_string()))?;
+
+    if !out.status.success() {
+        return Err(Error::GitNotFound(git_binary.display().to_string()));
+    }
+
+    let version = String::from_utf8_lossy(&out.stdout);
+    match parse_version(&version) {
+        Some(parsed) if parsed < MIN_GIT_VERSION => warn!(
+            "{} is older than {}.{}, the oldest git cln supports, so some checkouts may fail",
+            version.trim_end(),
+            MIN_GIT_VERSION.0,
+            MIN_GIT_VERSION.1
+        ),
+        Some(_) => {}
+        None => warn!("Failed to parse git version from {:?}", version.trim_end()),
+    }
+
+    *GIT_BINARY.lock().await = Some(git_binary);
+
+    Ok(())
+}
+
+// Parses the major and minor version out of `git --version` output, e.g. `git version 2.39.2 (Apple Git-143)`.
+pub fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut numbers = version
+        .strip_prefix("git version ")?
+        .split(|c: char| !c.is_ascii_digit());
+    let major = numbers.next()?.parse().ok()?;
+    let minor = numbers.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Runs `git --version` with the git binary verified by `ensure_git`.
+pub async fn git_version() -> Result<String, Error> {
+    let out = git()
+        .await
+        .arg("--version")
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    Ok(String::from_utf8(out.stdout)?.trim_end().to_string())
+}
+
+/// Creates a new `Command` for the git binary verified by `ensure_git`.
+///
+/// The git process is killed if the future running it is dropped, e.g. when a `cln` is cancelled,
+/// so no git processes are left behind writing to a removed temporary directory.
+pub async fn git() -> Command {
+    let git_binary = GIT_BINARY.lock().await.clone();
+
+    let mut command = Command::new(git_binary.unwrap_or_else(|| PathBuf::from("git")));
+    command.kill_on_drop(true);
+    command
+}
+
+/// Creates a new `Command` for the git binary verified by `ensure_git`, passing each of `config` as `-c key=value`.
+pub async fn git_with_config(config: &[(String, String)]) -> Command {
+    let mut command = git().await;
+    for (key, value) in config {
+        command.arg("-c").arg(format!("{key}={value}"));
+    }
+    command
+}
+
+/// Checks that a branch, tag or other reference is a valid git ref name, like `main`, `v1.0.0` or `refs/pull/1/head`.
+///
+/// References starting with `-` are always rejected, so that they can't be interpreted by git as an option.
+///
+/// # Errors
+/// Will return `Error::InvalidRefError` if the reference isn't a valid ref name.
+pub async fn validate_ref(reference: &str) -> Result<(), Error> {
+    if reference.starts_with('-') {
+        return Err(Error::InvalidRefError(reference.to_string()));
+    }
+
+    let out = git()
+        .await
+        .args(["check-ref-format", "--allow-onelevel", reference])
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Err(Error::InvalidRefError(reference.to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ensure_git_not_found() {
+        let err = ensure_git(Some(PathBuf::from("/nonexistent/git")))
+            .await
+            .expect_err("Expected a missing git binary to be rejected");
+
+        assert!(matches!(err, Error::GitNotFound(_)));
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("git version 2.39.2\n"), Some((2, 39)));
+        assert_eq!(
+            parse_version("git version 2.39.3 (Apple Git-146)"),
+            Some((2, 39))
+        );
+        assert_eq!(parse_version("git version 2.45.1.windows.1"), Some((2, 45)));
+        assert_eq!(parse_version("git version 1.8.3.1"), Some((1, 8)));
+        assert!(parse_version("git version 1.8.3.1") < Some(MIN_GIT_VERSION));
+        assert_eq!(parse_version("not git"), None);
+    }
+
+    #[tokio::test]
+    async fn test_validate_ref() {
+        for reference in ["main", "HEAD", "v1.0.0", "feature/cln", "refs/pull/1/head"] {
+            validate_ref(reference)
+                .await
+                .unwrap_or_else(|e| panic!("Expected {reference} to be valid: {e}"));
+        }
+
+        for reference in [
+            "--upload-pack=touch /tmp/pwned",
+            "-b",
+            "main..other",
+            "main~1",
+            "feature/",
+            "with space",
+        ] {
+            let err = validate_ref(reference)
+                .await
+                .expect_err("Expected an invalid ref to be rejected");
+            assert!(matches!(err, Error::InvalidRefError(_)), "{reference}");
+        }
+    }
+}
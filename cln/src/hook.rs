@@ -0,0 +1,45 @@
+use crate::Error;
+use log::debug;
+use std::path::Path;
+use tokio::process::Command;
+
+/// Runs a shell command in the target directory once it's checked out.
+///
+/// The command is run with `sh -c`, with `CLN_REPO`, `CLN_REF`, `CLN_HASH` and `CLN_TARGET`
+/// describing the checkout in its environment.
+pub async fn run_post_checkout(
+    command: &str,
+    repo: &str,
+    remote_ref: &str,
+    hash: &str,
+    target_dir: &Path,
+) -> Result<(), Error> {
+    debug!("Running post-checkout command in {}", target_dir.display());
+
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(target_dir)
+        .env("CLN_REPO", repo)
+        .env("CLN_REF", remote_ref)
+        .env("CLN_HASH", hash)
+        .env("CLN_TARGET", target_dir)
+        .output()
+        .await
+        .map_err(|e| Error::PostCheckoutFailed(e.to_string()))?;
+
+    debug!(
+        "Post-checkout command output: {}",
+        String::from_utf8_lossy(&out.stdout).trim_end()
+    );
+
+    if !out.status.success() {
+        return Err(Error::PostCheckoutFailed(format!(
+            "{}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )));
+    }
+
+    Ok(())
+}
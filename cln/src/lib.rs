@@ -1,30 +1,102 @@
+mod archive;
+mod attributes;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod cache;
+mod clean;
+mod doctor;
+mod entry;
 mod errors;
+mod exclude;
+mod git;
+mod hook;
+mod lock;
+mod loose;
+mod manifest;
+mod metadata;
+mod output;
+mod progress;
+mod refs;
+mod source;
 mod store;
 
+pub use archive::{export_store, import_store};
+use attributes::{checkout_filters, ExportIgnore, ATTRIBUTES_FILE};
+#[cfg(feature = "blocking")]
+pub use blocking::cln_blocking;
+pub use cache::{ensure_curl, DirObjectCache, HttpObjectCache, ObjectCache};
+use cache::{get_cached, put_cached};
+use clean::clean;
+pub use clean::{clean_target, prune_repo, OverwritePolicy};
+pub use doctor::{doctor, Diagnosis, FilesystemDiagnosis};
+use entry::Filter;
+pub use entry::TreeEntry;
 pub use errors::Error;
-use store::{ensure_cln_store_path, is_content_stored, STORE_PATH};
+use exclude::Exclude;
+pub use git::ensure_git;
+use git::{git, git_with_config, validate_ref};
+use hook::run_post_checkout;
+use lock::{lockfile_base, update_lockfile};
+pub use lock::{read_lockfile, LockEntry};
+use loose::write_loose_objects;
+pub use loose::LOOSE_OBJECTS_DIR;
+use manifest::write_manifest;
+use metadata::write_metadata;
+pub use metadata::{read_metadata, Metadata};
+use output::write_git_dir;
+pub use output::OutputFormat;
+use progress::read_progress;
+pub use progress::CloneProgress;
+pub use refs::{latest_version, resolve_refs, Reference};
+use refs::{matching_refs, newest_ref};
+pub use source::{GitCliSource, ObjectSource};
+pub use store::{
+    cln_store_path, dedup_stats, default_store_path, evict_store, migrate_store, store_path,
+    verify_store, DedupStats, MAX_STORE_BYTES_ENV, STORE_VERSION,
+};
+use store::{
+    ensure_cln_store_path, ensure_namespaced_store_path, evict, format_tree_listing,
+    is_content_stored, is_content_stored_or_seeded, max_store_bytes, parse_tree_listing,
+    touch_object, verify_stored_blob, write_object, write_object_if, write_object_with,
+};
 
 use async_trait::async_trait;
-use log::debug;
+use log::{debug, warn};
+#[cfg(feature = "rayon")]
 use rayon::prelude::*;
 use std::{
-    os::unix::fs::PermissionsExt,
-    path::{Path, PathBuf},
+    collections::{HashMap, VecDeque},
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
 };
 use tempfile::{Builder as TempBuilder, TempDir};
 use tokio::{
-    fs::{create_dir_all, hard_link, read_to_string, write, File},
-    process::Command,
+    fs::{
+        create_dir_all, hard_link, metadata, read, read_to_string, remove_dir_all, remove_file,
+        rename, set_permissions, symlink_metadata, File,
+    },
+    io::{copy, AsyncRead, AsyncWriteExt},
+    sync::mpsc::UnboundedSender,
+    task::{spawn_blocking, JoinSet},
 };
+use tracing::{instrument, Span};
 
 /// Clns a git repository into a given directory.
+///
 /// If no directory is given, the repository will be cloned into a directory with the same name as the repository.
 /// If no branch is given, the repository will be cloned at HEAD.
 /// If the repository is already in the cln-store, it will be copied from there.
 /// If the repository is not in the cln-store, it will be cloned into a temporary directory and copied from there.
 ///
+/// Files are hard linked read-only from the cln-store, so every checkout of a file shares one inode with the object and with each other.
+/// Editing a file in place edits it in every checkout, and corrupts the cln-store.
+/// Use `Builder::writable` or `Builder::writable_if` to copy the files that will be edited instead.
+///
 /// # Examples
-/// ```rust
+/// ```rust,no_run
 /// use cln::cln;
 /// use tempfile::Builder;
 ///
@@ -49,6 +121,7 @@ use tokio::{
 /// # Errors
 /// Will return an error if the repository cannot be clned.
 /// This can happen if:
+/// - The git binary cannot be run.
 /// - The tempdir where the repository is cloned cannot be created.
 /// - The git command to clone the repository into the tempdir fails.
 /// - The new directory where the repository is copied to cannot be created.
@@ -60,651 +133,6904 @@ pub async fn cln(
     branch: Option<&str>,
     store_path: Option<PathBuf>,
 ) -> Result<(), Error> {
-    ensure_cln_store_path(store_path).await?;
+    cln_with_options(repo, dir, branch, store_path, Arc::default()).await
+}
 
-    let target_dir = dir.map_or_else(|| get_repo_name(repo), |dir| dir);
-    let remote_ref = branch.as_ref().map_or(HEAD, |branch| branch);
+// Options that are only configurable through the `Builder`.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, Clone)]
+struct ClnOptions {
+    git_binary: Option<PathBuf>,
+    preserve_empty_dirs: bool,
+    output_format: OutputFormat,
+    continue_on_error: bool,
+    overwrite_policy: OverwritePolicy,
+    reference_repo: Option<PathBuf>,
+    verify_on_link: bool,
+    manifest: Option<PathBuf>,
+    temp_dir: Option<PathBuf>,
+    output_base: Option<PathBuf>,
+    filter: Option<Filter>,
+    atomic: bool,
+    force: bool,
+    strip_components: usize,
+    strip_prefix: Option<PathBuf>,
+    add_prefix: Option<PathBuf>,
+    post_checkout: Option<String>,
+    source: Option<Arc<dyn ObjectSource>>,
+    reference_stores: Vec<PathBuf>,
+    write_metadata: bool,
+    follow_default_branch: bool,
+    namespace_store: bool,
+    git_config: Vec<(String, String)>,
+    all_branches: bool,
+    dir_mode: Option<u32>,
+    concurrency: Option<usize>,
+    deterministic: bool,
+    clone_filter: Option<String>,
+    store_read_only: bool,
+    export_ignore: bool,
+    progress: Option<UnboundedSender<CloneProgress>>,
+    max_store_bytes: Option<u64>,
+    object_caches: Vec<Arc<dyn ObjectCache>>,
+    mirrors: Vec<String>,
+    // Set when the cln-store and the target directory are on different filesystems, where objects can't be hard linked.
+    copy_objects: bool,
+    mirror_to: Vec<PathBuf>,
+    // Whether objects are copied into each of `mirror_to`, rather than hard linked, as they're on a different filesystem to the cln-store.
+    mirror_copies: Vec<bool>,
+    // Set when the target directory is on a case-insensitive filesystem, where paths that only differ in case collide.
+    case_insensitive: bool,
+    lockfile: Option<PathBuf>,
+    writable: bool,
+    writable_filter: Option<Filter>,
+    branch_glob: bool,
+    loose_objects: bool,
+    max_file_size: Option<u64>,
+    skip_large_files: bool,
+    no_store: bool,
+    exclude_patterns: Vec<String>,
+    // Compiled from `exclude_patterns` when the checkout starts.
+    exclude: Exclude,
+    // Built from `concurrency` when the checkout starts.
+    parse_pool: ParsePool,
+    // The cln-store the checkout reads and writes objects in, resolved when it starts.
+    // It's the namespace of the repository when the store is namespaced, and the scratch store when it's read-only.
+    store: PathBuf,
+}
 
-    if let Ok(ls_remote) = run_ls_remote(repo, remote_ref).await {
-        if let Ok(ls_remote_hash) = ls_remote.get_hash() {
-            if is_content_stored(&ls_remote_hash).await? {
-                let head_tree = Tree::from_hash(&ls_remote_hash, ".".to_string()).await?;
-                if !&target_dir.exists() {
-                    create_dir_all(&target_dir)
-                        .await
-                        .map_err(Error::CreateDirAllError)?;
-                }
-                ls_remote_hash.walk(&head_tree, &target_dir).await?;
+// Compiles the exclude patterns and normalises the prefixes of the options, failing if any of them are invalid.
+fn prepare_paths(options: Arc<ClnOptions>) -> Result<Arc<ClnOptions>, Error> {
+    if options.exclude_patterns.is_empty()
+        && options.strip_prefix.is_none()
+        && options.add_prefix.is_none()
+    {
+        return Ok(options);
+    }
 
-                return Ok(());
-            }
+    let mut prepared = ClnOptions::clone(&options);
+    prepared.exclude = Exclude::new(&options.exclude_patterns)?;
+    prepared.strip_prefix = options
+        .strip_prefix
+        .as_deref()
+        .map(relative_prefix)
+        .transpose()?;
+    prepared.add_prefix = options
+        .add_prefix
+        .as_deref()
+        .map(relative_prefix)
+        .transpose()?;
+    Ok(Arc::new(prepared))
+}
 
-            let tmp_dir = create_temp_dir()?;
-            let tmp_dir_path = tmp_dir.path();
+async fn cln_with_options(
+    repo: &str,
+    dir: Option<PathBuf>,
+    branch: Option<&str>,
+    store_path: Option<PathBuf>,
+    options: Arc<ClnOptions>,
+) -> Result<(), Error> {
+    // Other object sources may not need git at all.
+    let mut default_branch = None;
+    let mut matched_branch = None;
+    if options.source.is_none() {
+        ensure_git(options.git_binary.clone()).await?;
+        if let Some(pattern) = branch.filter(|_| options.branch_glob) {
+            let refs = matching_refs(repo, pattern, &options.git_config).await?;
+            let (name, hash) = newest_ref(&refs)
+                .ok_or_else(|| Error::NoMatchingPatternError(pattern.to_string()))?;
+            debug!("Matched {pattern} to {name} ({hash}) in {repo}");
+            validate_ref(name).await?;
+            matched_branch = Some(name.clone());
+        } else if let Some(branch) = branch {
+            validate_ref(branch).await?;
+        } else if options.follow_default_branch {
+            default_branch = Some(resolve_default_branch(repo, &options.git_config).await?)
+                .filter(|branch| branch != HEAD);
+            debug!("Following default branch {default_branch:?} of {repo}");
+        }
+    }
+    let branch = matched_branch
+        .as_deref()
+        .or(branch)
+        .or(default_branch.as_deref());
+    for object_cache in &options.object_caches {
+        object_cache.check().await?;
+    }
+    // Without a store, the cln-store is never read or written, so it isn't created either.
+    let store_root = if options.no_store {
+        None
+    } else {
+        Some(ensure_cln_store_path(store_path).await?)
+    };
+    let max_store_bytes = max_store_bytes(options.max_store_bytes)?;
+    let options = prepare_paths(options)?;
+    let mut resolved = ClnOptions::clone(&options);
+    resolved.parse_pool = ParsePool::new(options.concurrency)?;
+    if let Some(store_root) = &store_root {
+        if options.namespace_store {
+            // Objects already in the shared store are linked into the namespace, rather than fetched again.
+            resolved.store = ensure_namespaced_store_path(store_root, repo).await?;
+            resolved.reference_stores.push(store_root.clone());
+        } else {
+            resolved.store.clone_from(store_root);
+        }
+    }
+    let options = Arc::new(resolved);
 
-            debug!("Cloning {} into {}", repo, tmp_dir_path.display());
-            clone_repo(repo, tmp_dir_path, branch).await?;
+    let target_dir = dir.unwrap_or_else(|| get_repo_name(repo));
+    let target_dir = match &options.output_base {
+        Some(output_base) if target_dir.is_relative() => output_base.join(target_dir),
+        _ => target_dir,
+    };
+    let target_dir = resolve_target_dir(&target_dir);
+    let remote_ref = branch.as_ref().map_or(HEAD, |branch| branch);
+    let previous_hash = read_metadata(&target_dir)
+        .await
+        .ok()
+        .map(|metadata| metadata.hash);
 
-            let head_tree = tmp_dir_path
-                .ls_tree(&ls_remote_hash, ".".to_string())
-                .await?;
+    if let Some(store_root) = store_root.filter(|_| !options.store_read_only) {
+        checkout_and_hook(
+            repo,
+            &target_dir,
+            branch,
+            remote_ref,
+            previous_hash,
+            options,
+        )
+        .await?;
 
-            if !Path::new(&target_dir).exists() {
-                create_dir_all(&target_dir)
-                    .await
-                    .map_err(Error::CreateDirAllError)?;
-            }
-            tmp_dir_path
-                .walk(&head_tree, Path::new(&target_dir))
-                .await?;
+        // Evicting after the checkout means its own objects are the most recently used, so they're the last to go.
+        if let Some(max_store_bytes) = max_store_bytes {
+            let (evicted, evicted_bytes) = evict(&store_root, max_store_bytes).await?;
+            debug!(
+                "Evicted {evicted} objects ({evicted_bytes} bytes) from the store to keep it under {max_store_bytes} bytes"
+            );
+        }
 
-            tmp_dir.close().map_err(Error::TempDirCloseError)?;
+        return Ok(());
+    }
 
-            return Ok(());
-        }
+    // Objects are read from the read-only store as a reference store,
+    // and anything missing from it is written to a scratch store next to the target, which is removed afterwards.
+    // Without a store, only what can't be streamed straight into the target is written to the scratch store.
+    let scratch_store = create_scratch_store(&target_dir, &options).await?;
+    let mut read_only = ClnOptions::clone(&options);
+    if !options.no_store {
+        read_only.reference_stores.push(options.store.clone());
     }
+    read_only.store = scratch_store.path().to_path_buf();
+    // The scratch store is removed afterwards, so there's nowhere to keep loose objects.
+    read_only.loose_objects = false;
+    let result = checkout_and_hook(
+        repo,
+        &target_dir,
+        branch,
+        remote_ref,
+        previous_hash,
+        Arc::new(read_only),
+    )
+    .await;
 
-    if is_content_stored(remote_ref).await? {
-        let head_tree = Tree::from_hash(remote_ref, ".".to_string()).await?;
-        if !&target_dir.exists() {
-            create_dir_all(&target_dir)
-                .await
-                .map_err(Error::CreateDirAllError)?;
-        }
-        remote_ref.to_string().walk(&head_tree, &target_dir).await?;
+    close_temp_dir(scratch_store, result).await
+}
 
-        return Ok(());
-    }
+// Creates a store next to the target directory, so that objects written to it can be hard linked into the target.
+async fn create_scratch_store(target_dir: &Path, options: &ClnOptions) -> Result<TempDir, Error> {
+    let parent = target_dir
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    create_dirs(parent, options).await?;
+    let scratch_store = TempBuilder::new()
+        .prefix(".cln-store")
+        .tempdir_in(parent)
+        .map_err(Error::TempDirError)?;
+    debug!(
+        "Writing objects missing from the read-only store to {}",
+        scratch_store.path().display()
+    );
 
-    let tempdir = create_temp_dir()?;
-    let tempdir_path = tempdir.path();
+    Ok(scratch_store)
+}
 
-    debug!("Slow cloning {} into {}", repo, tempdir_path.display());
-    slow_clone_repo(repo, tempdir_path, branch).await?;
+// Checks out the reference, then runs the post-checkout command unless the same commit was already checked out.
+async fn checkout_and_hook(
+    repo: &str,
+    target_dir: &Path,
+    branch: Option<&str>,
+    remote_ref: &str,
+    previous_hash: Option<String>,
+    options: Arc<ClnOptions>,
+) -> Result<(), Error> {
+    let store_path = options.store.clone();
+    let options = if is_same_filesystem(&store_path, target_dir) {
+        options
+    } else {
+        warn!(
+            "The cln-store {} and {} are on different filesystems, so objects are copied rather than hard linked",
+            store_path.display(),
+            target_dir.display()
+        );
+        let mut copying = ClnOptions::clone(&options);
+        copying.copy_objects = true;
+        Arc::new(copying)
+    };
+    let options = if is_case_insensitive(target_dir) {
+        debug!(
+            "{} is on a case-insensitive filesystem",
+            target_dir.display()
+        );
+        let mut case_insensitive = ClnOptions::clone(&options);
+        case_insensitive.case_insensitive = true;
+        Arc::new(case_insensitive)
+    } else {
+        options
+    };
+    let options = if options.mirror_to.is_empty() {
+        options
+    } else {
+        let mut mirroring = ClnOptions::clone(&options);
+        mirroring.mirror_copies = options
+            .mirror_to
+            .iter()
+            .map(|mirror| {
+                let copy = options.copy_objects || !is_same_filesystem(&store_path, mirror);
+                if copy && !options.copy_objects {
+                    warn!(
+                        "The cln-store {} and {} are on different filesystems, so objects are copied rather than hard linked",
+                        store_path.display(),
+                        mirror.display()
+                    );
+                }
+                copy
+            })
+            .collect();
+        Arc::new(mirroring)
+    };
 
-    let head_tree = tempdir_path.ls_tree(remote_ref, ".".to_string()).await?;
+    let hash = if options.atomic {
+        materialize_atomically(repo, target_dir, branch, remote_ref, Arc::clone(&options)).await?
+    } else {
+        materialize(repo, target_dir, branch, remote_ref, Arc::clone(&options)).await?
+    };
 
-    if !Path::new(&target_dir).exists() {
-        create_dir_all(&target_dir)
-            .await
-            .map_err(Error::CreateDirAllError)?;
+    if let Some(lockfile) = &options.lockfile {
+        update_lockfile(lockfile, repo, target_dir, &hash).await?;
     }
 
-    tempdir_path
-        .walk(&head_tree, Path::new(&target_dir))
-        .await?;
+    if options.loose_objects {
+        write_loose_objects(&options.store, &hash, &options.parse_pool).await?;
+    }
 
-    tempdir.close().map_err(Error::TempDirCloseError)?;
+    if let Some(post_checkout) = &options.post_checkout {
+        if previous_hash.as_ref() == Some(&hash) {
+            debug!(
+                "Skipping post-checkout command, as {} already had {} checked out",
+                target_dir.display(),
+                hash
+            );
+        } else {
+            run_post_checkout(post_checkout, repo, remote_ref, &hash, target_dir).await?;
+        }
+    }
 
     Ok(())
 }
 
-/// Using all those options can be cumbersome, so a builder is provided for a cleaner experience
-///
-/// ```rust
-/// use cln::Builder;
-/// use tempfile::Builder as TempBuilder;
-///
-/// #[tokio::main]
-/// async fn main() {
-///     let path = TempBuilder::new()
-///         .prefix("cln")
-///         .tempdir()
-///         .unwrap()
-///         .into_path();
-///
-///     let store_path = TempBuilder::new()
-///         .prefix("cln-store")
-///         .tempdir()
-///         .unwrap()
-///         .into_path();
-///
-///     Builder::new("https://github.com/yhakbar/cln.git")
-///         .dir(path)
-///         .store_path(store_path)
-///         .cln()
-///         .await
-///         .unwrap();
-/// }
-/// ```
-#[derive(Debug)]
-pub struct Builder {
-    repo: String,
-    dir: Option<PathBuf>,
-    branch: Option<String>,
-    store_path: Option<PathBuf>,
-}
+// Checks out the reference into the target directory, then writes everything else the options ask for,
+// returning the hash the reference resolved to.
+async fn materialize(
+    repo: &str,
+    target_dir: &Path,
+    branch: Option<&str>,
+    remote_ref: &str,
+    options: Arc<ClnOptions>,
+) -> Result<String, Error> {
+    let (hash, mirror) =
+        checkout(repo, target_dir, branch, remote_ref, Arc::clone(&options)).await?;
 
-impl Builder {
-    #[must_use]
-    pub fn new(repo: &str) -> Self {
-        Self {
-            repo: repo.to_string(),
-            dir: None,
-            branch: None,
-            store_path: None,
+    if options.overwrite_policy == OverwritePolicy::Sync {
+        for target_dir in
+            std::iter::once(target_dir).chain(options.mirror_to.iter().map(PathBuf::as_path))
+        {
+            clean(
+                &options.store,
+                target_dir,
+                &hash,
+                &options.parse_pool,
+                |path| checkout_path(path, &options),
+            )
+            .await?;
         }
     }
 
-    #[must_use]
-    pub fn dir(mut self, dir: PathBuf) -> Self {
-        self.dir = Some(dir);
-        self
+    if let Some(manifest) = &options.manifest {
+        write_manifest(
+            &options.store,
+            manifest,
+            &hash,
+            &options.parse_pool,
+            options.filter.as_ref(),
+            |path| checkout_path(path, &options),
+        )
+        .await?;
     }
 
-    #[must_use]
-    pub fn branch(mut self, branch: &str) -> Self {
-        self.branch = Some(branch.to_string());
-        self
+    if options.output_format == OutputFormat::Git {
+        write_git_dir(target_dir, repo, remote_ref, &hash).await?;
     }
 
-    #[must_use]
-    pub fn store_path(mut self, store_path: PathBuf) -> Self {
-        self.store_path = Some(store_path);
-        self
+    if options.write_metadata {
+        let default_branch = if branch.is_none() && options.source.is_none() {
+            // Best effort, as `HEAD` still records what was checked out.
+            match resolve_default_branch(repo, &options.git_config).await {
+                Ok(default_branch) => Some(default_branch).filter(|branch| branch != HEAD),
+                Err(e) => {
+                    debug!("Failed to resolve the default branch of {repo}: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let metadata = Metadata {
+            repo: repo.to_string(),
+            reference: remote_ref.to_string(),
+            hash: hash.clone(),
+            default_branch,
+            mirror,
+        };
+        write_metadata(target_dir, &metadata).await?;
     }
 
-    /// # Errors
-    /// Will return an error if the repository cannot be clned.
-    /// This can happen if:
-    /// - The tempdir where the repository is cloned cannot be created.
-    /// - The git command to clone the repository into the tempdir fails.
-    /// - The new directory where the repository is copied to cannot be created.
-    /// - The temporary directory cannot be persisted to the cln-store.
-    /// - The hard links from the cln-store to the new directory fail.
-    pub async fn cln(self) -> Result<(), Error> {
-        cln(
-            &self.repo,
-            self.dir,
-            self.branch.as_deref(),
-            self.store_path,
-        )
-        .await
-    }
+    Ok(hash)
 }
 
-fn create_temp_dir() -> Result<TempDir, Error> {
-    let tempdir = TempBuilder::new()
-        .prefix("cln")
-        .tempdir()
+// Materializes into a staging directory next to the target, and only renames it into place once it's complete,
+// so the target is either the full checkout or left as it was.
+// The staging directory has to be on the same filesystem as the target for the rename to be atomic.
+async fn materialize_atomically(
+    repo: &str,
+    target_dir: &Path,
+    branch: Option<&str>,
+    remote_ref: &str,
+    options: Arc<ClnOptions>,
+) -> Result<String, Error> {
+    let target_exists = target_dir.symlink_metadata().is_ok();
+    if target_exists && !options.force && !is_empty_dir(target_dir) {
+        return Err(Error::TargetExistsError(target_dir.display().to_string()));
+    }
+
+    let parent = target_dir
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    create_dirs(parent, &options).await?;
+    let staging = TempBuilder::new()
+        .prefix(".cln-staging")
+        .tempdir_in(parent)
         .map_err(Error::TempDirError)?;
+    let result = async {
+        // The staging directory becomes the target directory, so it's created with the mode the target would have.
+        if let Some(dir_mode) = options.dir_mode {
+            set_permissions(staging.path(), std::fs::Permissions::from_mode(dir_mode))
+                .await
+                .map_err(Error::CreateDirAllError)?;
+        }
 
-    Ok(tempdir)
-}
+        debug!(
+            "Staging checkout of {} in {}",
+            repo,
+            staging.path().display()
+        );
+        let hash = materialize(repo, staging.path(), branch, remote_ref, options).await?;
 
-async fn clone_repo(repo: &str, dir: &Path, branch: Option<&str>) -> Result<(), Error> {
-    let mut cmd = Command::new("git");
+        // Renaming over an empty directory replaces it, anything else has to be removed first.
+        if target_exists && !is_empty_dir(target_dir) {
+            debug!("Removing existing target {}", target_dir.display());
+            if target_dir.is_dir() {
+                remove_dir_all(target_dir).await
+            } else {
+                remove_file(target_dir).await
+            }
+            .map_err(Error::ReplaceTargetError)?;
+        }
 
-    cmd.arg("clone")
-        .arg("--bare")
-        .arg("--depth")
-        .arg("1")
-        .arg("--single-branch");
+        rename(staging.path(), target_dir)
+            .await
+            .map_err(Error::ReplaceTargetError)?;
 
-    if let Some(branch) = branch {
-        cmd.arg("--branch").arg(branch);
-    };
+        Ok(hash)
+    }
+    .await;
 
-    let out = cmd
-        .arg(repo)
-        .arg(dir)
-        .output()
-        .await
-        .map_err(Error::CommandSpawnError)?;
+    // Once it's renamed into place, the staging directory is the target, so it's only removed when the checkout failed.
+    if result.is_ok() {
+        let _ = staging.into_path();
+        return result;
+    }
+    close_temp_dir(staging, result).await
+}
 
-    if !out.status.success() {
-        return Err(Error::GitCloneError(
-            String::from_utf8_lossy(&out.stderr).to_string(),
-        ));
+// Resolves symlinks in the target directory, including the target itself, e.g. `~/work -> /mnt/work`,
+// so that it's checked, staged and linked into on the filesystem it's really on, rather than replaced by a directory.
+// Directories that don't exist yet are kept as they are, as the checkout creates them.
+fn resolve_target_dir(target_dir: &Path) -> PathBuf {
+    let mut existing = target_dir;
+    let mut missing = vec![];
+    loop {
+        let path = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        if let Ok(resolved) = path.canonicalize() {
+            return missing
+                .iter()
+                .rev()
+                .fold(resolved, |resolved, name| resolved.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return target_dir.to_path_buf(),
+        }
     }
+}
 
-    Ok(())
+// Whether the cln-store and the target directory, or the nearest directory above it that exists, are on the same filesystem.
+// If it can't be told, they're assumed to be, so that objects are hard linked as usual.
+fn is_same_filesystem(store_path: &Path, target_dir: &Path) -> bool {
+    let Ok(store_metadata) = store_path.metadata() else {
+        return true;
+    };
+
+    target_dir
+        .ancestors()
+        .find_map(|dir| dir.metadata().ok())
+        .map_or(true, |target_metadata| {
+            target_metadata.dev() == store_metadata.dev()
+        })
 }
 
-async fn slow_clone_repo(repo: &str, dir: &Path, branch: Option<&str>) -> Result<(), Error> {
-    let out = Command::new("git")
-        .arg("clone")
-        .arg(repo)
-        .arg(dir)
-        .output()
-        .await
-        .map_err(Error::CommandSpawnError)?;
+fn is_empty_dir(dir: &Path) -> bool {
+    dir.read_dir()
+        .is_ok_and(|mut entries| entries.next().is_none())
+}
 
-    if !out.status.success() {
-        return Err(Error::GitCloneError(
-            String::from_utf8_lossy(&out.stderr).to_string(),
-        ));
+// Materializes the reference into the target directory,
+// returning the hash it resolved to, and the mirror it was cloned from if it wasn't the repo itself.
+async fn checkout(
+    repo: &str,
+    target_dir: &Path,
+    branch: Option<&str>,
+    remote_ref: &str,
+    options: Arc<ClnOptions>,
+) -> Result<(String, Option<String>), Error> {
+    if let Some(source) = &options.source {
+        let hash = source.resolve_ref(remote_ref).await?;
+        link_from_store(target_dir, &hash, Arc::clone(source), Arc::clone(&options)).await?;
+
+        return Ok((hash, None));
     }
 
-    if let Some(branch) = branch {
-        let out = Command::new("git")
-            .arg("checkout")
-            .arg(branch)
-            .current_dir(dir)
-            .output()
-            .await
-            .map_err(Error::CommandSpawnError)?;
+    if let Some(local_repo) = local_repo_path(repo) {
+        if let Ok(hash) = rev_parse(&local_repo, remote_ref).await {
+            if is_fully_stored(&hash, &options).await? {
+                let source = git_cli_source(repo, branch, &options);
+                link_from_store(target_dir, &hash, source, options).await?;
+            } else {
+                debug!("Reading {} from local repo {}", hash, local_repo.display());
+                walk_local_repo(&local_repo, &hash, target_dir, options).await?;
+            }
 
-        if !out.status.success() {
-            return Err(Error::GitCloneError(
-                String::from_utf8_lossy(&out.stderr).to_string(),
-            ));
+            return Ok((hash, None));
         }
+
+        debug!(
+            "Failed to resolve {} in local repo {}, cloning it",
+            remote_ref,
+            local_repo.display()
+        );
     }
 
-    Ok(())
-}
+    // Mirrors are tried in order when the repo, or a mirror before them, can't be listed or cloned.
+    // Each is asked for the same reference, so a mirror that's behind may resolve it to an older commit.
+    // A full commit hash, e.g. from a lockfile, is already resolved, and ls-remote only lists references, so it's skipped.
+    let mut unknown = None;
+    let mut clone_error = None;
+    let remotes = std::iter::once(repo)
+        .chain(options.mirrors.iter().map(String::as_str))
+        .filter(|_| !is_full_commit_hash(remote_ref));
+    for remote in remotes {
+        let ls_remote =
+            match run_ls_remote(remote, remote_ref, &options.git_config, &options.parse_pool).await
+            {
+                Ok(ls_remote) => ls_remote,
+                Err(e) => {
+                    debug!("Failed to list the references of {remote}: {e}");
+                    continue;
+                }
+            };
+        let Ok(ls_remote_hash) = ls_remote.get_hash() else {
+            // The remote is reachable, but doesn't have the reference.
+            unknown.get_or_insert((remote, ls_remote));
+            continue;
+        };
 
-struct LsRemoteRow {
-    hash: String,
-    name: String,
-}
+        match checkout_remote(remote, target_dir, branch, &ls_remote_hash, &options).await {
+            Ok(()) => {
+                if remote != repo {
+                    warn!("Checked out {repo} from mirror {remote}");
+                }
+                return Ok((ls_remote_hash, (remote != repo).then(|| remote.to_string())));
+            }
+            Err(e @ Error::GitCloneError(_)) if !options.mirrors.is_empty() => {
+                warn!("Failed to clone {remote}: {e}");
+                clone_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(e) = clone_error {
+        return Err(e);
+    }
 
-impl LsRemoteRow {
-    fn new(row: &str) -> Self {
-        let mut row_iter = row.split_whitespace();
-        let hash = row_iter
-            .next()
-            .expect("Failed to find hash in LsRemoteRow")
-            .to_string();
-        let name = row_iter.collect::<Vec<&str>>().join(" ");
-        Self { hash, name }
+    // Unless it's a commit hash, which ls-remote doesn't list, the slow path won't find an unknown reference either.
+    if let Some((remote, ls_remote)) = unknown {
+        if !is_commit_hash(remote_ref) {
+            return Err(
+                unknown_reference(remote, remote_ref, &ls_remote, &options.git_config).await,
+            );
+        }
     }
-}
 
-struct LsRemote {
-    rows: Vec<LsRemoteRow>,
+    if is_fully_stored(remote_ref, &options).await? {
+        let source = git_cli_source(repo, branch, &options);
+        link_from_store(target_dir, remote_ref, source, options).await?;
+
+        return Ok((remote_ref.to_string(), None));
+    }
+
+    let tempdir = create_temp_dir(options.temp_dir.as_deref())?;
+    let tempdir_path = tempdir.path();
+
+    let result = async {
+        let clone_path =
+            clone_reference(repo, tempdir_path, branch, remote_ref, &options.git_config).await?;
+        walk_local_repo(&clone_path, remote_ref, target_dir, options).await?;
+        rev_parse(&clone_path, remote_ref).await
+    }
+    .await;
+
+    close_temp_dir(tempdir, result)
+        .await
+        .map(|hash| (hash, None))
 }
 
-impl LsRemote {
-    fn new(ls_remote: &str, reference: &str) -> Self {
-        let rows = ls_remote
-            .lines()
-            .par_bridge()
-            .map(LsRemoteRow::new)
-            .filter(|row| match row.name.as_str() {
-                _ if row.name == reference => true,
-                _ if row.name == format!("refs/tags/{reference}") => true,
-                _ if row.name == format!("refs/heads/{reference}") => true,
-                _ => false,
-            })
-            .collect::<Vec<LsRemoteRow>>();
-        Self { rows }
+// Checks out a hash listed by `remote`, from the cln-store, the reference repo, or a clone of `remote`, in that order.
+async fn checkout_remote(
+    remote: &str,
+    target_dir: &Path,
+    branch: Option<&str>,
+    hash: &str,
+    options: &Arc<ClnOptions>,
+) -> Result<(), Error> {
+    if is_fully_stored(hash, options).await? {
+        let source = git_cli_source(remote, branch, options);
+        return link_from_store(target_dir, hash, source, Arc::clone(options)).await;
     }
-    fn get_hash(&self) -> Result<String, Error> {
-        if self.rows.is_empty() {
-            return Err(Error::NoMatchingReferenceError);
+
+    if let Some(reference_repo) = &options.reference_repo {
+        if rev_parse(reference_repo, hash).await.is_ok() {
+            debug!(
+                "Reading {} from reference repo {}",
+                hash,
+                reference_repo.display()
+            );
+            return walk_local_repo(reference_repo, hash, target_dir, Arc::clone(options)).await;
         }
-        Ok(self.rows[0].hash.clone())
+
+        debug!(
+            "Reference repo {} doesn't contain {}, cloning {}",
+            reference_repo.display(),
+            hash,
+            remote
+        );
+    }
+
+    let tmp_dir = create_temp_dir(options.temp_dir.as_deref())?;
+    let tmp_dir_path = tmp_dir.path();
+
+    debug!("Cloning {} into {}", remote, tmp_dir_path.display());
+    let result = async {
+        clone_repo(
+            remote,
+            tmp_dir_path,
+            branch,
+            !options.all_branches,
+            options.clone_filter.as_deref(),
+            options.progress.as_ref(),
+            &options.git_config,
+        )
+        .await?;
+        walk_local_repo(tmp_dir_path, hash, target_dir, Arc::clone(options)).await
     }
+    .await;
+
+    close_temp_dir(tmp_dir, result).await
 }
 
-async fn run_ls_remote(repo: &str, reference: &str) -> Result<LsRemote, Error> {
-    let output = Command::new("git")
-        .args(["ls-remote", repo, reference])
-        .output()
-        .await
-        .map_err(Error::CommandSpawnError)?;
-    let stdout = String::from_utf8(output.stdout)?;
-    let stdout = stdout.trim_end();
-    Ok(LsRemote::new(stdout, reference))
+// The path of the repository if it's on the local filesystem, as a `file://` URL or a plain path.
+fn local_repo_path(repo: &str) -> Option<PathBuf> {
+    let path = Path::new(repo.strip_prefix("file://").unwrap_or(repo));
+    path.is_dir().then(|| path.to_path_buf())
 }
 
-// Struct for parsing the rows of stdout from the `git ls-tree` command
-#[derive(Debug)]
-struct TreeRow {
-    mode: String,
-    otype: String,
-    name: String,
-    path: String,
+// Walks the tree of a hash straight out of a repository on the local filesystem, writing its objects to the cln-store.
+async fn walk_local_repo(
+    local_repo: &Path,
+    hash: &str,
+    target_dir: &Path,
+    options: Arc<ClnOptions>,
+) -> Result<(), Error> {
+    let resolved = rev_parse(local_repo, hash)
+        .await
+        .unwrap_or_else(|_| hash.to_string());
+    ensure_target_matches(target_dir, &resolved, &options).await?;
+    let head_tree = local_repo
+        .ls_tree(
+            &options.store,
+            hash,
+            ".".to_string(),
+            &options.git_config,
+            &options.parse_pool,
+        )
+        .await?;
+    create_dirs(target_dir, &options).await?;
+    Arc::<Path>::from(local_repo)
+        .walk(head_tree, Arc::from(target_dir), options)
+        .await
 }
 
-impl TreeRow {
-    fn new(row: &str) -> Self {
-        let mut row_iter = row.split_whitespace();
-        let mode = row_iter
-            .next()
-            .expect("Failed to find mode in TreeRow")
-            .to_string();
-        let otype = row_iter
-            .next()
-            .expect("Failed to find otype in TreeRow")
-            .to_string();
-        let name = row_iter
-            .next()
-            .expect("Failed to find name in TreeRow")
-            .to_string();
-        let path = row_iter.collect::<Vec<&str>>().join(" ");
-        Self {
-            mode,
-            otype,
-            name,
-            path,
+fn git_cli_source(repo: &str, branch: Option<&str>, options: &ClnOptions) -> Arc<dyn ObjectSource> {
+    let mut source = GitCliSource::new(repo, branch).git_config(options.git_config.clone());
+    if let Some(temp_dir) = &options.temp_dir {
+        source = source.temp_dir(temp_dir.clone());
+    }
+    if let Some(filter) = &options.clone_filter {
+        source = source.filter(filter);
+    }
+    if let Some(progress) = &options.progress {
+        source = source.progress(progress.clone());
+    }
+    Arc::new(source)
+}
+
+// Links the tree of a hash into the target directory from the cln-store,
+// reading anything missing from the cln-store from the source.
+#[instrument(skip_all, fields(hash = %hash))]
+async fn link_from_store(
+    target_dir: &Path,
+    hash: &str,
+    source: Arc<dyn ObjectSource>,
+    options: Arc<ClnOptions>,
+) -> Result<(), Error> {
+    ensure_target_matches(target_dir, hash, &options).await?;
+    let stored_tree = Arc::new(StoredTree { source });
+    let head_tree = stored_tree
+        .fetch_tree(hash, ".".to_string(), &options)
+        .await?;
+    create_dirs(target_dir, &options).await?;
+    stored_tree
+        .walk(head_tree, Arc::from(target_dir), options)
+        .await
+}
+
+/// Clones several repositories concurrently, sharing the cln-store between the builders that use the same store path.
+///
+/// Each clone reads and writes the cln-store of its own builder, so builders can use different store paths.
+/// A failed clone doesn't stop the others; the result of each is returned in the order the builders were given.
+pub async fn cln_all<I>(builders: I) -> Vec<Result<(), Error>>
+where
+    I: IntoIterator<Item = Builder>,
+{
+    let tasks = builders
+        .into_iter()
+        .map(|builder| tokio::spawn(builder.cln()))
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        results.push(task.await.unwrap_or_else(|e| Err(Error::CloneTaskError(e))));
+    }
+
+    results
+}
+
+/// Checks out every repository in a lockfile written with `Builder::write_lock` at the commit it's pinned to.
+///
+/// The commits are already known, so no references are listed, and a commit that's already in the cln-store is linked without cloning.
+/// Relative directories are relative to the directory of the lockfile.
+/// Repositories are checked out one after another, stopping at the first that fails.
+///
+/// # Errors
+/// Will return an error if the lockfile cannot be read or is invalid, or any of its repositories fails to check out.
+pub async fn cln_locked(lockfile: &Path, store_path: Option<PathBuf>) -> Result<(), Error> {
+    let base = lockfile_base(lockfile);
+    for entry in read_lockfile(lockfile).await? {
+        debug!("Checking out locked {} at {}", entry.repo, entry.hash);
+        cln_with_options(
+            &entry.repo,
+            Some(base.join(&entry.dir)),
+            Some(&entry.hash),
+            store_path.clone(),
+            Arc::default(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Resolves the default branch of a git repository, the branch its `HEAD` points at, with `git ls-remote --symref`.
+///
+/// Returns `HEAD` if the repository doesn't advertise the branch its `HEAD` points at, e.g. if it's detached.
+///
+/// # Errors
+/// Will return an error if the git binary cannot be run, or the repository cannot be listed.
+pub async fn default_branch(repo: &str) -> Result<String, Error> {
+    ensure_git(None).await?;
+
+    resolve_default_branch(repo, &[]).await
+}
+
+async fn resolve_default_branch(
+    repo: &str,
+    git_config: &[(String, String)],
+) -> Result<String, Error> {
+    let ls_remote = run_ls_remote(repo, HEAD, git_config, &ParsePool::default()).await?;
+
+    Ok(ls_remote.head_symref.unwrap_or_else(|| HEAD.to_string()))
+}
+
+/// Warms the cln-store with the contents of a git repository, without creating a working directory.
+///
+/// The reference is resolved with `git ls-remote`, and if it is not already in the cln-store,
+/// the repository is cloned into a temporary directory and every blob and tree in it is written to the cln-store.
+/// Subsequent calls to `cln` for the same reference will link directly from the cln-store.
+///
+/// # Examples
+/// ```rust,no_run
+/// use cln::warm_store;
+/// use tempfile::Builder;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let store_path = Builder::new()
+///         .prefix("cln-store")
+///         .tempdir()
+///         .unwrap()
+///         .into_path();
+///
+///     warm_store("https://github.com/yhakbar/cln.git", None, Some(store_path)).await.unwrap();
+/// }
+/// ```
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be warmed.
+/// This can happen if:
+/// - The reference cannot be resolved on the remote.
+/// - The tempdir where the repository is cloned cannot be created.
+/// - The git command to clone the repository into the tempdir fails.
+/// - The contents of the repository cannot be written to the cln-store.
+pub async fn warm_store(
+    repo: &str,
+    branch: Option<&str>,
+    store_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    ensure_git(None).await?;
+    if let Some(branch) = branch {
+        validate_ref(branch).await?;
+    }
+    let options = Arc::new(ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    });
+
+    let remote_ref = branch.unwrap_or(HEAD);
+    let ls_remote_hash = resolve_remote_ref(repo, remote_ref, &[], &options.parse_pool).await?;
+
+    if verify_reachable(&ls_remote_hash, &options).await? {
+        debug!("{ls_remote_hash} is already in the cln-store");
+        return Ok(());
+    }
+
+    let tmp_dir = create_temp_dir(None)?;
+    let tmp_dir_path = tmp_dir.path();
+
+    debug!("Warming store from {} via {}", repo, tmp_dir_path.display());
+    let result = async {
+        clone_repo(repo, tmp_dir_path, branch, true, None, None, &[]).await?;
+
+        let head_tree = tmp_dir_path
+            .ls_tree(
+                &options.store,
+                &ls_remote_hash,
+                ".".to_string(),
+                &[],
+                &options.parse_pool,
+            )
+            .await?;
+        Arc::<Path>::from(tmp_dir_path)
+            .populate(head_tree, Arc::clone(&options))
+            .await
+    }
+    .await;
+
+    close_temp_dir(tmp_dir, result).await
+}
+
+/// Warms the cln-store with the latest commit of every branch of a git repository, in a single clone.
+///
+/// This downloads the tip of every branch, rather than of a single one, so it's only worth it
+/// when many branches of the repository are going to be checked out.
+/// Branches whose commits are already in the cln-store are skipped.
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be warmed.
+/// This can happen if:
+/// - The tempdir where the repository is cloned cannot be created.
+/// - The git command to clone the repository into the tempdir fails.
+/// - The branches of the clone cannot be listed.
+/// - The contents of the repository cannot be written to the cln-store.
+pub async fn warm_store_all_branches(repo: &str, store_path: Option<PathBuf>) -> Result<(), Error> {
+    ensure_git(None).await?;
+    let options = Arc::new(ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    });
+
+    let tmp_dir = create_temp_dir(None)?;
+    let tmp_dir_path = tmp_dir.path();
+
+    debug!(
+        "Warming store with every branch of {} via {}",
+        repo,
+        tmp_dir_path.display()
+    );
+    let result = async {
+        clone_repo(repo, tmp_dir_path, None, false, None, None, &[]).await?;
+
+        for hash in list_branch_commits(tmp_dir_path).await? {
+            if verify_reachable(&hash, &options).await? {
+                debug!("{hash} is already in the cln-store");
+                continue;
+            }
+
+            let head_tree = tmp_dir_path
+                .ls_tree(
+                    &options.store,
+                    &hash,
+                    ".".to_string(),
+                    &[],
+                    &options.parse_pool,
+                )
+                .await?;
+            Arc::<Path>::from(tmp_dir_path)
+                .populate(head_tree, Arc::clone(&options))
+                .await?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    close_temp_dir(tmp_dir, result).await
+}
+
+// Lists the commits the branches of a local repository point at, without duplicates.
+async fn list_branch_commits(dir: &Path) -> Result<Vec<String>, Error> {
+    let out = git()
+        .await
+        .args(["for-each-ref", "--format=%(objectname)", "refs/heads"])
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Err(Error::ReadObjectError(
+            String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+        ));
+    }
+
+    let mut commits = String::from_utf8(out.stdout)?
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    commits.sort_unstable();
+    commits.dedup();
+
+    Ok(commits)
+}
+
+/// Resolves a reference of a git repository, and lists every entry of its tree, without checking it out.
+///
+/// Entries are sorted by path, and only the listings of trees are fetched, so the sizes of files are unknown.
+/// The listings are written to the cln-store, so a later `cln` of the same reference doesn't need to fetch them again.
+///
+/// # Examples
+/// ```rust,no_run
+/// use cln::resolve_tree;
+/// use tempfile::Builder;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let store_path = Builder::new()
+///         .prefix("cln-store")
+///         .tempdir()
+///         .unwrap()
+///         .into_path();
+///
+///     let entries = resolve_tree("https://github.com/yhakbar/cln.git", None, Some(store_path))
+///         .await
+///         .unwrap();
+///     assert!(entries.iter().any(|entry| entry.path().ends_with("Cargo.toml")));
+/// }
+/// ```
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run.
+/// - The reference doesn't exist in the repository.
+/// - A tree cannot be read from the cln-store or the repository.
+pub async fn resolve_tree(
+    repo: &str,
+    branch: Option<&str>,
+    store_path: Option<PathBuf>,
+) -> Result<Vec<TreeEntry>, Error> {
+    ensure_git(None).await?;
+    if let Some(branch) = branch {
+        validate_ref(branch).await?;
+    }
+    let options = ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    };
+
+    let (_, rows) = fetch_rows(repo, branch, &options).await?;
+
+    Ok(rows
+        .iter()
+        .map(|(path, row)| TreeEntry::from_row(path.clone(), row))
+        .collect())
+}
+
+/// Resolves a reference of a git repository, and lists every file in its tree with its size, without checking it out.
+///
+/// Unlike `resolve_tree`, only files, including symlinks, are listed, and the size of every one is known.
+/// This is enough to diff the files of two references by their hashes, or to find how large a checkout will be.
+/// Sizes of files that are already in the cln-store are read from it,
+/// and the rest are looked up with `git cat-file -s` in a shallow clone of the repository.
+///
+/// ```rust,no_run
+/// use cln::list_tree;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let entries = list_tree("https://github.com/yhakbar/cln.git", None, None).await?;
+///     let bytes = entries.iter().filter_map(|entry| entry.size()).sum::<u64>();
+///     println!("{} files, {} bytes", entries.len(), bytes);
+///     Ok(())
+/// }
+/// ```
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run.
+/// - The reference doesn't exist in the repository.
+/// - A tree cannot be read from the cln-store or the repository.
+/// - The size of a file cannot be read from the cln-store or the repository.
+pub async fn list_tree(
+    repo: &str,
+    branch: Option<&str>,
+    store_path: Option<PathBuf>,
+) -> Result<Vec<TreeEntry>, Error> {
+    ensure_git(None).await?;
+    if let Some(branch) = branch {
+        validate_ref(branch).await?;
+    }
+    let options = ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    };
+
+    let (stored_tree, rows) = fetch_rows(repo, branch, &options).await?;
+    let mut entries = vec![];
+    for (path, row) in rows.iter().filter(|(_, row)| row.otype == "blob") {
+        entries.push(if is_content_stored(&options.store, &row.name) {
+            TreeEntry::from_stored_row(&options.store, path.clone(), row).await?
+        } else {
+            let size = stored_tree.blob_size(row, &options).await?;
+            TreeEntry::from_sized_row(path.clone(), row, size)
+        });
+    }
+
+    Ok(entries)
+}
+
+// Lists every row reachable from a reference, sorted by path, only fetching the listings of trees that aren't in the cln-store.
+// The tree the rows were read from is returned with them, so their blobs can be read from the same source.
+async fn fetch_rows(
+    repo: &str,
+    branch: Option<&str>,
+    options: &ClnOptions,
+) -> Result<(StoredTree, Vec<(PathBuf, TreeRow)>), Error> {
+    let hash = resolve_hash(repo, branch.unwrap_or(HEAD)).await?;
+
+    let stored_tree = StoredTree {
+        source: git_cli_source(repo, branch, options),
+    };
+    let mut rows = vec![];
+    let mut pending = vec![
+        stored_tree
+            .fetch_tree(&hash, ".".to_string(), options)
+            .await?,
+    ];
+    while let Some(tree) = pending.pop() {
+        for row in &tree.rows {
+            let path = tree_path(&tree, row);
+            if row.otype == "tree" {
+                pending.push(
+                    stored_tree
+                        .fetch_tree(&row.name, path.display().to_string(), options)
+                        .await?,
+                );
+            }
+            rows.push((path, row.clone()));
         }
     }
-    async fn write_to_store(&self, repo_dir: &RepoPath) -> Result<(), Error> {
-        let store_path = STORE_PATH.lock().await.clone();
+    rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok((stored_tree, rows))
+}
+
+/// Reads the contents of the file at `path` in the tree of a commit or tree `hash`, straight from the cln-store.
+///
+/// Nothing is fetched, so the tree and the file must already be in the cln-store, e.g. from a previous `cln` of the hash.
+///
+/// # Errors
+/// Will return an error if:
+/// - The tree, or any tree on the way to the file, isn't in the cln-store (`Error::NotInStoreError`).
+/// - There's no file at `path` in the tree (`Error::PathNotFoundError`).
+/// - The file cannot be read from the cln-store.
+pub async fn read_blob(
+    hash: &str,
+    path: &Path,
+    store_path: Option<PathBuf>,
+) -> Result<Vec<u8>, Error> {
+    let options = ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    };
+
+    let stored_tree = StoredTree {
+        source: Arc::new(StoreOnlySource),
+    };
+    stored_tree.read_file(hash, path, &options).await
+}
+
+/// Reads the contents of the file at `path` in a reference of a git repository, without checking it out.
+///
+/// Only the trees on the way to the file and the file itself are read,
+/// from the cln-store if they're there, otherwise from the repository, writing them to the cln-store.
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run.
+/// - The reference doesn't exist in the repository.
+/// - There's no file at `path` in the tree of the reference (`Error::PathNotFoundError`).
+/// - A tree or the file cannot be read from the cln-store or the repository.
+pub async fn cat_file(
+    repo: &str,
+    branch: Option<&str>,
+    path: &Path,
+    store_path: Option<PathBuf>,
+) -> Result<Vec<u8>, Error> {
+    ensure_git(None).await?;
+    if let Some(branch) = branch {
+        validate_ref(branch).await?;
+    }
+    let options = ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    };
+
+    let hash = resolve_hash(repo, branch.unwrap_or(HEAD)).await?;
+
+    let stored_tree = StoredTree {
+        source: git_cli_source(repo, branch, &options),
+    };
+    stored_tree.read_file(&hash, path, &options).await
+}
+
+/// Opens the file at `path` in a reference of a git repository for reading, without checking it out,
+/// so it can be streamed rather than read into memory like with `cat_file`.
+///
+/// The file is read from the cln-store, after writing it there from the repository if it's missing, as `cat_file` does.
+/// The reader stays valid even if the file is evicted from the cln-store while it's being read.
+///
+/// ```rust,no_run
+/// use cln::open_file;
+/// use std::path::Path;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut file = open_file("https://github.com/yhakbar/cln.git", None, Path::new("README.md"), None).await?;
+///     let mut out = tokio::fs::File::create("README.md").await?;
+///     tokio::io::copy(&mut file, &mut out).await?;
+///     Ok(())
+/// }
+/// ```
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run.
+/// - The reference doesn't exist in the repository.
+/// - There's no file at `path` in the tree of the reference (`Error::PathNotFoundError`).
+/// - A tree or the file cannot be read from the cln-store or the repository.
+pub async fn open_file(
+    repo: &str,
+    branch: Option<&str>,
+    path: &Path,
+    store_path: Option<PathBuf>,
+) -> Result<impl AsyncRead + Send + Unpin, Error> {
+    ensure_git(None).await?;
+    if let Some(branch) = branch {
+        validate_ref(branch).await?;
+    }
+    let options = ClnOptions {
+        store: ensure_cln_store_path(store_path).await?,
+        ..ClnOptions::default()
+    };
+
+    let hash = resolve_hash(repo, branch.unwrap_or(HEAD)).await?;
+
+    let stored_tree = StoredTree {
+        source: git_cli_source(repo, branch, &options),
+    };
+    let content_path = stored_tree.prepare_file(&hash, path, &options).await?;
+    File::open(&content_path)
+        .await
+        .map_err(|e| Error::ReadFileError(content_path.display().to_string(), e))
+}
+
+// Resolves a reference to a hash, in the repository itself if it's on the local filesystem.
+async fn resolve_hash(repo: &str, remote_ref: &str) -> Result<String, Error> {
+    let local_hash = match local_repo_path(repo) {
+        Some(local_repo) => rev_parse(&local_repo, remote_ref).await.ok(),
+        None => None,
+    };
+    match local_hash {
+        Some(hash) => Ok(hash),
+        None => resolve_remote_ref(repo, remote_ref, &[], &ParsePool::default()).await,
+    }
+}
+
+/// Using all those options can be cumbersome, so a builder is provided for a cleaner experience
+///
+/// ```rust,no_run
+/// use cln::Builder;
+/// use tempfile::Builder as TempBuilder;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let path = TempBuilder::new()
+///         .prefix("cln")
+///         .tempdir()
+///         .unwrap()
+///         .into_path();
+///
+///     let store_path = TempBuilder::new()
+///         .prefix("cln-store")
+///         .tempdir()
+///         .unwrap()
+///         .into_path();
+///
+///     Builder::new("https://github.com/yhakbar/cln.git")
+///         .dir(path)
+///         .store_path(store_path)
+///         .cln()
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Builder {
+    repo: String,
+    dir: Option<PathBuf>,
+    reference: Option<Reference>,
+    store_path: Option<PathBuf>,
+    options: ClnOptions,
+}
+
+impl Builder {
+    #[must_use]
+    pub fn new(repo: &str) -> Self {
+        Self {
+            repo: repo.to_string(),
+            dir: None,
+            reference: None,
+            store_path: None,
+            options: ClnOptions::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn dir(mut self, dir: PathBuf) -> Self {
+        self.dir = Some(dir);
+        self
+    }
+
+    #[must_use]
+    pub fn branch(mut self, branch: &str) -> Self {
+        self.reference = Some(Reference::Branch(branch.to_string()));
+        self
+    }
+
+    /// Experimental: also write the blobs and trees of the checkout to `objects/` in the cln-store as git loose objects,
+    /// so the cln-store can be used as an alternate object directory by plain git,
+    /// e.g. with `GIT_ALTERNATE_OBJECT_DIRECTORIES` or in `.git/objects/info/alternates`.
+    ///
+    /// Commits aren't kept in the cln-store, so only blobs and trees are written.
+    /// Loose objects are a second, compressed copy of each object, so they aren't counted towards `max_store_bytes`
+    /// and aren't evicted, pruned or exported with the rest of the cln-store.
+    #[must_use]
+    pub const fn loose_objects(mut self, loose_objects: bool) -> Self {
+        self.options.loose_objects = loose_objects;
+        self
+    }
+
+    /// Refuse to check out files larger than `max_file_size` bytes, failing with `Error::FileTooLargeError`.
+    ///
+    /// The size of each file is looked up before it's fetched, so a file that's too large is never downloaded or written to the cln-store.
+    /// Files left out by the filter are left out whatever their size.
+    #[must_use]
+    pub const fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.options.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// Leave files larger than `max_file_size` out of the checkout with a warning, rather than failing.
+    #[must_use]
+    pub const fn skip_large_files(mut self, skip_large_files: bool) -> Self {
+        self.options.skip_large_files = skip_large_files;
+        self
+    }
+
+    /// Treat the branch as a glob, e.g. `v1.*`, and check out the branch or tag matching it with the highest semantic version,
+    /// or the last by name if none of them are versions.
+    ///
+    /// This is off by default, so the branch has to match a reference exactly.
+    /// Annotated tags are matched by name, and check out the commit they point at.
+    ///
+    /// ```rust
+    /// use cln::Builder;
+    ///
+    /// let builder = Builder::new("https://github.com/yhakbar/cln.git")
+    ///     .branch("v*")
+    ///     .branch_glob(true);
+    /// ```
+    #[must_use]
+    pub const fn branch_glob(mut self, branch_glob: bool) -> Self {
+        self.options.branch_glob = branch_glob;
+        self
+    }
+
+    /// Check out a branch, tag, commit or full reference, in place of `branch`.
+    ///
+    /// A tag is only matched against tags, even if there's a branch of the same name.
+    /// A commit has to be a full hash, e.g. from a lockfile, and skips `git ls-remote`,
+    /// so checking it out again from the cln-store doesn't need the network.
+    /// Only a branch is treated as a glob by `branch_glob`.
+    ///
+    /// ```rust
+    /// use cln::{Builder, Reference};
+    ///
+    /// let builder = Builder::new("https://github.com/yhakbar/cln.git")
+    ///     .reference(Reference::Commit("0123456789012345678901234567890123456789".to_string()));
+    /// ```
+    #[must_use]
+    pub fn reference(mut self, reference: Reference) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    #[must_use]
+    pub fn store_path(mut self, store_path: PathBuf) -> Self {
+        self.store_path = Some(store_path);
+        self
+    }
+
+    /// Create directories for subtrees even if they contain no files.
+    ///
+    /// Git doesn't track empty directories, so by default (like `git clone`) a directory is only created
+    /// when a file is linked into it. Enable this for tooling that relies on the directory layout of the tree.
+    #[must_use]
+    pub const fn preserve_empty_dirs(mut self, preserve_empty_dirs: bool) -> Self {
+        self.options.preserve_empty_dirs = preserve_empty_dirs;
+        self
+    }
+
+    /// Choose what cln writes into the target directory.
+    ///
+    /// `OutputFormat::Git` additionally writes a minimal, shallow `.git` directory so git commands work in the checkout.
+    #[must_use]
+    pub const fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.options.output_format = output_format;
+        self
+    }
+
+    /// Keep checking out the rest of the tree when a blob or subtree fails, instead of stopping at the first error.
+    ///
+    /// The failures are collected and returned together as `Error::PartialCheckoutError` once the walk is done.
+    #[must_use]
+    pub const fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.options.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// Choose how files already in the target directory are treated.
+    ///
+    /// `OverwritePolicy::Sync` removes anything that isn't in the tree of the reference after the checkout.
+    #[must_use]
+    pub const fn overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.options.overwrite_policy = overwrite_policy;
+        self
+    }
+
+    /// Read objects from a local clone of the repository when they aren't in the cln-store,
+    /// instead of cloning the repository over the network, like `git clone --reference`.
+    ///
+    /// If the local clone doesn't contain the resolved commit, the repository is cloned as usual.
+    #[must_use]
+    pub fn reference_repo(mut self, reference_repo: PathBuf) -> Self {
+        self.options.reference_repo = Some(reference_repo);
+        self
+    }
+
+    /// Seed the cln-store from another cln-store, e.g. a shared, read-only one on a network mount,
+    /// before reading objects from the repository.
+    ///
+    /// Objects are hard linked from the reference store, or copied if it's on a different filesystem.
+    /// Reference stores are checked in the order they're added, and are never written to.
+    #[must_use]
+    pub fn reference_store(mut self, reference_store: PathBuf) -> Self {
+        self.options.reference_stores.push(reference_store);
+        self
+    }
+
+    /// Fall back to cloning from `mirror` when the repository, and any mirror added before it, can't be listed or cloned,
+    /// e.g. when the primary remote is down.
+    ///
+    /// Every mirror is asked for the same branch or tag. The mirror that was cloned from is logged,
+    /// and recorded in the metadata with `write_metadata`.
+    #[must_use]
+    pub fn mirror(mut self, mirror: &str) -> Self {
+        self.options.mirrors.push(mirror.to_string());
+        self
+    }
+
+    /// Read blobs missing from the cln-store from `object_cache` before reading them from the repository,
+    /// and put blobs read from the repository in it, e.g. a cache shared by CI runners.
+    ///
+    /// Object caches are checked in the order they're added, after reference stores.
+    /// A cache that fails to read or write a blob is logged and skipped, rather than failing the checkout,
+    /// but one that can't be used at all, e.g. an `HttpObjectCache` without curl installed, fails it before anything is checked out.
+    #[must_use]
+    pub fn object_cache<C: ObjectCache + 'static>(mut self, object_cache: C) -> Self {
+        self.options.object_caches.push(Arc::new(object_cache));
+        self
+    }
+
+    /// Set this mode on every directory created in the target directory, e.g. `0o755`, rather than leaving it to the umask.
+    ///
+    /// Directories that already exist are left alone.
+    /// The mode should let the owner write to the directory, or files cannot be linked into it.
+    #[must_use]
+    pub const fn dir_mode(mut self, dir_mode: u32) -> Self {
+        self.options.dir_mode = Some(dir_mode);
+        self
+    }
+
+    /// Write at most this many blobs and trees at once, rather than 16.
+    ///
+    /// Each of them runs at most one git process at a time, so this also caps the number of git processes a checkout spawns.
+    /// With the `rayon` feature, git output is parsed on a pool of this many threads of its own, rather than on rayon's global pool.
+    #[must_use]
+    pub const fn concurrency(mut self, concurrency: usize) -> Self {
+        self.options.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Write one blob or tree at a time, in path order, so that every checkout of the same tree
+    /// creates directories, links files and logs in the same order, e.g. for golden-file tests.
+    ///
+    /// The files checked out are the same either way, this only makes checkouts slower.
+    #[must_use]
+    pub const fn deterministic(mut self, deterministic: bool) -> Self {
+        self.options.deterministic = deterministic;
+        self
+    }
+
+    /// Leave out the files and directories marked `export-ignore` in the `.gitattributes` files of the tree,
+    /// like `git archive` does, e.g. tests and CI config that a release doesn't need.
+    ///
+    /// `.gitattributes` files in subdirectories are honoured too, taking precedence over those above them.
+    #[must_use]
+    pub const fn export_ignore(mut self, export_ignore: bool) -> Self {
+        self.options.export_ignore = export_ignore;
+        self
+    }
+
+    /// Keep the cln-store under `max_store_bytes` by evicting the least recently used objects after each checkout,
+    /// rather than letting it grow until it's pruned by hand.
+    ///
+    /// Defaults to the `CLN_MAX_STORE_BYTES` environment variable, if it's set.
+    /// The store is never evicted from when it's read-only.
+    #[must_use]
+    pub const fn max_store_bytes(mut self, max_store_bytes: u64) -> Self {
+        self.options.max_store_bytes = Some(max_store_bytes);
+        self
+    }
+
+    /// Send the progress of `git clone` to `progress` as it's cloning, e.g. to show a progress bar,
+    /// as the clone is usually the longest part of a checkout that isn't in the cln-store.
+    ///
+    /// Nothing is sent when the checkout doesn't need a clone.
+    #[must_use]
+    pub fn progress(mut self, progress: UnboundedSender<CloneProgress>) -> Self {
+        self.options.progress = Some(progress);
+        self
+    }
+
+    /// Clone only the branch being checked out (the default), or every branch of the repository.
+    ///
+    /// Cloning every branch brings down the tip of each of them in the same network operation,
+    /// e.g. to warm the cln-store for several references of the same repository.
+    /// The checkout itself is of the resolved reference either way.
+    #[must_use]
+    pub const fn single_branch(mut self, single_branch: bool) -> Self {
+        self.options.all_branches = !single_branch;
+        self
+    }
+
+    /// Pass `-c key=value` to every git command that clones, lists or reads from the repository,
+    /// e.g. `http.postBuffer` or `protocol.version` for a server that needs them, without changing the global git config.
+    ///
+    /// Can be called more than once to set several keys.
+    #[must_use]
+    pub fn git_config(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .git_config
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Re-hash every blob in the cln-store before linking it, failing with `Error::StoreCorruption` on a mismatch.
+    ///
+    /// This is slower, as it runs `git hash-object` for every file,
+    /// but guards against a corrupted or tampered cln-store, e.g. on a shared mount.
+    #[must_use]
+    pub const fn verify_on_link(mut self, verify_on_link: bool) -> Self {
+        self.options.verify_on_link = verify_on_link;
+        self
+    }
+
+    /// After the checkout, write a CSV manifest of every file to this path, with its path, blob hash and mode.
+    ///
+    /// Two checkouts can be compared by diffing their manifests, without reading any file contents.
+    #[must_use]
+    pub fn manifest(mut self, manifest: PathBuf) -> Self {
+        self.options.manifest = Some(manifest);
+        self
+    }
+
+    /// Create the temporary directory for the bare clone in this directory, instead of the system temp directory.
+    ///
+    /// Useful where the system temp directory is small, or on a different mount than the cln-store.
+    /// Blobs are always read out of the bare clone into the cln-store and then hard linked from there,
+    /// so the cln-store and target directory still need to share a filesystem for hard links to work.
+    #[must_use]
+    pub fn temp_dir(mut self, temp_dir: PathBuf) -> Self {
+        self.options.temp_dir = Some(temp_dir);
+        self
+    }
+
+    /// Resolve a relative target directory, including the one derived from the repository name,
+    /// against this directory instead of the current working directory.
+    ///
+    /// This acts as a prefix shared by several checkouts, e.g. `vendor` to check each repository out into `vendor/<repo>`.
+    /// Missing directories in it are created.
+    #[must_use]
+    pub fn output_base(mut self, output_base: PathBuf) -> Self {
+        self.options.output_base = Some(output_base);
+        self
+    }
+
+    /// Only link the files for which `filter` returns `true` into the target directory.
+    ///
+    /// Blobs may still be written to the cln-store, so a later checkout with a different filter doesn't need to fetch them again.
+    ///
+    /// ```rust
+    /// use cln::Builder;
+    ///
+    /// let builder = Builder::new("https://github.com/yhakbar/cln.git")
+    ///     .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "rs"));
+    /// ```
+    #[must_use]
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&TreeEntry) -> bool + Send + Sync + 'static,
+    {
+        self.options.filter = Some(Filter::new(filter));
+        self
+    }
+
+    /// Leave the files and directories matching the glob `pattern` out of the checkout, e.g. `test/fixtures/**` or `*.mp4`.
+    ///
+    /// Can be called more than once, to exclude everything that matches any of the patterns.
+    /// Patterns without a slash match the name of a file or directory at any depth, and the rest match its whole path from the root of the tree.
+    /// Unlike `filter`, excluded files are never fetched or written to the cln-store, and excluded directories aren't walked.
+    ///
+    /// The patterns are checked when the checkout starts, failing with `Error::InvalidPatternError` if one isn't a valid glob.
+    #[must_use]
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.options.exclude_patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Build the checkout in a temporary directory next to the target, and only move it into place once it's complete.
+    ///
+    /// If the checkout fails, the temporary directory is removed and the target directory is left untouched.
+    /// An existing, non-empty target directory is an error, unless `force` is also set.
+    #[must_use]
+    pub const fn atomic(mut self, atomic: bool) -> Self {
+        self.options.atomic = atomic;
+        self
+    }
+
+    /// With `atomic`, replace an existing target directory once the new checkout is complete.
+    #[must_use]
+    pub const fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    /// Drop the first `strip_components` directories from the path of every file, like `tar --strip-components`.
+    ///
+    /// Files that are less than `strip_components` directories deep aren't checked out.
+    #[must_use]
+    pub const fn strip_components(mut self, strip_components: usize) -> Self {
+        self.options.strip_components = strip_components;
+        self
+    }
+
+    /// Also check the files out into `mirror_to`, e.g. a backup of the target directory.
+    ///
+    /// Can be called more than once, for several mirrors. Every file is linked into the target directory and each mirror in the same walk,
+    /// so the reference is only resolved, cloned and written to the cln-store once, rather than once per directory.
+    /// Objects are copied into a mirror on a different filesystem to the cln-store, rather than hard linked.
+    ///
+    /// Only files are mirrored, and `OverwritePolicy::Sync` syncs the mirrors too.
+    /// Metadata, like the `.git` directory written for `OutputFormat::Git`, is only written to the target directory,
+    /// and with `atomic`, only the target directory is moved into place atomically.
+    #[must_use]
+    pub fn mirror_to(mut self, mirror_to: PathBuf) -> Self {
+        self.options.mirror_to.push(mirror_to);
+        self
+    }
+
+    /// Only check out the files under `strip_prefix`, a directory relative to the root of the tree, making it the root of the checkout.
+    ///
+    /// e.g. `src` checks out `src/main.rs` as `main.rs`. Files outside of it are never fetched or written to the cln-store.
+    /// It's applied before `strip_components` and `add_prefix`.
+    ///
+    /// A prefix that isn't a relative path without `..` fails the checkout with `Error::InvalidPrefixError`.
+    #[must_use]
+    pub fn strip_prefix(mut self, strip_prefix: PathBuf) -> Self {
+        self.options.strip_prefix = Some(strip_prefix);
+        self
+    }
+
+    /// Check every file out under `add_prefix`, a directory relative to the target directory, e.g. `vendor/cln`.
+    ///
+    /// This places several repositories side by side in one directory without their files overlapping.
+    /// Metadata, like the `.git` directory written for `OutputFormat::Git`, stays at the root of the target directory,
+    /// and syncing with `OverwritePolicy::Sync` still removes anything outside of the prefix.
+    ///
+    /// A prefix that isn't a relative path without `..` fails the checkout with `Error::InvalidPrefixError`.
+    #[must_use]
+    pub fn add_prefix(mut self, add_prefix: PathBuf) -> Self {
+        self.options.add_prefix = Some(add_prefix);
+        self
+    }
+
+    /// When no branch is given, check out the repository's default branch by name, rather than the literal `HEAD`.
+    ///
+    /// The default branch is resolved with `git ls-remote --symref`, falling back to `HEAD` if it isn't advertised.
+    /// The branch name is then what's recorded by `write_metadata`, and passed to `post_checkout` as `CLN_REF`.
+    #[must_use]
+    pub const fn follow_default_branch(mut self, follow_default_branch: bool) -> Self {
+        self.options.follow_default_branch = follow_default_branch;
+        self
+    }
+
+    /// Keep the objects of this repository in its own namespace of the cln-store, a subdirectory named after a hash of the repository URL.
+    ///
+    /// This isolates repositories from each other, e.g. to prune or limit the size of the store per repository,
+    /// at the cost of deduplication: a file in several repositories is stored once per namespace.
+    /// Objects already in the shared cln-store are hard linked into the namespace, rather than fetched again.
+    #[must_use]
+    pub const fn namespace_store(mut self, namespace_store: bool) -> Self {
+        self.options.namespace_store = namespace_store;
+        self
+    }
+
+    /// Record the repository, reference and resolved commit in `.cln/HEAD` in the target directory,
+    /// to be read back with `read_metadata`.
+    ///
+    /// This is off by default, so that the target directory only contains the files of the tree, like `git clone`.
+    #[must_use]
+    pub const fn write_metadata(mut self, write_metadata: bool) -> Self {
+        self.options.write_metadata = write_metadata;
+        self
+    }
+
+    /// Copy files into the target directory, writable by their owner, rather than hard linking them read-only from the cln-store.
+    ///
+    /// By default, every checkout of a file is a hard link to the same object in the cln-store, so they share one inode.
+    /// Objects are read-only so that they aren't edited by accident, as editing a hard linked file in place,
+    /// e.g. after `chmod +w`, changes it in the cln-store and in every other checkout of it.
+    /// Writable checkouts are independent copies, like a `git clone`, trading the disk and time saved by linking for that.
+    #[must_use]
+    pub const fn writable(mut self, writable: bool) -> Self {
+        self.options.writable = writable;
+        self
+    }
+
+    /// Copy the files for which `filter` returns `true` into the target directory writable, as with `writable`,
+    /// and hard link the rest read-only from the cln-store.
+    ///
+    /// This keeps the files that are edited in place, e.g. config files or scripts, independent of every other checkout,
+    /// while the rest of the tree is still shared.
+    ///
+    /// ```rust
+    /// use cln::Builder;
+    ///
+    /// let builder = Builder::new("https://github.com/yhakbar/cln.git")
+    ///     .writable_if(|entry| entry.path().extension().is_some_and(|extension| extension == "toml"));
+    /// ```
+    #[must_use]
+    pub fn writable_if<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&TreeEntry) -> bool + Send + Sync + 'static,
+    {
+        self.options.writable_filter = Some(Filter::new(filter));
+        self
+    }
+
+    /// Record the repository and the commit it resolved to in `lockfile`, to be checked out again with `cln_locked`.
+    ///
+    /// An entry for the same target directory is replaced, and the lockfile is created if it doesn't exist.
+    /// The target directory is recorded relative to the lockfile when it's below it.
+    #[must_use]
+    pub fn write_lock(mut self, lockfile: PathBuf) -> Self {
+        self.options.lockfile = Some(lockfile);
+        self
+    }
+
+    /// Make the temporary bare clone a partial clone with this filter, e.g. `blob:none`, passed to `git clone --filter`.
+    ///
+    /// Blobs left out of the clone are fetched one at a time as they're read into the cln-store,
+    /// which downloads less for large repositories when only a single tree is needed.
+    /// The remote has to support partial clones.
+    #[must_use]
+    pub fn clone_filter(mut self, filter: &str) -> Self {
+        self.options.clone_filter = Some(filter.to_string());
+        self
+    }
+
+    /// Don't use the cln-store at all, e.g. for a one-off clone in CI, where caching objects would only waste disk.
+    ///
+    /// Files are written straight into the target directory from the clone, so the checkout is the only copy of them.
+    /// Tree listings, and files from sources that can't be streamed, go to a scratch store next to the target directory,
+    /// which is removed afterwards. Nothing is read from, or written to, the cln-store.
+    #[must_use]
+    pub const fn no_store(mut self, no_store: bool) -> Self {
+        self.options.no_store = no_store;
+        self
+    }
+
+    /// Never write to the cln-store, e.g. when it's a cache mounted read-only that another job warms.
+    ///
+    /// Objects in the cln-store are linked, or copied if they can't be, into a scratch store next to the target directory.
+    /// Anything missing from the cln-store is fetched into the scratch store instead,
+    /// which is removed once the checkout is done, so misses are never cached.
+    #[must_use]
+    pub const fn store_read_only(mut self, store_read_only: bool) -> Self {
+        self.options.store_read_only = store_read_only;
+        self
+    }
+
+    /// Run a shell command in the target directory once the checkout has succeeded.
+    ///
+    /// The command is run with `sh -c`, with `CLN_REPO`, `CLN_REF`, `CLN_HASH` and `CLN_TARGET` set in its environment.
+    /// If it fails, `cln` returns `Error::PostCheckoutFailed` with its exit status and stderr.
+    ///
+    /// With `write_metadata`, the command is skipped when the metadata shows the target directory already had the same commit checked out,
+    /// so re-running `cln` when nothing has changed doesn't re-run e.g. a build.
+    #[must_use]
+    pub fn post_checkout(mut self, post_checkout: &str) -> Self {
+        self.options.post_checkout = Some(post_checkout.to_string());
+        self
+    }
+
+    /// Read references and objects that aren't in the cln-store from this source, instead of with the `git` CLI.
+    ///
+    /// The repository passed to `Builder::new` is then only used to name the target directory.
+    /// Options that run git themselves, like `OutputFormat::Git` and `verify_on_link`, still need git installed.
+    #[must_use]
+    pub fn source<S: ObjectSource + 'static>(mut self, source: S) -> Self {
+        self.options.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Use a git binary other than the `git` found on the `PATH`.
+    #[must_use]
+    pub fn git_binary(mut self, git_binary: PathBuf) -> Self {
+        self.options.git_binary = Some(git_binary);
+        self
+    }
+
+    /// # Errors
+    /// Will return an error if the repository cannot be clned.
+    /// This can happen if:
+    /// - The git binary cannot be run.
+    /// - The tempdir where the repository is cloned cannot be created.
+    /// - The git command to clone the repository into the tempdir fails.
+    /// - The new directory where the repository is copied to cannot be created.
+    /// - The temporary directory cannot be persisted to the cln-store.
+    /// - The hard links from the cln-store to the new directory fail.
+    /// - The reference is a commit that isn't a full hash.
+    /// - An object cache cannot be used, e.g. curl isn't installed for an `HttpObjectCache`.
+    pub async fn cln(mut self) -> Result<(), Error> {
+        let remote_ref = self
+            .reference
+            .as_ref()
+            .map(Reference::remote_ref)
+            .transpose()?;
+        if !matches!(self.reference, None | Some(Reference::Branch(_))) {
+            self.options.branch_glob = false;
+        }
+        cln_with_options(
+            &self.repo,
+            self.dir,
+            remote_ref.as_deref(),
+            self.store_path,
+            Arc::new(self.options),
+        )
+        .await
+    }
+}
+
+fn create_temp_dir(temp_dir: Option<&Path>) -> Result<TempDir, Error> {
+    let mut builder = TempBuilder::new();
+    builder.prefix("cln");
+
+    temp_dir
+        .map_or_else(|| builder.tempdir(), |dir| builder.tempdir_in(dir))
+        .map_err(Error::TempDirError)
+}
+
+// Removes a temporary directory once the work in it is done, whether or not it succeeded.
+// A failure to remove it is reported, unless the work already failed, in which case it's logged and that error wins.
+// A bare clone can be large, so it's removed on the blocking thread pool rather than in `TempDir`'s `Drop` on the runtime.
+async fn close_temp_dir<T>(tmp_dir: TempDir, result: Result<T, Error>) -> Result<T, Error> {
+    let closed = spawn_blocking(move || tmp_dir.close())
+        .await
+        .map_err(io::Error::other)
+        .and_then(|closed| closed)
+        .map_err(Error::TempDirCloseError);
+    match (result, closed) {
+        (Ok(value), Ok(())) => Ok(value),
+        (Ok(_), Err(e)) | (Err(e), Ok(())) => Err(e),
+        (Err(e), Err(close_error)) => {
+            warn!("Failed to remove temporary directory: {close_error}");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(skip_all, fields(repo = %repo, branch = ?branch))]
+async fn clone_repo(
+    repo: &str,
+    dir: &Path,
+    branch: Option<&str>,
+    single_branch: bool,
+    filter: Option<&str>,
+    progress: Option<&UnboundedSender<CloneProgress>>,
+    git_config: &[(String, String)],
+) -> Result<(), Error> {
+    // `git clone --branch` only takes the short name of a branch or tag,
+    // so full references, e.g. `refs/pull/123/head`, and commit hashes are fetched into an empty repository instead.
+    if let Some(reference) =
+        branch.filter(|branch| is_full_ref(branch) || is_full_commit_hash(branch))
+    {
+        debug!("Fetching {reference} from {repo}");
+        return fetch_reference(repo, dir, reference, filter, git_config).await;
+    }
+
+    let mut cmd = git_with_config(git_config).await;
+
+    cmd.arg("clone").arg("--bare").arg("--depth").arg("1");
+
+    // Objects the filter leaves out are fetched from the remote when they're read with `git cat-file`.
+    if let Some(filter) = filter {
+        cmd.arg(format!("--filter={filter}"));
+    }
+
+    // `--depth` implies `--single-branch`, so fetching every branch has to be asked for explicitly.
+    if single_branch {
+        cmd.arg("--single-branch");
+    } else {
+        cmd.arg("--no-single-branch");
+    }
+
+    if let Some(branch) = branch {
+        cmd.arg(format!("--branch={branch}"));
+    }
+
+    let Some(progress) = progress else {
+        let out = cmd
+            .arg("--")
+            .arg(repo)
+            .arg(dir)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+
+        if !out.status.success() {
+            return Err(Error::GitCloneError(
+                String::from_utf8_lossy(&out.stderr).to_string(),
+            ));
+        }
+
+        return Ok(());
+    };
+
+    // git only reports progress to a terminal unless asked to, and redraws it on stderr as it goes.
+    let mut child = cmd
+        .arg("--progress")
+        .arg("--")
+        .arg(repo)
+        .arg(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::CommandSpawnError)?;
+    let stderr = child.stderr.take().ok_or_else(|| {
+        Error::GitCloneError("Failed to read the stderr of git clone".to_string())
+    })?;
+    let messages = read_progress(stderr, progress)
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    let status = child.wait().await.map_err(Error::CommandSpawnError)?;
+
+    if !status.success() {
+        return Err(Error::GitCloneError(messages));
+    }
+
+    Ok(())
+}
+
+// Clones the repo under `dir` to check the reference out of it, returning the path of the clone.
+// A full commit hash is fetched on its own first, as servers that allow it, e.g. GitHub, then only send that commit,
+// falling back to cloning every branch to find it.
+async fn clone_reference(
+    repo: &str,
+    dir: &Path,
+    branch: Option<&str>,
+    reference: &str,
+    git_config: &[(String, String)],
+) -> Result<PathBuf, Error> {
+    if is_full_commit_hash(reference) {
+        let commit_path = dir.join("commit");
+        debug!(
+            "Fetching {} from {} into {}",
+            reference,
+            repo,
+            commit_path.display()
+        );
+        match fetch_reference(repo, &commit_path, reference, None, git_config).await {
+            Ok(()) => return Ok(commit_path),
+            Err(e) => debug!("Failed to fetch {reference} from {repo}: {e}"),
+        }
+    }
+
+    let clone_path = dir.join("clone");
+    debug!("Slow cloning {} into {}", repo, clone_path.display());
+    slow_clone_repo(repo, &clone_path, branch, git_config).await?;
+
+    Ok(clone_path)
+}
+
+// Fetches a single commit, without its history, into a new bare repository.
+// A full reference, e.g. `refs/pull/123/head`, is fetched to the same reference, so it resolves in the repository as in a clone.
+async fn fetch_reference(
+    repo: &str,
+    dir: &Path,
+    reference: &str,
+    filter: Option<&str>,
+    git_config: &[(String, String)],
+) -> Result<(), Error> {
+    let out = git()
+        .await
+        .args(["init", "--quiet", "--bare"])
+        .arg(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !out.status.success() {
+        return Err(Error::GitCloneError(
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        ));
+    }
+
+    let mut cmd = git_with_config(git_config).await;
+    cmd.args(["fetch", "--quiet", "--depth", "1"]);
+    if let Some(filter) = filter {
+        cmd.arg(format!("--filter={filter}"));
+    }
+    let refspec = if reference.starts_with("refs/") {
+        format!("+{reference}:{reference}")
+    } else {
+        reference.to_string()
+    };
+    let out = cmd
+        .args(["--", repo, &refspec])
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !out.status.success() {
+        return Err(Error::GitCloneError(
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn slow_clone_repo(
+    repo: &str,
+    dir: &Path,
+    branch: Option<&str>,
+    git_config: &[(String, String)],
+) -> Result<(), Error> {
+    let out = git_with_config(git_config)
+        .await
+        .arg("clone")
+        .arg("--")
+        .arg(repo)
+        .arg(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Err(Error::GitCloneError(
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        ));
+    }
+
+    if let Some(branch) = branch {
+        let out = git()
+            .await
+            .arg("checkout")
+            .arg("--end-of-options")
+            .arg(branch)
+            .current_dir(dir)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+
+        if !out.status.success() {
+            return Err(Error::GitCloneError(
+                String::from_utf8_lossy(&out.stderr).to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn rev_parse(dir: &Path, reference: &str) -> Result<String, Error> {
+    let out = git()
+        .await
+        .args(["rev-parse", "--verify", "--end-of-options"])
+        .arg(format!("{reference}^{{commit}}"))
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Err(Error::NoMatchingReferenceError);
+    }
+
+    Ok(String::from_utf8(out.stdout)?.trim_end().to_string())
+}
+
+// The thread pool git output is parsed on, with as many threads as `Builder::concurrency`,
+// so that parsing a large listing doesn't take every core.
+// Without a concurrency, rayon's global pool is used, as a library can't size that pool for the rest of the process.
+#[derive(Debug, Default, Clone)]
+struct ParsePool {
+    #[cfg(feature = "rayon")]
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl ParsePool {
+    #[cfg(feature = "rayon")]
+    fn new(threads: Option<usize>) -> Result<Self, Error> {
+        let pool = threads
+            .map(|threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads.max(1))
+                    .thread_name(|index| format!("cln-parse-{index}"))
+                    .build()
+                    .map(Arc::new)
+                    .map_err(|e| Error::ThreadPoolError(e.to_string()))
+            })
+            .transpose()?;
+
+        Ok(Self { pool })
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    #[allow(clippy::unnecessary_wraps)]
+    const fn new(_threads: Option<usize>) -> Result<Self, Error> {
+        Ok(Self {})
+    }
+
+    // Parses each line of git output that `parse` keeps, in the order of the lines.
+    // With the `rayon` feature, lines are parsed in parallel.
+    #[cfg(feature = "rayon")]
+    fn parse_lines<T, F>(&self, output: &str, parse: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&str) -> Option<T> + Send + Sync,
+    {
+        let parse = || output.par_lines().filter_map(parse).collect();
+        match &self.pool {
+            Some(pool) => pool.install(parse),
+            None => parse(),
+        }
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    #[allow(clippy::unused_self)]
+    fn parse_lines<T, F>(&self, output: &str, parse: F) -> Vec<T>
+    where
+        F: Fn(&str) -> Option<T>,
+    {
+        output.lines().filter_map(parse).collect()
+    }
+}
+
+struct LsRemoteRow {
+    hash: String,
+    name: String,
+}
+
+impl LsRemoteRow {
+    fn new(row: &str) -> Self {
+        let mut row_iter = row.split_whitespace();
+        let hash = row_iter
+            .next()
+            .expect("Failed to find hash in LsRemoteRow")
+            .to_string();
+        let name = row_iter.collect::<Vec<&str>>().join(" ");
+        Self { hash, name }
+    }
+}
+
+struct LsRemote {
+    rows: Vec<LsRemoteRow>,
+    // The branch the remote's `HEAD` points at, if it was listed with `--symref`.
+    head_symref: Option<String>,
+}
+
+impl LsRemote {
+    fn new(ls_remote: &str, reference: &str, parse_pool: &ParsePool) -> Self {
+        let head_symref = ls_remote.lines().find_map(|line| {
+            let target = line.strip_prefix("ref: ")?.strip_suffix("\tHEAD")?;
+            Some(
+                target
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(target)
+                    .to_string(),
+            )
+        });
+        // Rows are kept in the order git listed them, so that a branch and a tag of the same name always resolve the same way.
+        let rows = parse_pool.parse_lines(ls_remote, |line| {
+            if line.starts_with("ref: ") {
+                return None;
+            }
+            let row = LsRemoteRow::new(line);
+            match row.name.as_str() {
+                _ if row.name == reference => Some(row),
+                _ if row.name == format!("{reference}^{{}}") => Some(row),
+                _ if row.name == format!("refs/tags/{reference}") => Some(row),
+                _ if row.name == format!("refs/tags/{reference}^{{}}") => Some(row),
+                _ if row.name == format!("refs/heads/{reference}") => Some(row),
+                _ => None,
+            }
+        });
+        Self { rows, head_symref }
+    }
+    // Annotated tags are listed twice, once for the tag object and once peeled to the commit it points at (`^{}`).
+    // The peeled commit is preferred, so the store is keyed by the commit rather than the tag object.
+    fn get_hash(&self) -> Result<String, Error> {
+        self.rows
+            .iter()
+            .find(|row| row.name.ends_with("^{}"))
+            .or_else(|| self.rows.first())
+            .map(|row| row.hash.clone())
+            .ok_or(Error::NoMatchingReferenceError)
+    }
+}
+
+#[instrument(skip_all, fields(repo = %repo, reference = %reference))]
+async fn run_ls_remote(
+    repo: &str,
+    reference: &str,
+    git_config: &[(String, String)],
+    parse_pool: &ParsePool,
+) -> Result<LsRemote, Error> {
+    let output = git_with_config(git_config)
+        .await
+        .args(["ls-remote", "--symref", "--", repo, reference])
+        // Peeled annotated tags are only listed when asked for explicitly.
+        .arg(format!("{reference}^{{}}"))
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !output.status.success() {
+        return Err(Error::LsRemoteError(
+            String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_string(),
+        ));
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let stdout = stdout.trim_end();
+    Ok(LsRemote::new(stdout, reference, parse_pool))
+}
+
+// Resolves a reference of a remote repository to a hash, listing the references it does have if there's no match.
+async fn resolve_remote_ref(
+    repo: &str,
+    reference: &str,
+    git_config: &[(String, String)],
+    parse_pool: &ParsePool,
+) -> Result<String, Error> {
+    let ls_remote = run_ls_remote(repo, reference, git_config, parse_pool).await?;
+    match ls_remote.get_hash() {
+        Ok(hash) => Ok(hash),
+        Err(_) => Err(unknown_reference(repo, reference, &ls_remote, git_config).await),
+    }
+}
+
+// Builds the error for a reference the remote doesn't have, listing its branches and tags.
+// Listing them is best effort, as the reference is unknown either way.
+async fn unknown_reference(
+    repo: &str,
+    reference: &str,
+    ls_remote: &LsRemote,
+    git_config: &[(String, String)],
+) -> Error {
+    let mut available = match run_ls_remote_refs(repo, git_config).await {
+        Ok(refs) => refs,
+        Err(e) => {
+            debug!("Failed to list references of {repo}: {e}");
+            vec![]
+        }
+    };
+    available.sort_unstable();
+    available.dedup();
+
+    Error::UnknownReferenceError {
+        reference: reference.to_string(),
+        available,
+        default_branch: ls_remote.head_symref.clone(),
+    }
+}
+
+// Lists the names of the branches and tags of a remote repository.
+async fn run_ls_remote_refs(
+    repo: &str,
+    git_config: &[(String, String)],
+) -> Result<Vec<String>, Error> {
+    Ok(list_remote_refs(repo, git_config)
+        .await?
+        .into_iter()
+        .filter(|row| !row.name.ends_with("^{}"))
+        .map(|row| short_ref_name(&row.name).to_string())
+        .collect())
+}
+
+// Lists the branches and tags of a remote repository, including annotated tags peeled to their commits.
+async fn list_remote_refs(
+    repo: &str,
+    git_config: &[(String, String)],
+) -> Result<Vec<LsRemoteRow>, Error> {
+    let output = git_with_config(git_config)
+        .await
+        .args(["ls-remote", "--heads", "--tags", "--", repo])
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !output.status.success() {
+        return Err(Error::LsRemoteError(
+            String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(LsRemoteRow::new)
+        .collect())
+}
+
+// The name of a branch or tag without its `refs/heads/` or `refs/tags/` prefix.
+fn short_ref_name(name: &str) -> &str {
+    name.strip_prefix("refs/heads/")
+        .or_else(|| name.strip_prefix("refs/tags/"))
+        .unwrap_or(name)
+}
+
+// Whether the reference looks like a full or abbreviated commit hash, rather than a branch or tag.
+fn is_commit_hash(reference: &str) -> bool {
+    (4..=64).contains(&reference.len()) && reference.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Parses the size of a blob printed by `git cat-file -s`.
+fn parse_blob_size(stdout: &[u8]) -> Result<u64, Error> {
+    let size = String::from_utf8_lossy(stdout);
+    size.trim()
+        .parse()
+        .map_err(|_| Error::ReadObjectError(format!("Invalid blob size `{}`", size.trim())))
+}
+
+// Whether the reference is a full reference, e.g. `refs/pull/123/head`, rather than the short name of a branch or tag.
+fn is_full_ref(reference: &str) -> bool {
+    reference.starts_with("refs/")
+}
+
+// Whether the reference is a full SHA-1 or SHA-256 commit hash, rather than one that may be abbreviated.
+fn is_full_commit_hash(reference: &str) -> bool {
+    matches!(reference.len(), 40 | 64) && is_commit_hash(reference)
+}
+
+// Struct for parsing the rows of stdout from the `git ls-tree` command
+#[derive(Debug, Clone)]
+struct TreeRow {
+    mode: String,
+    otype: String,
+    name: String,
+    path: String,
+}
+
+impl TreeRow {
+    fn new(row: &str) -> Self {
+        let mut row_iter = row.split_whitespace();
+        let mode = row_iter
+            .next()
+            .expect("Failed to find mode in TreeRow")
+            .to_string();
+        let otype = row_iter
+            .next()
+            .expect("Failed to find otype in TreeRow")
+            .to_string();
+        let name = row_iter
+            .next()
+            .expect("Failed to find name in TreeRow")
+            .to_string();
+        let path = row_iter.collect::<Vec<&str>>().join(" ");
+        Self {
+            mode,
+            otype,
+            name,
+            path,
+        }
+    }
+    async fn write_to_store(
+        &self,
+        store_path: &Path,
+        repo_dir: &RepoPath,
+        git_config: &[(String, String)],
+    ) -> Result<(), Error> {
+        let content_path = store_path.join(&self.name);
+
+        if content_path.exists() {
+            return Ok(());
+        }
+
+        debug!(
+            "Writing blob {} to store path {}",
+            self.name,
+            content_path.display()
+        );
+
+        self.write_from_repo(
+            store_path,
+            &self.name,
+            self.stored_permissions()?,
+            repo_dir,
+            git_config,
+        )
+        .await
+    }
+    // Writes the blob from a repository to `name` in `dir`, with `permissions`.
+    async fn write_from_repo(
+        &self,
+        dir: &Path,
+        name: &str,
+        permissions: std::fs::Permissions,
+        repo_dir: &RepoPath,
+        git_config: &[(String, String)],
+    ) -> Result<(), Error> {
+        // The blob is streamed, rather than read into memory, as it may be larger than memory.
+        let mut child = git_with_config(git_config)
+            .await
+            .args(["cat-file", "-p", &self.name])
+            .current_dir(repo_dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::CommandSpawnError)?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::ReadObjectError(self.name.clone()))?;
+
+        write_object_with(
+            dir,
+            name,
+            Some(permissions),
+            |mut file, content_path| async move {
+                let write_error = |e| Error::WriteToStoreError(content_path.clone(), e);
+                copy(&mut stdout, &mut file).await.map_err(write_error)?;
+                file.flush().await.map_err(write_error)?;
+
+                let out = child
+                    .wait_with_output()
+                    .await
+                    .map_err(Error::CommandSpawnError)?;
+                if !out.status.success() {
+                    return Err(Error::ReadObjectError(
+                        String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+                    ));
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
+    // Streams the blob from `source` into the cln-store, read-only.
+    async fn copy_from_source(
+        &self,
+        store_path: &Path,
+        source: &dyn ObjectSource,
+    ) -> Result<(), Error> {
+        write_object_with(
+            store_path,
+            &self.name,
+            Some(self.stored_permissions()?),
+            |mut file, content_path| async move {
+                source.copy_blob(&self.name, &mut file).await?;
+                file.flush()
+                    .await
+                    .map_err(|e| Error::WriteToStoreError(content_path, e))
+            },
+        )
+        .await
+    }
+    // Writes the blob to the cln-store from the first object cache that has it, returning whether one did.
+    async fn read_from_object_caches(&self, options: &ClnOptions) -> Result<bool, Error> {
+        if options.object_caches.is_empty() {
+            return Ok(false);
+        }
+
+        write_object_if(
+            &options.store,
+            &self.name,
+            Some(self.stored_permissions()?),
+            |mut file, _| async move {
+                Ok(get_cached(&options.object_caches, &self.name, &mut file).await)
+            },
+        )
+        .await
+    }
+    // Puts the blob, once it's in the cln-store, in every object cache.
+    async fn write_to_object_caches(&self, options: &ClnOptions) {
+        let content_path = options.store.join(&self.name);
+        put_cached(&options.object_caches, &self.name, &content_path).await;
+    }
+    // Blobs are stored read-only, with the rest of the mode of the file in the tree.
+    fn stored_permissions(&self) -> Result<std::fs::Permissions, Error> {
+        let mut stored_file_permissions =
+            std::fs::Permissions::from_mode(self.mode.parse().map_err(Error::ParseModeError)?);
+        stored_file_permissions.set_readonly(true);
+
+        Ok(stored_file_permissions)
+    }
+}
+
+#[derive(Debug)]
+struct Tree {
+    rows: Vec<TreeRow>,
+    path: String,
+    // The `export-ignore` patterns of this tree and the trees above it, only read with `Builder::export_ignore`.
+    export_ignore: Arc<ExportIgnore>,
+}
+
+impl Tree {
+    // Rows are sorted by path,
+    // so that walking the same tree always schedules its blobs and subtrees in the same order.
+    fn new(tree: &str, path: String, parse_pool: &ParsePool) -> Self {
+        let mut rows = parse_pool.parse_lines(tree, |line| Some(TreeRow::new(line)));
+        rows.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            rows,
+            path,
+            export_ignore: Arc::default(),
+        }
+    }
+    // Reads a tree listing from the cln-store, returning `None` if it's in a format other than the current one,
+    // so that it's listed again.
+    async fn from_path(
+        store_path: &Path,
+        path: String,
+        parse_pool: &ParsePool,
+    ) -> Result<Option<Self>, Error> {
+        let content = read_to_string(store_path)
+            .await
+            .map_err(|e| Error::ReadFileError(store_path.display().to_string(), e))?;
+        let Some(tree) = parse_tree_listing(&content) else {
+            debug!(
+                "Tree listing {} is in an older format",
+                store_path.display()
+            );
+            return Ok(None);
+        };
+
+        Ok(Some(Self::new(tree.trim_end(), path, parse_pool)))
+    }
+    async fn from_hash(
+        store_path: &Path,
+        hash: &str,
+        path: String,
+        parse_pool: &ParsePool,
+    ) -> Result<Option<Self>, Error> {
+        let content_path = store_path.join(hash);
+
+        Self::from_path(&content_path, path, parse_pool).await
+    }
+}
+
+// Reads a tree listing that has to be in the cln-store already, e.g. from the checkout being cleaned.
+async fn stored_tree(
+    store_path: &Path,
+    hash: &str,
+    path: String,
+    parse_pool: &ParsePool,
+) -> Result<Tree, Error> {
+    Tree::from_hash(store_path, hash, path, parse_pool)
+        .await?
+        .ok_or_else(|| Error::NotInStoreError(hash.to_string()))
+}
+
+// Whether everything reachable from `hash` that's checked out is in the cln-store, or one of the reference stores,
+// so it can be linked from the cln-store without fetching objects one at a time.
+// Only checks that the objects exist, so it's cheap next to the checkout itself.
+// Objects can go missing after the root is stored, e.g. when a crash interrupts a checkout, or they're pruned or evicted.
+async fn verify_reachable(hash: &str, options: &ClnOptions) -> Result<bool, Error> {
+    let mut pending = vec![(hash.to_string(), ".".to_string())];
+
+    while let Some((hash, path)) = pending.pop() {
+        if !is_content_stored_or_seeded(&options.store, &hash, &options.reference_stores).await? {
+            debug!("Tree {hash} is missing from the store");
+            return Ok(false);
+        }
+        let Some(tree) = Tree::from_hash(&options.store, &hash, path, &options.parse_pool).await?
+        else {
+            debug!("Tree listing {hash} is in an older format");
+            return Ok(false);
+        };
+        for row in &tree.rows {
+            let path = tree_path(&tree, row);
+            // Objects that aren't checked out may never have been stored.
+            if options.exclude.is_excluded(&path) || is_outside_prefix(&path, options) {
+                continue;
+            }
+            match row.otype.as_str() {
+                "tree" => pending.push((row.name.clone(), path.display().to_string())),
+                // Blobs skipped for their size are never stored.
+                "blob" if options.skip_large_files && options.max_file_size.is_some() => {}
+                "blob"
+                    if !is_content_stored_or_seeded(
+                        &options.store,
+                        &row.name,
+                        &options.reference_stores,
+                    )
+                    .await? =>
+                {
+                    debug!("Blob {} is missing from the store", row.name);
+                    return Ok(false);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+// Whether a hash can be checked out from the cln-store alone, rather than from a clone.
+async fn is_fully_stored(hash: &str, options: &ClnOptions) -> Result<bool, Error> {
+    if !is_content_stored_or_seeded(&options.store, hash, &options.reference_stores).await? {
+        return Ok(false);
+    }
+    if !verify_reachable(hash, options).await? {
+        warn!(
+            "{hash} is in the cln-store, but some of the objects it needs aren't, checking it out from a clone"
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+// Flattens a tree in the cln-store at `store_path` into all of the rows reachable from it,
+// paired with their paths relative to the root of the tree.
+async fn flatten_stored_tree(
+    store_path: &Path,
+    hash: &str,
+    parse_pool: &ParsePool,
+) -> Result<Vec<(PathBuf, TreeRow)>, Error> {
+    let mut rows = vec![];
+    let mut pending = vec![stored_tree(store_path, hash, ".".to_string(), parse_pool).await?];
+
+    while let Some(tree) = pending.pop() {
+        for row in tree.rows {
+            let path = Path::new(&tree.path).join(&row.path);
+            if row.otype == "tree" {
+                pending.push(
+                    stored_tree(
+                        store_path,
+                        &row.name,
+                        path.display().to_string(),
+                        parse_pool,
+                    )
+                    .await?,
+                );
+            }
+            rows.push((path.strip_prefix(".").unwrap_or(&path).to_path_buf(), row));
+        }
+    }
+
+    Ok(rows)
+}
+
+type RepoPath = Path;
+
+// Checks a blob against the filter, if there is one.
+async fn is_filtered_out<W: Walkable + ?Sized>(
+    walkable: &W,
+    tree: &Tree,
+    row: &TreeRow,
+    options: &ClnOptions,
+) -> Result<bool, Error> {
+    let Some(filter) = &options.filter else {
+        return Ok(false);
+    };
+
+    let entry = walkable.blob_entry(tree, row, options).await?;
+
+    Ok(!filter.matches(&entry))
+}
+
+// Whether a file is copied writable rather than linked, as it matches the filter set with `Builder::writable_if`.
+async fn is_writable<W: Walkable + ?Sized>(
+    walkable: &W,
+    tree: &Tree,
+    row: &TreeRow,
+    options: &ClnOptions,
+) -> Result<bool, Error> {
+    let Some(filter) = &options.writable_filter else {
+        return Ok(false);
+    };
+
+    let entry = walkable.blob_entry(tree, row, options).await?;
+
+    Ok(filter.matches(&entry))
+}
+
+// Warns when a `.gitattributes` file sets attributes that git applies to files as it checks them out, e.g. `eol=crlf`,
+// as cln checks out every file as it's stored, so the files they match won't be the same as in a `git clone`.
+async fn warn_on_checkout_filters(path: &Path, content_path: &Path) {
+    let Ok(attributes) = read_to_string(content_path).await else {
+        return;
+    };
+    let filters = checkout_filters(&attributes);
+    if !filters.is_empty() {
+        warn!(
+            "{} sets {}, which git applies when checking out files, but cln doesn't. The files they match are checked out as they're stored, so they won't match a git clone",
+            path.display(),
+            filters.join(", ")
+        );
+    }
+}
+
+// Whether a file in the target directory is already checked out, so it doesn't need linking.
+// With `OverwritePolicy::Sync`, a file that isn't a link to `content_path`, e.g. from a checkout of another reference,
+// is removed to be linked again. Without a `content_path`, as the blob is streamed rather than linked, it's always removed.
+async fn is_already_checked_out(
+    target_file: &Path,
+    content_path: Option<&Path>,
+    options: &ClnOptions,
+) -> Result<bool, Error> {
+    let Ok(target_metadata) = symlink_metadata(target_file).await else {
+        return Ok(false);
+    };
+    if options.overwrite_policy != OverwritePolicy::Sync {
+        return Ok(true);
+    }
+
+    if let Some(content_path) = content_path {
+        let content_metadata = metadata(content_path)
+            .await
+            .map_err(Error::ReadStoreError)?;
+        if target_metadata.dev() == content_metadata.dev()
+            && target_metadata.ino() == content_metadata.ino()
+        {
+            return Ok(true);
+        }
+    }
+
+    debug!("Replacing {}", target_file.display());
+    if target_metadata.is_dir() {
+        remove_dir_all(target_file).await
+    } else {
+        remove_file(target_file).await
+    }
+    .map_err(Error::CleanError)?;
+
+    Ok(false)
+}
+
+// Refuses to check a hash out over a different one recorded in the metadata of the target directory, unless it's being synced,
+// as files that are in both would be left as they were, mixing the two checkouts.
+async fn ensure_target_matches(
+    target_dir: &Path,
+    hash: &str,
+    options: &ClnOptions,
+) -> Result<(), Error> {
+    if options.overwrite_policy == OverwritePolicy::Sync {
+        return Ok(());
+    }
+    let Ok(existing) = read_metadata(target_dir).await else {
+        return Ok(());
+    };
+    if existing.hash != hash {
+        return Err(Error::TargetMismatchError {
+            dir: target_dir.display().to_string(),
+            existing: existing.hash,
+            hash: hash.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Creates a directory and any missing parents, setting the `dir_mode` option on each directory it creates, if there is one.
+async fn create_dirs(dir: &Path, options: &ClnOptions) -> Result<(), Error> {
+    if dir.exists() {
+        return Ok(());
+    }
+    let created = dir
+        .ancestors()
+        .take_while(|ancestor| !ancestor.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+
+    create_dir_all(dir)
+        .await
+        .map_err(Error::CreateDirAllError)?;
+
+    if let Some(dir_mode) = options.dir_mode {
+        for created_dir in created {
+            set_permissions(&created_dir, std::fs::Permissions::from_mode(dir_mode))
+                .await
+                .map_err(Error::CreateDirAllError)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Path of a row relative to the root of the tree being walked.
+fn tree_path(tree: &Tree, row: &TreeRow) -> PathBuf {
+    let path = Path::new(&tree.path).join(&row.path);
+    path.strip_prefix(".").unwrap_or(&path).to_path_buf()
+}
+
+// Drops the first `strip_components` components of a path relative to the root of the tree, like `tar --strip-components`,
+// returning `None` if nothing is left of it.
+fn strip_components(path: &Path, strip_components: usize) -> Option<PathBuf> {
+    let mut components = path.components();
+    for _ in 0..strip_components {
+        components.next()?;
+    }
+    let stripped = components.as_path();
+    (!stripped.as_os_str().is_empty()).then(|| stripped.to_path_buf())
+}
+
+// Hard links an object from the cln-store to `target_file`, or copies it if `copy` is set, e.g. as they're on different filesystems.
+// Copies keep the read-only permissions of the object, as links do, unless the file is writable.
+// Where the filesystem supports it, e.g. btrfs, XFS or APFS, the copy is a copy-on-write clone, sharing the object's blocks until it's edited.
+async fn link_object(
+    content_path: &Path,
+    target_file: &Path,
+    copy: bool,
+    writable: bool,
+) -> Result<(), Error> {
+    if copy {
+        tokio::fs::copy(content_path, target_file)
+            .await
+            .map_err(Error::CopyObjectError)?;
+        if writable {
+            let mut permissions = metadata(target_file)
+                .await
+                .map_err(Error::CopyObjectError)?
+                .permissions();
+            permissions.set_mode(permissions.mode() | 0o200);
+            set_permissions(target_file, permissions)
+                .await
+                .map_err(Error::CopyObjectError)?;
+        }
+    } else {
+        hard_link(content_path, target_file)
+            .await
+            .map_err(Error::HardLinkError)?;
+    }
+
+    debug!(
+        "Linked {} to {}",
+        content_path.display(),
+        target_file.display()
+    );
+
+    Ok(())
+}
+
+// Where a path relative to the root of the tree is checked out, relative to the target directory,
+// after `strip_prefix`, `strip_components` and `add_prefix` are applied in turn.
+// Returns `None` if the path isn't checked out at all.
+fn checkout_path(path: &Path, options: &ClnOptions) -> Option<PathBuf> {
+    let path = match &options.strip_prefix {
+        Some(prefix) => path.strip_prefix(prefix).ok()?,
+        None => path,
+    };
+    let path = strip_components(path, options.strip_components)?;
+    Some(match &options.add_prefix {
+        Some(prefix) => prefix.join(path),
+        None => path,
+    })
+}
+
+// Whether a path relative to the root of the tree is neither under `strip_prefix` nor one of its parents, so nothing in it is checked out.
+fn is_outside_prefix(path: &Path, options: &ClnOptions) -> bool {
+    options
+        .strip_prefix
+        .as_ref()
+        .is_some_and(|prefix| !path.starts_with(prefix) && !prefix.starts_with(path))
+}
+
+// Normalises a prefix to a relative path, rejecting anything that could point outside of the directory it's relative to.
+fn relative_prefix(prefix: &Path) -> Result<PathBuf, Error> {
+    prefix
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => Ok(name),
+            _ => Err(Error::InvalidPrefixError(prefix.display().to_string())),
+        })
+        .collect()
+}
+
+// Finds two entries of a tree whose names only differ in case, e.g. `README` and `Readme`,
+// which would be checked out as the same file on a case-insensitive filesystem, like the defaults of macOS and Windows.
+fn case_collision(tree: &Tree) -> Option<(PathBuf, PathBuf)> {
+    let mut names = HashMap::with_capacity(tree.rows.len());
+    tree.rows.iter().find_map(|row| {
+        names
+            .insert(row.path.to_lowercase(), row)
+            .map(|other| (tree_path(tree, other), tree_path(tree, row)))
+    })
+}
+
+// Fails on a case collision when the target directory is on a case-insensitive filesystem, where one entry would overwrite the other,
+// and only warns about it otherwise, as the checkout is still correct, if not portable.
+fn check_case_collisions(tree: &Tree, options: &ClnOptions) -> Result<(), Error> {
+    let Some((a, b)) = case_collision(tree) else {
+        return Ok(());
+    };
+    if options.case_insensitive {
+        return Err(Error::CaseCollisionError {
+            a: a.display().to_string(),
+            b: b.display().to_string(),
+        });
+    }
+
+    warn!(
+        "{} and {} only differ in case, so they collide when checked out on a case-insensitive filesystem",
+        a.display(),
+        b.display()
+    );
+    Ok(())
+}
+
+// Whether the filesystem of a directory, or of its closest existing parent, treats names that only differ in case as the same,
+// found by creating a file in it and looking it up by an upper case name, as git does for `core.ignorecase`.
+fn is_case_insensitive(dir: &Path) -> bool {
+    let Some(probe) = dir
+        .ancestors()
+        .filter(|dir| dir.is_dir())
+        .find_map(|dir| TempBuilder::new().prefix(".cln-case").tempfile_in(dir).ok())
+    else {
+        return false;
+    };
+    let Some(name) = probe.path().file_name() else {
+        return false;
+    };
+
+    probe
+        .path()
+        .with_file_name(name.to_string_lossy().to_uppercase())
+        .exists()
+}
+
+fn queue_rows(pending: &mut VecDeque<(Arc<Tree>, usize)>, tree: Tree) {
+    let tree = Arc::new(tree);
+    pending.extend((0..tree.rows.len()).map(|index| (Arc::clone(&tree), index)));
+}
+
+// The maximum number of blobs and trees being written at once, unless set with `Builder::concurrency`.
+// Each of them runs at most one git process at a time, so this also caps the number of git processes spawned by a walk.
+const MAX_CONCURRENT_TASKS: usize = 16;
+
+#[async_trait]
+trait Walkable: Send + Sync + 'static {
+    // Walks the tree breadth first from a queue of pending entries, rather than recursing into subtrees,
+    // so that deeply nested repositories don't build deeply nested futures.
+    // Every blob and subtree is its own task, so independent entries are written concurrently.
+    #[instrument(skip_all, fields(objects))]
+    async fn walk(
+        self: Arc<Self>,
+        tree: Tree,
+        target_path: Arc<Path>,
+        options: Arc<ClnOptions>,
+    ) -> Result<(), Error> {
+        let mut pending = VecDeque::new();
+        let mut tasks = JoinSet::new();
+        let mut failures = vec![];
+        let mut objects = 0;
+
+        // Rows are sorted by path, so walking one at a time links them in a stable order.
+        let max_tasks = if options.deterministic {
+            1
+        } else {
+            options.concurrency.unwrap_or(MAX_CONCURRENT_TASKS).max(1)
+        };
+        check_case_collisions(&tree, &options)?;
+        let tree = if options.export_ignore {
+            self.read_export_ignore(tree, &Arc::default(), &options)
+                .await?
+        } else {
+            tree
+        };
+        queue_rows(&mut pending, tree);
+
+        while !pending.is_empty() || !tasks.is_empty() {
+            while tasks.len() < max_tasks {
+                let Some((tree, index)) = pending.pop_front() else {
+                    break;
+                };
+                let walkable = Arc::clone(&self);
+                let target_path = Arc::clone(&target_path);
+                let options = Arc::clone(&options);
+                let path = Path::new(&tree.path).join(&tree.rows[index].path);
+                tasks.spawn(async move {
+                    let row = &tree.rows[index];
+                    let subtree = match row.otype.as_str() {
+                        _ if options.exclude.is_excluded(&tree_path(&tree, row)) => {
+                            debug!("Skipping {} as it's excluded", row.path);
+                            Ok(None)
+                        }
+                        _ if is_outside_prefix(&tree_path(&tree, row), &options) => {
+                            debug!(
+                                "Skipping {} as it's outside of the stripped prefix",
+                                row.path
+                            );
+                            Ok(None)
+                        }
+                        _ if tree
+                            .export_ignore
+                            .is_ignored(&tree_path(&tree, row), row.otype == "tree") =>
+                        {
+                            debug!("Skipping {} as it's export-ignored", row.path);
+                            Ok(None)
+                        }
+                        "blob" => walkable
+                            .write_blob(&tree, row, &target_path, &options)
+                            .await
+                            .map(|()| None),
+                        "tree" => walkable
+                            .walk_tree(&tree, row, &target_path, &options)
+                            .await
+                            .map(Some),
+                        _ => Ok(None),
+                    };
+                    (path, subtree)
+                });
+            }
+
+            // Unless continuing on errors, the first error wins, and dropping the remaining tasks aborts them.
+            if let Some(joined) = tasks.join_next().await {
+                objects += 1;
+                let (path, subtree) = joined.map_err(Error::WalkTaskError)?;
+                match subtree {
+                    Ok(Some(subtree)) => queue_rows(&mut pending, subtree),
+                    Ok(None) => {}
+                    Err(e) if options.continue_on_error => {
+                        warn!("Failed to check out {}: {}", path.display(), e);
+                        failures.push((path, e));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to check out {}, stopping after {} objects. Use atomic checkouts to leave the target untouched on failure",
+                            path.display(),
+                            objects - 1
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Span::current().record("objects", objects);
+
+        if !failures.is_empty() {
+            // Tasks finish in whatever order their git processes do, so failures are reported in path order instead.
+            failures.sort_by(|(a, _), (b, _)| a.cmp(b));
+            return Err(Error::PartialCheckoutError(failures));
+        }
+
+        Ok(())
+    }
+    // Makes sure a blob is in the cln-store, so that it can be linked from there.
+    async fn prepare_blob(&self, row: &TreeRow, options: &ClnOptions) -> Result<(), Error>;
+    // Reads the size of a blob, from the cln-store if it's there, without fetching it.
+    async fn blob_size(&self, row: &TreeRow, options: &ClnOptions) -> Result<u64, Error>;
+    // Whether blobs can be streamed straight into the target with `stream_blob`, so that `Builder::no_store` skips the scratch store for them.
+    fn streams_blobs(&self) -> bool {
+        false
+    }
+    // Writes a blob straight to `target_file`, read-only unless it's `writable`.
+    // Sources that can't stream blobs write them to the cln-store, and copy them from there.
+    async fn stream_blob(
+        &self,
+        row: &TreeRow,
+        target_file: &Path,
+        writable: bool,
+        options: &ClnOptions,
+    ) -> Result<(), Error> {
+        self.prepare_blob(row, options).await?;
+        link_object(&options.store.join(&row.name), target_file, true, writable).await
+    }
+    // The entry of a blob, for matching it against filters, with its size read from the cln-store if it's there.
+    async fn blob_entry(
+        &self,
+        tree: &Tree,
+        row: &TreeRow,
+        options: &ClnOptions,
+    ) -> Result<TreeEntry, Error> {
+        let path = tree_path(tree, row);
+        if is_content_stored(&options.store, &row.name) {
+            return TreeEntry::from_stored_row(&options.store, path, row).await;
+        }
+
+        let size = self.blob_size(row, options).await?;
+        Ok(TreeEntry::from_sized_row(path, row, size))
+    }
+    // Checks a blob against the maximum file size before it's fetched,
+    // returning whether it's left out of the checkout, or failing if it's too large to be skipped.
+    async fn is_too_large(
+        &self,
+        tree: &Tree,
+        row: &TreeRow,
+        max_file_size: u64,
+        options: &ClnOptions,
+    ) -> Result<bool, Error> {
+        let size = match metadata(options.store.join(&row.name)).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => self.blob_size(row, options).await?,
+        };
+        if size <= max_file_size {
+            return Ok(false);
+        }
+
+        let path = tree_path(tree, row);
+        let entry = TreeEntry::from_sized_row(path.clone(), row, size);
+        if options
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(&entry))
+        {
+            debug!("Skipping {} as it doesn't match the filter", row.path);
+            return Ok(true);
+        }
+        if options.skip_large_files {
+            warn!(
+                "Skipping {} as it's {} bytes, more than the maximum file size of {} bytes",
+                path.display(),
+                size,
+                max_file_size
+            );
+            return Ok(true);
+        }
+
+        Err(Error::FileTooLargeError {
+            path: path.display().to_string(),
+            size,
+            max_file_size,
+        })
+    }
+    // Lists the subtree of `hash`, at `path` relative to the root of the walk.
+    async fn fetch_subtree(
+        &self,
+        hash: &str,
+        path: String,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error>;
+    // Adds the `export-ignore` patterns of the `.gitattributes` file in `tree`, if it has one, to those of its parent.
+    async fn read_export_ignore(
+        &self,
+        mut tree: Tree,
+        parent: &Arc<ExportIgnore>,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error> {
+        let Some(row) = tree
+            .rows
+            .iter()
+            .find(|row| row.otype == "blob" && row.path == ATTRIBUTES_FILE)
+        else {
+            tree.export_ignore = Arc::clone(parent);
+            return Ok(tree);
+        };
+
+        self.prepare_blob(row, options).await?;
+        let content_path = options.store.join(&row.name);
+        let attributes = read(&content_path)
+            .await
+            .map_err(|e| Error::ReadFileError(content_path.display().to_string(), e))?;
+        let base = Path::new(&tree.path);
+        tree.export_ignore = Arc::new(parent.with_attributes(
+            base.strip_prefix(".").unwrap_or(base),
+            &String::from_utf8_lossy(&attributes),
+        ));
+
+        Ok(tree)
+    }
+    async fn write_blob(
+        &self,
+        tree: &Tree,
+        row: &TreeRow,
+        target_path: &Path,
+        options: &ClnOptions,
+    ) -> Result<(), Error> {
+        if let Some(max_file_size) = options.max_file_size {
+            if self.is_too_large(tree, row, max_file_size, options).await? {
+                return Ok(());
+            }
+        }
+        // Without a store, blobs are streamed straight into the target where they can be, rather than through the scratch store.
+        let streamed = options.no_store && self.streams_blobs();
+        if !streamed {
+            self.prepare_blob(row, options).await?;
+            touch_object(&options.store, &row.name).await;
+        }
+        if is_filtered_out(self, tree, row, options).await? {
+            debug!("Skipping {} as it doesn't match the filter", row.path);
+            return Ok(());
+        }
+        let Some(checkout_path) = checkout_path(&tree_path(tree, row), options) else {
+            debug!(
+                "Skipping {} as all of its directories are stripped",
+                row.path
+            );
+            return Ok(());
+        };
+        let mut content_path = options.store.join(&row.name);
+        let writable = options.writable || is_writable(self, tree, row, options).await?;
+        // Streamed blobs are checked by git as it reads them.
+        let mut verified = streamed;
+        let mut streaming = streamed;
+        // The same object is linked into every mirror target, as it's linked into the target.
+        let targets = std::iter::once((target_path, options.copy_objects)).chain(
+            options.mirror_to.iter().enumerate().map(|(index, mirror)| {
+                let copy = options.mirror_copies.get(index).copied().unwrap_or(true);
+                (mirror.as_path(), copy)
+            }),
+        );
+        for (target_dir, copy_objects) in targets {
+            let target_file = target_dir.join(&checkout_path);
+            if let Some(target_dir) = target_file.parent() {
+                create_dirs(target_dir, options).await?;
+            }
+            if streaming {
+                // Mirrors are linked from the target, as there's no object in the store to link them from.
+                if !is_already_checked_out(&target_file, None, options).await? {
+                    self.stream_blob(row, &target_file, writable, options)
+                        .await?;
+                }
+                content_path = target_file;
+                streaming = false;
+                continue;
+            }
+            if is_already_checked_out(&target_file, Some(&content_path), options).await? {
+                continue;
+            }
+
+            if options.verify_on_link && !verified {
+                verify_stored_blob(&options.store, &row.name).await?;
+                verified = true;
+            }
+
+            link_object(
+                &content_path,
+                &target_file,
+                copy_objects || writable,
+                writable,
+            )
+            .await?;
+        }
+
+        if row.path == ATTRIBUTES_FILE {
+            warn_on_checkout_filters(&tree_path(tree, row), &content_path).await;
+        }
+
+        Ok(())
+    }
+    async fn walk_tree(
+        &self,
+        tree: &Tree,
+        row: &TreeRow,
+        target_path: &Path,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error> {
+        let new_path = Path::new(tree.path.as_str()).join(&row.path);
+        if options.preserve_empty_dirs {
+            if let Some(checkout_path) = checkout_path(&tree_path(tree, row), options) {
+                for target_dir in std::iter::once(target_path)
+                    .chain(options.mirror_to.iter().map(PathBuf::as_path))
+                {
+                    create_dirs(&target_dir.join(&checkout_path), options).await?;
+                }
+            }
+        }
+        let subtree = self
+            .fetch_subtree(&row.name, new_path.display().to_string(), options)
+            .await?;
+        check_case_collisions(&subtree, options)?;
+        if !options.export_ignore {
+            return Ok(subtree);
+        }
+
+        self.read_export_ignore(subtree, &tree.export_ignore, options)
+            .await
+    }
+}
+
+#[async_trait]
+impl Walkable for RepoPath {
+    async fn prepare_blob(&self, row: &TreeRow, options: &ClnOptions) -> Result<(), Error> {
+        if is_content_stored_or_seeded(&options.store, &row.name, &options.reference_stores).await?
+            || row.read_from_object_caches(options).await?
+        {
+            return Ok(());
+        }
+
+        row.write_to_store(&options.store, self, &options.git_config)
+            .await?;
+        row.write_to_object_caches(options).await;
+
+        Ok(())
+    }
+    fn streams_blobs(&self) -> bool {
+        true
+    }
+    async fn stream_blob(
+        &self,
+        row: &TreeRow,
+        target_file: &Path,
+        writable: bool,
+        options: &ClnOptions,
+    ) -> Result<(), Error> {
+        let (Some(dir), Some(name)) = (target_file.parent(), target_file.file_name()) else {
+            return Err(Error::PathNotFoundError(target_file.display().to_string()));
+        };
+        let mut permissions = row.stored_permissions()?;
+        if writable {
+            permissions.set_mode(permissions.mode() | 0o200);
+        }
+        debug!("Writing blob {} to {}", row.name, target_file.display());
+
+        row.write_from_repo(
+            dir,
+            &name.to_string_lossy(),
+            permissions,
+            self,
+            &options.git_config,
+        )
+        .await
+    }
+    async fn blob_size(&self, row: &TreeRow, options: &ClnOptions) -> Result<u64, Error> {
+        let out = git_with_config(&options.git_config)
+            .await
+            .args(["cat-file", "-s", &row.name])
+            .current_dir(self)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+        if !out.status.success() {
+            return Err(Error::ReadObjectError(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            ));
+        }
+
+        parse_blob_size(&out.stdout)
+    }
+    async fn fetch_subtree(
+        &self,
+        hash: &str,
+        path: String,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error> {
+        self.ls_tree(
+            &options.store,
+            hash,
+            path,
+            &options.git_config,
+            &options.parse_pool,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+trait Populatable: Send + Sync + 'static {
+    async fn populate(self: Arc<Self>, tree: Tree, options: Arc<ClnOptions>) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl Populatable for RepoPath {
+    // Writes everything reachable from the tree to the cln-store, from a queue of pending entries like `walk`,
+    // rather than recursing into subtrees, with each blob and subtree as its own task.
+    async fn populate(self: Arc<Self>, tree: Tree, options: Arc<ClnOptions>) -> Result<(), Error> {
+        let mut pending = VecDeque::new();
+        let mut tasks = JoinSet::new();
+        let max_tasks = options.concurrency.unwrap_or(MAX_CONCURRENT_TASKS).max(1);
+        queue_rows(&mut pending, tree);
+
+        while !pending.is_empty() || !tasks.is_empty() {
+            while tasks.len() < max_tasks {
+                let Some((tree, index)) = pending.pop_front() else {
+                    break;
+                };
+                let repo_dir = Arc::clone(&self);
+                let options = Arc::clone(&options);
+                tasks.spawn(async move {
+                    let row = &tree.rows[index];
+                    match row.otype.as_str() {
+                        "blob" => row
+                            .write_to_store(&options.store, &repo_dir, &options.git_config)
+                            .await
+                            .map(|()| None),
+                        "tree" => {
+                            let path = Self::new(&tree.path).join(&row.path);
+                            repo_dir
+                                .ls_tree(
+                                    &options.store,
+                                    &row.name,
+                                    path.display().to_string(),
+                                    &options.git_config,
+                                    &options.parse_pool,
+                                )
+                                .await
+                                .map(Some)
+                        }
+                        _ => Ok(None),
+                    }
+                });
+            }
+
+            // The first error wins, and dropping the remaining tasks aborts them.
+            if let Some(joined) = tasks.join_next().await {
+                if let Some(subtree) = joined.map_err(Error::WalkTaskError)?? {
+                    queue_rows(&mut pending, subtree);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Walks a tree that is already in the cln-store.
+// Anything missing from the cln-store is read from the source, and written to the cln-store.
+struct StoredTree {
+    source: Arc<dyn ObjectSource>,
+}
+
+impl StoredTree {
+    async fn fetch_tree(
+        &self,
+        hash: &str,
+        path: String,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error> {
+        if is_content_stored_or_seeded(&options.store, hash, &options.reference_stores).await? {
+            touch_object(&options.store, hash).await;
+            if let Some(tree) =
+                Tree::from_hash(&options.store, hash, path.clone(), &options.parse_pool).await?
+            {
+                return Ok(tree);
+            }
+        }
+
+        debug!("Tree {hash} is missing from the store");
+        let listing = self.source.ls_tree(hash).await?;
+        store_tree(&options.store, hash, &listing).await?;
+
+        Ok(Tree::new(&listing, path, &options.parse_pool))
+    }
+
+    // Reads the contents of the file at `path` in the tree of `hash`, only fetching the trees on the way to it.
+    async fn read_file(
+        &self,
+        hash: &str,
+        path: &Path,
+        options: &ClnOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let content_path = self.prepare_file(hash, path, options).await?;
+        read(&content_path)
+            .await
+            .map_err(|e| Error::ReadFileError(content_path.display().to_string(), e))
+    }
+    // Writes the file at `path` in the tree of `hash` to the cln-store, returning where it's stored.
+    async fn prepare_file(
+        &self,
+        hash: &str,
+        path: &Path,
+        options: &ClnOptions,
+    ) -> Result<PathBuf, Error> {
+        let not_found = || Error::PathNotFoundError(path.display().to_string());
+        let mut names = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .peekable();
+
+        let mut tree = self.fetch_tree(hash, ".".to_string(), options).await?;
+        while let Some(name) = names.next() {
+            let row = tree
+                .rows
+                .into_iter()
+                .find(|row| Path::new(&row.path) == Path::new(name))
+                .ok_or_else(not_found)?;
+            match (row.otype.as_str(), names.peek()) {
+                ("tree", Some(_)) => {
+                    tree = self.fetch_tree(&row.name, ".".to_string(), options).await?;
+                }
+                ("blob", None) => {
+                    self.prepare_blob(&row, options).await?;
+                    return Ok(options.store.join(&row.name));
+                }
+                _ => return Err(not_found()),
+            }
+        }
+
+        Err(not_found())
+    }
+}
+
+// Never reads anything, for reading objects from the cln-store alone.
+#[derive(Debug)]
+struct StoreOnlySource;
+
+#[async_trait]
+impl ObjectSource for StoreOnlySource {
+    async fn resolve_ref(&self, reference: &str) -> Result<String, Error> {
+        Err(Error::NotInStoreError(reference.to_string()))
+    }
+    async fn ls_tree(&self, hash: &str) -> Result<String, Error> {
+        Err(Error::NotInStoreError(hash.to_string()))
+    }
+    async fn cat_blob(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::NotInStoreError(hash.to_string()))
+    }
+}
+
+#[async_trait]
+impl Walkable for StoredTree {
+    async fn prepare_blob(&self, row: &TreeRow, options: &ClnOptions) -> Result<(), Error> {
+        if is_content_stored_or_seeded(&options.store, &row.name, &options.reference_stores).await?
+            || row.read_from_object_caches(options).await?
+        {
+            return Ok(());
+        }
+
+        debug!("Blob {} is missing from the store", row.name);
+        row.copy_from_source(&options.store, self.source.as_ref())
+            .await?;
+        row.write_to_object_caches(options).await;
+
+        Ok(())
+    }
+    async fn blob_size(&self, row: &TreeRow, _options: &ClnOptions) -> Result<u64, Error> {
+        self.source.blob_size(&row.name).await
+    }
+    async fn fetch_subtree(
+        &self,
+        hash: &str,
+        path: String,
+        options: &ClnOptions,
+    ) -> Result<Tree, Error> {
+        self.fetch_tree(hash, path, options).await
+    }
+}
+
+trait Treevarsable {
+    async fn ls_tree(
+        &self,
+        store_path: &Path,
+        reference: &str,
+        path: String,
+        git_config: &[(String, String)],
+        parse_pool: &ParsePool,
+    ) -> Result<Tree, Error>;
+}
+
+const HEAD: &str = "HEAD";
+
+impl Treevarsable for RepoPath {
+    async fn ls_tree(
+        &self,
+        store_path: &Path,
+        reference: &str,
+        path: String,
+        git_config: &[(String, String)],
+        parse_pool: &ParsePool,
+    ) -> Result<Tree, Error> {
+        debug!("ls-tree: {reference}");
+
+        let content_path = store_path.join(reference);
+
+        if content_path.exists() {
+            touch_object(store_path, reference).await;
+            let content = read_to_string(&content_path)
+                .await
+                .map_err(Error::ReadTreeError)?;
+            if let Some(listing) = parse_tree_listing(&content) {
+                return Ok(Tree::new(listing.trim_end(), path, parse_pool));
+            }
+            debug!("Tree listing {reference} is in an older format");
+        }
+
+        let out = git_with_config(git_config)
+            .await
+            .args(["ls-tree", reference])
+            .current_dir(self)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+        // A failed listing is empty, and mustn't be stored as the listing of the tree.
+        if !out.status.success() {
+            return Err(Error::ReadObjectError(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            ));
+        }
+        let ls_tree_string = String::from_utf8_lossy(&out.stdout);
+        let ls_tree_trimmed = ls_tree_string.trim_end().to_string();
+
+        store_tree(store_path, reference, &ls_tree_trimmed).await?;
+
+        Ok(Tree::new(&ls_tree_trimmed, path, parse_pool))
+    }
+}
+
+// Writes the listing of a tree to the cln-store at `store_path`.
+async fn store_tree(store_path: &Path, hash: &str, listing: &str) -> Result<(), Error> {
+    write_object(
+        store_path,
+        hash,
+        format_tree_listing(listing).as_bytes(),
+        None,
+    )
+    .await?;
+
+    debug!("Wrote tree {hash} to store");
+
+    Ok(())
+}
+
+// Derives the directory name like `git clone` does, from the last component of a URL, scp-like address or local path.
+// Trailing slashes, a trailing `.git` directory (for a path to a non-bare repo) and a `.git` suffix are ignored.
+fn get_repo_name(repo: &str) -> PathBuf {
+    let repo = repo.trim_end_matches('/');
+    let repo = repo.strip_suffix("/.git").unwrap_or(repo);
+    let repo_name = repo
+        .rsplit(['/', ':'])
+        .next()
+        .expect("Could not parse repo name. Check the URL.");
+    PathBuf::from(repo_name.strip_suffix(".git").unwrap_or(repo_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::STORE_TEST_LOCK;
+    use tokio::io::AsyncWrite;
+
+    fn run_git(dir: &Path, args: &[&str]) -> String {
+        let out = std::process::Command::new("git")
+            .args(["-c", "user.name=cln", "-c", "user.email=cln@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .expect("Failed to run git. Check the test setup.");
+        assert!(
+            out.status.success(),
+            "git {args:?} failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8_lossy(&out.stdout).trim_end().to_string()
+    }
+
+    fn mktree(dir: &Path, listing: &str) -> String {
+        use std::io::Write;
+
+        let mut child = std::process::Command::new("git")
+            .arg("mktree")
+            .current_dir(dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("Failed to run git mktree. Check the test setup.");
+        child
+            .stdin
+            .take()
+            .expect("Failed to open git mktree stdin")
+            .write_all(listing.as_bytes())
+            .expect("Failed to write to git mktree stdin");
+        let out = child
+            .wait_with_output()
+            .expect("Failed to wait for git mktree");
+        assert!(out.status.success());
+        String::from_utf8_lossy(&out.stdout).trim_end().to_string()
+    }
+
+    // Creates a local repository with a few nested files, so tests don't need the network.
+    fn create_fixture_repo() -> TempDir {
+        let repo = create_temp_dir(None).expect("Failed to create fixture tempdir");
+        let repo_path = repo.path();
+        std::fs::create_dir_all(repo_path.join("src").join("nested"))
+            .expect("Failed to create fixture directories");
+        std::fs::write(repo_path.join("README.md"), "# Fixture\n").expect("Failed to write README");
+        std::fs::write(repo_path.join("src").join("main.rs"), "fn main() {}\n")
+            .expect("Failed to write main.rs");
+        std::fs::write(
+            repo_path.join("src").join("nested").join("file.txt"),
+            "nested\n",
+        )
+        .expect("Failed to write nested file");
+        run_git(repo_path, &["init", "--quiet", "--initial-branch", "main"]);
+        run_git(repo_path, &["add", "."]);
+        run_git(
+            repo_path,
+            &["commit", "--quiet", "--message", "Initial commit"],
+        );
+        repo
+    }
+
+    fn path_str(path: &Path) -> &str {
+        path.to_str()
+            .expect("Failed to convert path to string. Check the test setup.")
+    }
+
+    #[test]
+    fn test_create_temp_dir() {
+        let tempdir = create_temp_dir(None).expect("Failed to create tempdir");
+        assert!(tempdir.path().exists());
+        tempdir.close().expect("Failed to close tempdir");
+    }
+
+    #[test]
+    fn test_create_temp_dir_in() {
+        let parent = create_temp_dir(None).expect("Failed to create parent tempdir");
+        let tempdir = create_temp_dir(Some(parent.path())).expect("Failed to create tempdir");
+        assert!(tempdir.path().starts_with(parent.path()));
+        tempdir.close().expect("Failed to close tempdir");
+    }
+
+    #[tokio::test]
+    async fn test_close_temp_dir_on_error() {
+        let tempdir = create_temp_dir(None).expect("Failed to create tempdir");
+        let path = tempdir.path().to_path_buf();
+        std::fs::write(path.join("partial"), "partial").expect("Failed to write file");
+
+        let result: Result<(), Error> =
+            close_temp_dir(tempdir, Err(Error::GitCloneError("failed".to_string()))).await;
+
+        assert!(matches!(result, Err(Error::GitCloneError(_))));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_ls_remote() {
+        let fixture = create_fixture_repo();
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let ls_remote = run_ls_remote(path_str(fixture.path()), HEAD, &[], &ParsePool::default())
+            .await
+            .expect("Failed to run ls-remote");
+        assert_eq!(ls_remote.get_hash().expect("Failed to get hash"), head);
+    }
+
+    #[tokio::test]
+    async fn test_git_config() {
+        let fixture = create_fixture_repo();
+        let repo = format!("file://{}", path_str(fixture.path()));
+        let git_config = vec![("protocol.file.allow".to_string(), "never".to_string())];
+
+        let err = run_ls_remote(&repo, HEAD, &git_config, &ParsePool::default())
+            .await
+            .err()
+            .expect("Expected ls-remote to honor the git config");
+        assert!(matches!(err, Error::LsRemoteError(_)), "{err}");
+
+        let tmp_dir = create_temp_dir(None).expect("Failed to create tempdir");
+        let err = clone_repo(&repo, tmp_dir.path(), None, true, None, None, &git_config)
+            .await
+            .expect_err("Expected clone to honor the git config");
+        assert!(matches!(err, Error::GitCloneError(_)), "{err}");
+
+        let source = GitCliSource::new(&repo, None).git_config(git_config);
+        assert!(source.resolve_ref(HEAD).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clone_repo() {
+        let fixture = create_fixture_repo();
+        let repo = format!("file://{}", path_str(fixture.path()));
+        let tmp_dir = create_temp_dir(None).expect("Failed to create tempdir");
+        let tmp_dir_path = tmp_dir.path();
+        clone_repo(&repo, tmp_dir_path, None, true, None, None, &[])
+            .await
+            .expect("Failed to clone repo");
+        assert!(tmp_dir_path.join("HEAD").exists());
+        assert_eq!(
+            run_git(tmp_dir_path, &["branch", "--format=%(refname:short)"]),
+            "main"
+        );
+
+        for entry in tmp_dir_path.read_dir().expect("Failed to read tempdir") {
+            let entry = entry.expect("Failed to read entry");
+            let entry_path = entry.path();
+            let filesize = entry_path.metadata().expect("Failed to get metadata").len();
+            assert!(filesize > 0);
+            assert!(entry_path.exists());
+        }
+
+        tmp_dir.close().expect("Failed to close tempdir");
+    }
+
+    #[tokio::test]
+    async fn test_clone_repo_all_branches() {
+        let fixture = create_fixture_repo();
+        run_git(fixture.path(), &["branch", "other"]);
+        let repo = format!("file://{}", path_str(fixture.path()));
+        let tmp_dir = create_temp_dir(None).expect("Failed to create tempdir");
+
+        clone_repo(&repo, tmp_dir.path(), None, false, None, None, &[])
+            .await
+            .expect("Failed to clone repo");
+
+        assert_eq!(
+            run_git(tmp_dir.path(), &["branch", "--format=%(refname:short)"]),
+            "main\nother"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clone_repo_progress() {
+        let fixture = create_fixture_repo();
+        let repo = format!("file://{}", path_str(fixture.path()));
+        let tmp_dir = create_temp_dir(None).expect("Failed to create tempdir");
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        clone_repo(&repo, tmp_dir.path(), None, true, None, Some(&sender), &[])
+            .await
+            .expect("Failed to clone repo");
+        drop(sender);
+
+        let mut updates = vec![];
+        while let Some(update) = receiver.recv().await {
+            updates.push(update);
+        }
+        assert!(
+            updates
+                .iter()
+                .any(|update| update.phase == "Receiving objects" && update.percent == 100),
+            "{updates:?}"
+        );
+
+        let missing = tmp_dir.path().join("missing");
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let err = clone_repo(
+            path_str(&missing),
+            &tmp_dir.path().join("clone"),
+            None,
+            true,
+            None,
+            Some(&sender),
+            &[],
+        )
+        .await
+        .expect_err("Cloning a missing repo should fail");
+        assert!(err.to_string().contains("does not exist"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_clone_repo_filter() {
+        let fixture = create_fixture_repo();
+        run_git(
+            fixture.path(),
+            &["config", "uploadpack.allowFilter", "true"],
+        );
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let repo = format!("file://{}", path_str(fixture.path()));
+        let tmp_dir = create_temp_dir(None).expect("Failed to create tempdir");
+
+        clone_repo(
+            &repo,
+            tmp_dir.path(),
+            None,
+            true,
+            Some("blob:none"),
+            None,
+            &[],
+        )
+        .await
+        .expect("Failed to clone repo");
+
+        assert_eq!(
+            run_git(
+                tmp_dir.path(),
+                &["config", "remote.origin.partialclonefilter"]
+            ),
+            "blob:none"
+        );
+        // The blob is fetched on demand.
+        assert_eq!(
+            run_git(tmp_dir.path(), &["cat-file", "-p", &readme]),
+            "# Fixture"
+        );
+    }
+
+    #[test]
+    fn test_get_repo_name() {
+        for (repo, name) in [
+            ("https://github.com/yhakbar/cln.git", "cln"),
+            ("https://github.com/yhakbar/cln", "cln"),
+            ("git@github.com:cln.git", "cln"),
+            ("file:///srv/git/cln.git/", "cln"),
+            ("/srv/git/cln/", "cln"),
+            ("/srv/git/cln/.git", "cln"),
+            ("/srv/git/my.github.io.git", "my.github.io"),
+        ] {
+            assert_eq!(get_repo_name(repo), PathBuf::from(name), "{repo}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cln_local_bare_repo() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let repos = create_temp_dir(None).expect("Failed to create repos tempdir");
+        run_git(
+            repos.path(),
+            &[
+                "clone",
+                "--quiet",
+                "--bare",
+                path_str(fixture.path()),
+                "local.git",
+            ],
+        );
+        let bare = repos.path().join("local.git");
+
+        for repo in [
+            format!("{}/", path_str(&bare)),
+            format!("file://{}", path_str(&bare)),
+        ] {
+            let store = create_temp_dir(None).expect("Failed to create store tempdir");
+            let output_base = create_temp_dir(None).expect("Failed to create output tempdir");
+
+            Builder::new(&repo)
+                .store_path(store.path().to_path_buf())
+                .output_base(output_base.path().to_path_buf())
+                .branch("main")
+                .cln()
+                .await
+                .unwrap_or_else(|e| panic!("Failed to cln {repo}: {e}"));
+
+            assert!(output_base.path().join("local").join("README.md").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warm_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let repo = path_str(fixture.path());
+
+        warm_store(repo, None, Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to warm store");
+
+        assert!(is_content_stored(store.path(), &head));
+        assert!(!target.path().join("README.md").exists());
+
+        cln(
+            repo,
+            Some(target.path().to_path_buf()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cln from warm store");
+
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_warm_store_all_branches() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let main = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        run_git(fixture.path(), &["checkout", "-b", "other"]);
+        std::fs::write(fixture.path().join("other.txt"), "other\n").expect("Failed to write");
+        run_git(fixture.path(), &["add", "other.txt"]);
+        run_git(fixture.path(), &["commit", "-m", "Other"]);
+        let other = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+
+        warm_store_all_branches(
+            &format!("file://{}", path_str(fixture.path())),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to warm store with every branch");
+
+        for hash in [main, other] {
+            assert!(store.path().join(hash).exists());
+        }
+        let other_blob = run_git(fixture.path(), &["rev-parse", "other:other.txt"]);
+        assert!(store.path().join(other_blob).exists());
+    }
+
+    #[tokio::test]
+    async fn test_preserve_empty_dirs() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let fixture_path = fixture.path();
+
+        // Git won't commit an empty directory, so graft an empty tree into the root tree by hand.
+        let empty_tree = run_git(
+            fixture_path,
+            &["hash-object", "-t", "tree", "-w", "/dev/null"],
+        );
+        let root_tree = run_git(fixture_path, &["ls-tree", "HEAD"]);
+        let tree = mktree(
+            fixture_path,
+            &format!("{root_tree}\n040000 tree {empty_tree}\tempty\n"),
+        );
+        let commit = run_git(fixture_path, &["commit-tree", &tree, "-m", "Add empty dir"]);
+        run_git(fixture_path, &["update-ref", "refs/heads/main", &commit]);
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture_path))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .preserve_empty_dirs(true)
+            .cln()
+            .await
+            .expect("Failed to cln with preserve_empty_dirs");
+
+        assert!(target.path().join("empty").is_dir());
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_warm_store_deep_tree() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let deep_dir = (0..256).fold(fixture.path().join("deep"), |dir, depth| {
+            dir.join(depth.to_string())
+        });
+        std::fs::create_dir_all(&deep_dir).expect("Failed to create deep fixture directory");
+        std::fs::write(deep_dir.join("bottom.txt"), "bottom\n")
+            .expect("Failed to write bottom.txt");
+        run_git(fixture.path(), &["add", "."]);
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--message", "Add deep tree"],
+        );
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        warm_store(
+            path_str(fixture.path()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to warm store with deep tree");
+
+        let bottom = deep_dir
+            .strip_prefix(fixture.path())
+            .expect("Failed to strip fixture path")
+            .join("bottom.txt");
+        let bottom_hash = run_git(
+            fixture.path(),
+            &["rev-parse", &format!("HEAD:{}", bottom.display())],
+        );
+        assert!(store.path().join(bottom_hash).exists());
+    }
+
+    #[tokio::test]
+    async fn test_walk_deep_tree() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let fixture_path = fixture.path();
+        let deep_dir = (0..256).fold(fixture_path.join("deep"), |dir, depth| {
+            dir.join(depth.to_string())
+        });
+        std::fs::create_dir_all(&deep_dir).expect("Failed to create deep fixture directory");
+        std::fs::write(deep_dir.join("bottom.txt"), "bottom\n")
+            .expect("Failed to write bottom.txt");
+        run_git(fixture_path, &["add", "."]);
+        run_git(
+            fixture_path,
+            &["commit", "--quiet", "--message", "Add deep tree"],
+        );
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = path_str(fixture_path);
+        let deep_file = deep_dir
+            .strip_prefix(fixture_path)
+            .expect("Failed to strip fixture path")
+            .join("bottom.txt");
+
+        // The first cln populates the store, and the second links from it.
+        for _ in 0..2 {
+            let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+            cln(
+                repo,
+                Some(target.path().to_path_buf()),
+                None,
+                Some(store.path().to_path_buf()),
+            )
+            .await
+            .expect("Failed to cln deep tree");
+
+            assert!(target.path().join(&deep_file).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tree_missing_from_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let fixture_path = fixture.path();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = path_str(fixture_path);
+
+        warm_store(repo, None, Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to warm store");
+
+        let nested_tree = run_git(fixture_path, &["rev-parse", "HEAD:src/nested"]);
+        std::fs::remove_file(store.path().join(&nested_tree))
+            .expect("Failed to remove nested tree from store");
+
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        cln(
+            repo,
+            Some(target.path().to_path_buf()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cln with a tree missing from the store");
+
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+        assert!(store.path().join(&nested_tree).exists());
+    }
+
+    #[tokio::test]
+    async fn test_ls_tree_failure_not_stored() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let store_path = ensure_cln_store_path(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to set store path");
+
+        let missing = "0123456789012345678901234567890123456789";
+        assert!(matches!(
+            fixture
+                .path()
+                .ls_tree(
+                    &store_path,
+                    missing,
+                    ".".to_string(),
+                    &[],
+                    &ParsePool::default(),
+                )
+                .await,
+            Err(Error::ReadObjectError(_))
+        ));
+        assert!(!store.path().join(missing).exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_blob_missing_from_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let fixture_path = fixture.path();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = path_str(fixture_path);
+
+        warm_store(repo, None, Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to warm store");
+
+        let readme = run_git(fixture_path, &["rev-parse", "HEAD:README.md"]);
+        std::fs::remove_file(store.path().join(&readme))
+            .expect("Failed to remove README blob from store");
+
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        cln(
+            repo,
+            Some(target.path().to_path_buf()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cln with a blob missing from the store");
+
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md"))
+                .expect("Failed to read README"),
+            "# Fixture\n"
+        );
+        assert!(store.path().join(&readme).exists());
+    }
+
+    #[tokio::test]
+    async fn test_output_format_git() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .output_format(OutputFormat::Git)
+            .cln()
+            .await
+            .expect("Failed to cln with a .git directory");
+
+        assert_eq!(run_git(target.path(), &["rev-parse", "HEAD"]), head);
+        assert_eq!(run_git(target.path(), &["status", "--porcelain"]), "");
+    }
+
+    #[tokio::test]
+    async fn test_continue_on_error() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        // A file where the `src` directory belongs makes every blob under it fail to link.
+        std::fs::write(target.path().join("src"), "").expect("Failed to write blocking file");
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .continue_on_error(true)
+            .cln()
+            .await
+            .expect_err("Expected a partial checkout");
+
+        let Error::PartialCheckoutError(failures) = err else {
+            panic!("Expected a partial checkout, got {err}");
+        };
+        let paths = failures
+            .iter()
+            .map(|(path, _)| path.as_path())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("./src/main.rs"),
+                Path::new("./src/nested/file.txt")
+            ]
+        );
+        assert!(target.path().join("README.md").exists());
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_policy_sync() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let repo = path_str(fixture.path());
+
+        cln(
+            repo,
+            Some(target.path().to_path_buf()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cln");
+
+        std::fs::write(target.path().join("src").join("orphan.rs"), "")
+            .expect("Failed to write orphan file");
+        std::fs::create_dir_all(target.path().join("orphan").join("dir"))
+            .expect("Failed to create orphan dir");
+
+        Builder::new(repo)
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .overwrite_policy(OverwritePolicy::Sync)
+            .cln()
+            .await
+            .expect("Failed to cln with sync");
+
+        assert!(!target.path().join("src").join("orphan.rs").exists());
+        assert!(!target.path().join("orphan").exists());
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+        assert!(target.path().join("README.md").exists());
+    }
+
+    #[tokio::test]
+    async fn test_reference_repo() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let reference = create_temp_dir(None).expect("Failed to create reference tempdir");
+        run_git(
+            reference.path(),
+            &["clone", "--quiet", "--bare", path_str(fixture.path()), "."],
+        );
+        let unrelated = create_temp_dir(None).expect("Failed to create unrelated tempdir");
+        run_git(unrelated.path(), &["init", "--quiet"]);
+
+        // The first reference contains the commit, and the second falls back to cloning.
+        for reference_repo in [reference.path(), unrelated.path()] {
+            let store = create_temp_dir(None).expect("Failed to create store tempdir");
+            let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+            Builder::new(path_str(fixture.path()))
+                .dir(target.path().to_path_buf())
+                .store_path(store.path().to_path_buf())
+                .reference_repo(reference_repo.to_path_buf())
+                .cln()
+                .await
+                .expect("Failed to cln with a reference repo");
+
+            assert!(target
+                .path()
+                .join("src")
+                .join("nested")
+                .join("file.txt")
+                .exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_link() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = path_str(fixture.path());
+
+        warm_store(repo, None, Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to warm store");
+
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let stored_readme = store.path().join(&readme);
+        let mut permissions = std::fs::metadata(&stored_readme)
+            .expect("Failed to read stored README metadata")
+            .permissions();
+        permissions.set_mode(0o644);
+        std::fs::set_permissions(&stored_readme, permissions)
+            .expect("Failed to make stored README writable");
+        std::fs::write(&stored_readme, "tampered\n").expect("Failed to tamper with stored README");
+
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let err = Builder::new(repo)
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .verify_on_link(true)
+            .cln()
+            .await
+            .expect_err("Expected the tampered store to be detected");
+
+        assert!(matches!(err, Error::StoreCorruption { hash } if hash == readme));
+    }
+
+    #[tokio::test]
+    async fn test_manifest() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let manifest = store.path().join("manifest.csv");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .manifest(manifest.clone())
+            .cln()
+            .await
+            .expect("Failed to cln with a manifest");
+
+        let manifest = std::fs::read_to_string(manifest).expect("Failed to read manifest");
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let nested = run_git(fixture.path(), &["rev-parse", "HEAD:src/nested/file.txt"]);
+
+        assert_eq!(manifest.lines().count(), 4);
+        assert!(manifest.starts_with("path,hash,mode\n"));
+        assert!(manifest.contains(&format!("README.md,{readme},100644\n")));
+        assert!(manifest.contains(&format!("src/nested/file.txt,{nested},100644\n")));
+    }
+
+    #[test]
+    fn test_ls_remote_peels_annotated_tags() {
+        let ls_remote = LsRemote::new(
+            "1111111111111111111111111111111111111111\trefs/tags/v1\n\
+             2222222222222222222222222222222222222222\trefs/tags/v1^{}",
+            "v1",
+            &ParsePool::default(),
+        );
+
+        assert_eq!(ls_remote.rows.len(), 2);
+        assert_eq!(
+            ls_remote.get_hash().expect("Failed to get hash"),
+            "2222222222222222222222222222222222222222"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cln_annotated_tag() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        run_git(
+            fixture.path(),
+            &["tag", "--annotate", "v1", "--message", "v1"],
+        );
+        let commit = run_git(fixture.path(), &["rev-list", "-n", "1", "v1"]);
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        cln(
+            path_str(fixture.path()),
+            Some(target.path().to_path_buf()),
+            Some("v1"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cln annotated tag");
+
+        assert!(target.path().join("README.md").exists());
+        assert!(is_content_stored(store.path(), &commit));
+    }
+
+    #[tokio::test]
+    async fn test_output_base() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let output_dir = create_temp_dir(None).expect("Failed to create output tempdir");
+        // Nested directories that don't exist yet, as with a shared vendor prefix.
+        let output_base = output_dir.path().join("vendor").join("third_party");
+        let repo = path_str(fixture.path());
+
+        Builder::new(repo)
+            .store_path(store.path().to_path_buf())
+            .output_base(output_base.clone())
+            .cln()
+            .await
+            .expect("Failed to cln with an output base");
+
+        assert!(output_base
+            .join(get_repo_name(repo))
+            .join("README.md")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "rs"))
+            .cln()
+            .await
+            .expect("Failed to cln with a path filter");
+
+        assert!(target.path().join("src").join("main.rs").exists());
+        assert!(!target.path().join("README.md").exists());
+        assert!(!target.path().join("src").join("nested").exists());
+
+        // The store is warm now, so this links from the store.
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .filter(|entry| entry.size().is_some_and(|size| size < 10))
+            .cln()
+            .await
+            .expect("Failed to cln with a size filter");
+
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+        assert!(!target.path().join("README.md").exists());
+        assert!(!target.path().join("src").join("main.rs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_atomic() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let parent = create_temp_dir(None).expect("Failed to create parent tempdir");
+        let target = parent.path().join("target");
+        std::fs::create_dir(&target).expect("Failed to create target");
+        std::fs::write(target.join("stale.txt"), "stale").expect("Failed to write stale file");
+
+        let missing = parent.path().join("missing-repo");
+        Builder::new(path_str(&missing))
+            .dir(target.clone())
+            .store_path(store.path().to_path_buf())
+            .atomic(true)
+            .force(true)
+            .cln()
+            .await
+            .expect_err("Expected cln of a missing repo to fail");
+
+        let entries = std::fs::read_dir(parent.path())
+            .expect("Failed to read parent")
+            .count();
+        assert_eq!(entries, 1, "Expected the staging directory to be removed");
+        assert!(target.join("stale.txt").exists());
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.clone())
+            .store_path(store.path().to_path_buf())
+            .atomic(true)
+            .cln()
+            .await
+            .expect_err("Expected cln into a non-empty target to fail without force");
+        assert!(matches!(err, Error::TargetExistsError(_)));
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.clone())
+            .store_path(store.path().to_path_buf())
+            .atomic(true)
+            .force(true)
+            .cln()
+            .await
+            .expect("Failed to cln atomically");
+
+        assert!(target.join("README.md").exists());
+        assert!(!target.join("stale.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_strip_components() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .strip_components(1)
+            .overwrite_policy(OverwritePolicy::Sync)
+            .cln()
+            .await
+            .expect("Failed to cln with stripped components");
+
+        assert!(target.path().join("main.rs").exists());
+        assert!(target.path().join("nested").join("file.txt").exists());
+        assert!(!target.path().join("README.md").exists());
+        assert!(!target.path().join("src").exists());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_to() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let live = target.path().join("live");
+        let backups = [
+            target.path().join("backup"),
+            target.path().join("nested").join("backup"),
+        ];
+        std::fs::create_dir_all(&backups[0]).expect("Failed to create backup");
+        std::fs::write(backups[0].join("stale.txt"), "stale").expect("Failed to write stale file");
+
+        backups
+            .iter()
+            .fold(
+                Builder::new(path_str(fixture.path()))
+                    .dir(live.clone())
+                    .store_path(store.path().to_path_buf())
+                    .write_metadata(true)
+                    .overwrite_policy(OverwritePolicy::Sync),
+                |builder, backup| builder.mirror_to(backup.clone()),
+            )
+            .cln()
+            .await
+            .expect("Failed to cln with mirrors");
+
+        for dir in std::iter::once(&live).chain(&backups) {
+            assert_eq!(
+                std::fs::read_to_string(dir.join("src").join("nested").join("file.txt"))
+                    .expect("Failed to read mirrored file"),
+                "nested\n"
+            );
+        }
+        // The store, the target and both mirrors share the same inode.
+        let readme = std::fs::metadata(live.join("README.md")).expect("Failed to read README.md");
+        assert_eq!(readme.nlink(), 4);
+        assert!(!backups[0].join("stale.txt").exists());
+        assert!(live.join(".cln").exists());
+        assert!(!backups[0].join(".cln").exists());
+    }
+
+    #[tokio::test]
+    async fn test_prefixes() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .strip_prefix(PathBuf::from("./src"))
+            .add_prefix(PathBuf::from("vendor/fixture"))
+            .overwrite_policy(OverwritePolicy::Sync)
+            .cln()
+            .await
+            .expect("Failed to cln with prefixes");
+
+        let checkout = target.path().join("vendor").join("fixture");
+        assert!(checkout.join("main.rs").exists());
+        assert!(checkout.join("nested").join("file.txt").exists());
+        assert!(!checkout.join("README.md").exists());
+        assert!(!checkout.join("src").exists());
+        // Files outside of the stripped prefix are never fetched.
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        assert!(!store.path().join(readme_hash).exists());
+
+        for prefix in ["../escape", "/absolute"] {
+            let err = Builder::new(path_str(fixture.path()))
+                .dir(target.path().to_path_buf())
+                .store_path(store.path().to_path_buf())
+                .add_prefix(PathBuf::from(prefix))
+                .cln()
+                .await
+                .expect_err("Accepted a prefix outside of the target");
+            assert!(matches!(err, Error::InvalidPrefixError(_)));
+        }
+    }
+
+    #[test]
+    fn test_checkout_path() {
+        let options = ClnOptions {
+            strip_prefix: Some(PathBuf::from("src")),
+            strip_components: 1,
+            add_prefix: Some(PathBuf::from("vendor")),
+            ..ClnOptions::default()
+        };
+        assert_eq!(
+            checkout_path(Path::new("src/nested/file.txt"), &options),
+            Some(PathBuf::from("vendor/file.txt"))
+        );
+        assert_eq!(checkout_path(Path::new("src/main.rs"), &options), None);
+        assert_eq!(checkout_path(Path::new("README.md"), &options), None);
+
+        assert!(!is_outside_prefix(Path::new("src"), &options));
+        assert!(!is_outside_prefix(Path::new("src/nested"), &options));
+        assert!(is_outside_prefix(Path::new("docs"), &options));
+        assert!(is_outside_prefix(Path::new("srcs"), &options));
+    }
+
+    #[test]
+    fn test_strip_components_of_path() {
+        let path = Path::new("src/nested/file.txt");
+        assert_eq!(strip_components(path, 0), Some(path.to_path_buf()));
+        assert_eq!(strip_components(path, 2), Some(PathBuf::from("file.txt")));
+        assert_eq!(strip_components(path, 3), None);
+        assert_eq!(strip_components(path, 4), None);
+    }
+
+    #[tokio::test]
+    async fn test_cln_rejects_option_like_branch() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let pwned = target.path().join("pwned");
+        let branch = format!("--upload-pack=touch {}", path_str(&pwned));
+
+        let err = cln(
+            path_str(fixture.path()),
+            Some(target.path().join("checkout")),
+            Some(&branch),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect_err("Expected an option-like branch to be rejected");
+
+        assert!(matches!(err, Error::InvalidRefError(_)));
+        assert!(!pwned.exists());
+    }
+
+    #[tokio::test]
+    async fn test_post_checkout() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .post_checkout("test -f README.md && echo \"$CLN_HASH\" > hash.txt")
+            .cln()
+            .await
+            .expect("Failed to cln with a post-checkout command");
+
+        let hash = std::fs::read_to_string(target.path().join("hash.txt"))
+            .expect("Failed to read post-checkout output");
+        assert_eq!(hash.trim_end(), head);
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .post_checkout("echo failed >&2 && exit 3")
+            .cln()
+            .await
+            .expect_err("Expected a failing post-checkout command to fail the cln");
+
+        assert!(matches!(&err, Error::PostCheckoutFailed(output) if output.contains("failed")));
+    }
+
+    #[tokio::test]
+    async fn test_store_read_only() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let targets = create_temp_dir(None).expect("Failed to create target tempdir");
+        let repo = path_str(fixture.path());
+
+        cln(
+            repo,
+            Some(targets.path().join("warm")),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to warm the store");
+        std::fs::write(fixture.path().join("new.txt"), "new\n").expect("Failed to write");
+        run_git(fixture.path(), &["add", "new.txt"]);
+        run_git(fixture.path(), &["commit", "-m", "New"]);
+        let stored_objects = || {
+            std::fs::read_dir(store.path())
+                .expect("Failed to read store")
+                .count()
+        };
+        let objects = stored_objects();
+
+        let target = targets.path().join("read-only");
+        Builder::new(repo)
+            .dir(target.clone())
+            .store_path(store.path().to_path_buf())
+            .store_read_only(true)
+            .cln()
+            .await
+            .expect("Failed to cln from a read-only store");
+
+        assert_eq!(
+            std::fs::read_to_string(target.join("new.txt")).expect("Failed to read"),
+            "new\n"
+        );
+        assert!(target.join("src").join("nested").join("file.txt").exists());
+        assert_eq!(stored_objects(), objects);
+        let leftovers = std::fs::read_dir(targets.path())
+            .expect("Failed to read targets")
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".cln-store")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_store_read_only_concurrent() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixtures = (0..8).map(|_| create_fixture_repo()).collect::<Vec<_>>();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let targets = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        cln(
+            path_str(fixtures[0].path()),
+            Some(targets.path().join("warm")),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to warm the store");
+        for (index, fixture) in fixtures.iter().enumerate() {
+            std::fs::write(fixture.path().join("new.txt"), format!("{index}\n"))
+                .expect("Failed to write");
+            run_git(fixture.path(), &["add", "new.txt"]);
+            run_git(fixture.path(), &["commit", "-m", "New"]);
+        }
+        let stored_objects = || {
+            std::fs::read_dir(store.path())
+                .expect("Failed to read store")
+                .count()
+        };
+        let objects = stored_objects();
+
+        // Each checkout writes what's missing from the store to a scratch store of its own.
+        let results = cln_all(fixtures.iter().enumerate().map(|(index, fixture)| {
+            Builder::new(path_str(fixture.path()))
+                .dir(targets.path().join(index.to_string()))
+                .store_path(store.path().to_path_buf())
+                .store_read_only(true)
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+
+        for index in 0..fixtures.len() {
+            let target = targets.path().join(index.to_string());
+            assert_eq!(
+                std::fs::read_to_string(target.join("new.txt")).expect("Failed to read"),
+                format!("{index}\n")
+            );
+            assert!(target.join("src").join("nested").join("file.txt").exists());
+        }
+        assert_eq!(stored_objects(), objects);
+        assert_eq!(
+            std::fs::read_dir(targets.path())
+                .expect("Failed to read targets")
+                .count(),
+            fixtures.len() + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_checkout_skipped_when_unchanged() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let builder = |post_checkout: &str| {
+            Builder::new(path_str(fixture.path()))
+                .dir(target.path().to_path_buf())
+                .store_path(store.path().to_path_buf())
+                .write_metadata(true)
+                .post_checkout(post_checkout)
+        };
+
+        builder("touch ran.txt")
+            .cln()
+            .await
+            .expect("Failed to cln with a post-checkout command");
+        assert!(target.path().join("ran.txt").exists());
+
+        builder("exit 1")
+            .cln()
+            .await
+            .expect("Expected the post-checkout command to be skipped");
+    }
+
+    // Serves a repository with a single commit from memory.
+    #[derive(Debug)]
+    struct MemorySource {
+        objects: std::collections::HashMap<&'static str, &'static str>,
+    }
+
+    #[async_trait]
+    impl ObjectSource for MemorySource {
+        async fn resolve_ref(&self, reference: &str) -> Result<String, Error> {
+            match reference {
+                "main" => Ok("c0".to_string()),
+                _ => Err(Error::NoMatchingReferenceError),
+            }
+        }
+        async fn ls_tree(&self, hash: &str) -> Result<String, Error> {
+            self.objects
+                .get(hash)
+                .map(ToString::to_string)
+                .ok_or_else(|| Error::ReadObjectError(hash.to_string()))
+        }
+        async fn cat_blob(&self, hash: &str) -> Result<Vec<u8>, Error> {
+            self.objects
+                .get(hash)
+                .map(|content| content.as_bytes().to_vec())
+                .ok_or_else(|| Error::ReadObjectError(hash.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_source() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let source = MemorySource {
+            objects: [
+                ("c0", "100644 blob b0\tREADME.md\n040000 tree t1\tsrc"),
+                ("t1", "100644 blob b1\tlib.rs"),
+                ("b0", "# Memory\n"),
+                ("b1", "pub fn memory() {}\n"),
+            ]
+            .into(),
+        };
+
+        Builder::new("memory")
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .branch("main")
+            .source(source)
+            .cln()
+            .await
+            .expect("Failed to cln from an in-memory source");
+
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("src").join("lib.rs"))
+                .expect("Failed to read lib.rs"),
+            "pub fn memory() {}\n"
+        );
+        assert!(store.path().join("b0").exists());
+        assert!(store.path().join("c0").exists());
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_skips_clone() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        for repo in [
+            path_str(fixture.path()).to_string(),
+            format!("file://{}", path_str(fixture.path())),
+        ] {
+            // A clone would need a temporary directory, which can't be created here.
+            Builder::new(&repo)
+                .dir(target.path().join(get_repo_name(&repo)))
+                .store_path(store.path().to_path_buf())
+                .temp_dir(target.path().join("missing"))
+                .cln()
+                .await
+                .unwrap_or_else(|e| panic!("Failed to cln local repo {repo}: {e}"));
+        }
+
+        assert!(target
+            .path()
+            .join(get_repo_name(path_str(fixture.path())))
+            .join("src")
+            .join("main.rs")
+            .exists());
+    }
+
+    // Resolves every reference to the same commit, without being able to read any objects.
+    #[derive(Debug)]
+    struct ResolveOnlySource(String);
+
+    #[async_trait]
+    impl ObjectSource for ResolveOnlySource {
+        async fn resolve_ref(&self, _reference: &str) -> Result<String, Error> {
+            Ok(self.0.clone())
+        }
+        async fn ls_tree(&self, hash: &str) -> Result<String, Error> {
+            Err(Error::ReadObjectError(hash.to_string()))
+        }
+        async fn cat_blob(&self, hash: &str) -> Result<Vec<u8>, Error> {
+            Err(Error::ReadObjectError(hash.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reference_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let repo = path_str(fixture.path());
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let reference_store = create_temp_dir(None).expect("Failed to create store tempdir");
+        warm_store(repo, None, Some(reference_store.path().to_path_buf()))
+            .await
+            .expect("Failed to warm reference store");
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(repo)
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .reference_store(reference_store.path().to_path_buf())
+            .source(ResolveOnlySource(head.clone()))
+            .cln()
+            .await
+            .expect("Failed to cln from the reference store");
+
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+        assert!(store.path().join(&head).exists());
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let hash = "0".repeat(40);
+        let tree = Tree::new(
+            &format!("100644 blob {hash}\tREADME\n100644 blob {hash}\tReadme\n100644 blob {hash}\tmain.rs"),
+            "docs".to_string(),
+            &ParsePool::default(),
+        );
+        assert_eq!(
+            case_collision(&tree),
+            Some((PathBuf::from("docs/README"), PathBuf::from("docs/Readme")))
+        );
+        assert!(check_case_collisions(&tree, &ClnOptions::default()).is_ok());
+        let case_insensitive = ClnOptions {
+            case_insensitive: true,
+            ..ClnOptions::default()
+        };
+        assert!(matches!(
+            check_case_collisions(&tree, &case_insensitive),
+            Err(Error::CaseCollisionError { .. })
+        ));
+
+        let tree = Tree::new(
+            &format!("100644 blob {hash}\tREADME\n040000 tree {hash}\tsrc"),
+            ".".to_string(),
+            &ParsePool::default(),
+        );
+        assert_eq!(case_collision(&tree), None);
+    }
+
+    #[test]
+    fn test_tree_rows_are_sorted() {
+        let listing = (0..64)
+            .rev()
+            .map(|i| format!("100644 blob {i:040x}\tfile-{i:02}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let tree = Tree::new(&listing, ".".to_string(), &ParsePool::default());
+
+        let paths = tree
+            .rows
+            .iter()
+            .map(|row| row.path.as_str())
+            .collect::<Vec<&str>>();
+        let mut sorted = paths.clone();
+        sorted.sort_unstable();
+        assert_eq!(paths, sorted);
+        assert_eq!(paths.len(), 64);
+    }
+
+    #[test]
+    fn test_ls_remote_head_symref() {
+        let ls_remote = LsRemote::new(
+            "ref: refs/heads/main\tHEAD\n\
+             1111111111111111111111111111111111111111\tHEAD",
+            HEAD,
+            &ParsePool::default(),
+        );
+
+        assert_eq!(ls_remote.head_symref.as_deref(), Some("main"));
+        assert_eq!(
+            ls_remote.get_hash().expect("Failed to get hash"),
+            "1111111111111111111111111111111111111111"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cln_unknown_branch_lists_available_refs() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        run_git(fixture.path(), &["tag", "v1"]);
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        let err = cln(
+            &format!("file://{}", path_str(fixture.path())),
+            Some(target.path().join("checkout")),
+            Some("missing"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect_err("Expected an unknown branch to fail");
+
+        match &err {
+            Error::UnknownReferenceError {
+                reference,
+                available,
+                ..
+            } => {
+                assert_eq!(reference, "missing");
+                assert_eq!(available, &vec!["main".to_string(), "v1".to_string()]);
+            }
+            _ => panic!("Expected an unknown reference error, got {err}"),
+        }
+        assert!(err.to_string().contains("main, v1"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tree() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+
+        let entries = resolve_tree(
+            path_str(fixture.path()),
+            None,
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to resolve tree");
+
+        let paths = entries
+            .iter()
+            .map(|entry| entry.path().display().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            paths,
+            vec![
+                "README.md",
+                "src",
+                "src/main.rs",
+                "src/nested",
+                "src/nested/file.txt"
+            ]
+        );
+        assert_eq!(entries[0].hash(), readme);
+        assert_eq!(entries[0].object_type(), "blob");
+        assert_eq!(entries[0].mode(), "100644");
+        assert_eq!(entries[0].size(), None);
+        assert_eq!(entries[1].object_type(), "tree");
+        assert!(!store.path().join(&readme).exists());
+    }
+
+    #[tokio::test]
+    async fn test_list_tree() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let expected = vec![
+            ("README.md".to_string(), 10),
+            ("src/main.rs".to_string(), 13),
+            ("src/nested/file.txt".to_string(), 7),
+        ];
+        let list = || async {
+            list_tree(
+                path_str(fixture.path()),
+                None,
+                Some(store.path().to_path_buf()),
+            )
+            .await
+            .expect("Failed to list tree")
+        };
+        let paths_and_sizes = |entries: &[TreeEntry]| {
+            entries
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.path().display().to_string(),
+                        entry.size().expect("Missing size"),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Sizes are looked up in the repository, without writing the files to the store.
+        let entries = list().await;
+        assert_eq!(paths_and_sizes(&entries), expected);
+        assert_eq!(entries[0].hash(), readme);
+        assert_eq!(entries[0].mode(), "100644");
+        assert!(entries.iter().all(|entry| entry.object_type() == "blob"));
+        assert!(!store.path().join(&readme).exists());
+
+        // And read from the store once the files are in it.
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln");
+        assert_eq!(paths_and_sizes(&list().await), expected);
+    }
+
+    #[tokio::test]
+    async fn test_write_metadata() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let repo = path_str(fixture.path());
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+
+        Builder::new(repo)
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .branch("main")
+            .write_metadata(true)
+            .overwrite_policy(OverwritePolicy::Sync)
+            .cln()
+            .await
+            .expect("Failed to cln with metadata");
+
+        let metadata = read_metadata(target.path())
+            .await
+            .expect("Failed to read metadata");
+        assert_eq!(metadata.repo, repo);
+        assert_eq!(metadata.reference, "main");
+        assert_eq!(metadata.hash, head);
+    }
+
+    #[tokio::test]
+    async fn test_cln_another_branch_into_target() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        run_git(fixture.path(), &["checkout", "-b", "other"]);
+        std::fs::write(fixture.path().join("README.md"), "# Other\n").expect("Failed to write");
+        std::fs::write(fixture.path().join("other.txt"), "other\n").expect("Failed to write");
+        run_git(fixture.path(), &["add", "."]);
+        run_git(fixture.path(), &["commit", "-m", "Other"]);
+        run_git(fixture.path(), &["checkout", "main"]);
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let repo = path_str(fixture.path());
+        let builder = |branch: &str| {
+            Builder::new(repo)
+                .dir(target.path().to_path_buf())
+                .store_path(store.path().to_path_buf())
+                .branch(branch)
+                .write_metadata(true)
+        };
+
+        builder("other").cln().await.expect("Failed to cln other");
+        builder("other")
+            .cln()
+            .await
+            .expect("Failed to cln other again");
+
+        let result = builder("main").cln().await;
+        assert!(
+            matches!(result, Err(Error::TargetMismatchError { .. })),
+            "{result:?}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md")).expect("Failed to read"),
+            "# Other\n"
+        );
+
+        builder("main")
+            .overwrite_policy(OverwritePolicy::Sync)
+            .cln()
+            .await
+            .expect("Failed to sync main");
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md")).expect("Failed to read"),
+            "# Fixture\n"
+        );
+        assert!(!target.path().join("other.txt").exists());
+        assert_eq!(
+            read_metadata(target.path())
+                .await
+                .expect("Failed to read metadata")
+                .hash,
+            run_git(fixture.path(), &["rev-parse", "main"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_refs() {
+        let fixture = create_fixture_repo();
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        run_git(fixture.path(), &["tag", "v1.2.0"]);
+        run_git(fixture.path(), &["tag", "-a", "v1.10.0", "-m", "Release"]);
+        run_git(fixture.path(), &["tag", "nightly"]);
+
+        let refs = resolve_refs(path_str(fixture.path()), "v*")
+            .await
+            .expect("Failed to resolve refs");
+
+        assert_eq!(
+            refs,
+            vec![
+                ("v1.10.0".to_string(), head.clone()),
+                ("v1.2.0".to_string(), head.clone()),
+            ]
+        );
+        assert_eq!(
+            latest_version(&refs).map(|(name, _)| name.as_str()),
+            Some("v1.10.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_temp_dir_removed_on_clone_failure() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let temp_dir = create_temp_dir(None).expect("Failed to create temp dir parent");
+
+        Builder::new("file:///nonexistent/repo.git")
+            .dir(target.path().join("checkout"))
+            .store_path(store.path().to_path_buf())
+            .temp_dir(temp_dir.path().to_path_buf())
+            .cln()
+            .await
+            .expect_err("Expected cln of a missing repo to fail");
+
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("Failed to read temp dir parent")
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_store_annotated_tag() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        run_git(
+            fixture.path(),
+            &["tag", "--annotate", "v1", "--message", "v1"],
+        );
+        let tag = run_git(fixture.path(), &["rev-parse", "v1"]);
+        let commit = run_git(fixture.path(), &["rev-list", "-n", "1", "v1"]);
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+
+        warm_store(
+            &format!("file://{}", path_str(fixture.path())),
+            Some("v1"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to warm store with an annotated tag");
+
+        assert_ne!(tag, commit);
+        assert!(store.path().join(&commit).exists());
+        assert!(!store.path().join(&tag).exists());
+    }
+
+    #[tokio::test]
+    async fn test_read_blob() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln the fixture");
+
+        let content = read_blob(
+            &head,
+            Path::new("src/nested/file.txt"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to read blob");
+        assert_eq!(content, b"nested\n");
+
+        for missing in ["src/missing.txt", "src", "README.md/file"] {
+            let err = read_blob(&head, Path::new(missing), Some(store.path().to_path_buf()))
+                .await
+                .expect_err("Expected a missing path to be rejected");
+            assert!(
+                matches!(err, Error::PathNotFoundError(_)),
+                "{missing}: {err}"
+            );
+        }
+
+        let err = read_blob(
+            "0000000000000000000000000000000000000000",
+            Path::new("README.md"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect_err("Expected a hash missing from the store to be rejected");
+        assert!(matches!(err, Error::NotInStoreError(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_cat_file() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = format!("file://{}", path_str(fixture.path()));
+
+        let content = cat_file(
+            &repo,
+            Some("main"),
+            Path::new("src/nested/file.txt"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to cat file");
+        assert_eq!(content, b"nested\n");
+
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let main_hash = run_git(fixture.path(), &["rev-parse", "HEAD:src/main.rs"]);
+        assert!(!store.path().join(readme_hash).exists());
+        assert!(!store.path().join(main_hash).exists());
+
+        let err = cat_file(
+            &repo,
+            None,
+            Path::new("src/nested"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect_err("Expected a directory to be rejected");
+        assert!(matches!(err, Error::PathNotFoundError(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_open_file() {
+        use tokio::io::AsyncReadExt;
+
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let repo = format!("file://{}", path_str(fixture.path()));
+
+        let mut file = open_file(
+            &repo,
+            None,
+            Path::new("src/main.rs"),
+            Some(store.path().to_path_buf()),
+        )
+        .await
+        .expect("Failed to open file");
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .await
+            .expect("Failed to read file");
+        assert_eq!(content, "fn main() {}\n");
+
+        let main_hash = run_git(fixture.path(), &["rev-parse", "HEAD:src/main.rs"]);
+        assert!(store.path().join(main_hash).exists());
+
+        assert!(matches!(
+            open_file(
+                &repo,
+                None,
+                Path::new("src/missing.rs"),
+                Some(store.path().to_path_buf()),
+            )
+            .await,
+            Err(Error::PathNotFoundError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_write_to_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let store_path = ensure_cln_store_path(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to ensure cln-store path");
+
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        TreeRow::new(&format!("100644 blob {readme_hash}\tREADME.md"))
+            .write_to_store(&store_path, fixture.path(), &[])
+            .await
+            .expect("Failed to write blob to store");
+        let stored = store.path().join(&readme_hash);
+        assert_eq!(
+            std::fs::read_to_string(&stored).expect("Failed to read stored blob"),
+            "# Fixture\n"
+        );
+        assert!(stored
+            .metadata()
+            .expect("Failed to read stored blob metadata")
+            .permissions()
+            .readonly());
+
+        let missing = "0123456789012345678901234567890123456789";
+        let err = TreeRow::new(&format!("100644 blob {missing}\tmissing.txt"))
+            .write_to_store(&store_path, fixture.path(), &[])
+            .await
+            .expect_err("Expected a missing blob to fail");
+        assert!(matches!(err, Error::ReadObjectError(_)), "{err}");
+        assert_eq!(
+            store::stored_files(store.path())
+                .expect("Failed to read store")
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dir_mode() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let targets = create_temp_dir(None).expect("Failed to create targets tempdir");
+        let target = targets.path().join("target");
+        let parent_mode = || {
+            targets
+                .path()
+                .metadata()
+                .expect("Failed to read parent metadata")
+                .permissions()
+                .mode()
+        };
+        let parent_mode_before = parent_mode();
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.clone())
+            .store_path(store.path().to_path_buf())
+            .dir_mode(0o750)
+            .cln()
+            .await
+            .expect("Failed to cln with a dir mode");
+
+        for dir in [
+            target.clone(),
+            target.join("src"),
+            target.join("src/nested"),
+        ] {
+            let mode = dir
+                .metadata()
+                .expect("Failed to read directory metadata")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o750, "{}", dir.display());
+        }
+        assert_eq!(parent_mode(), parent_mode_before);
+    }
+
+    #[test]
+    fn test_ls_remote_keeps_order() {
+        let listing = (0..1000)
+            .map(|i| format!("{i:040x}\trefs/heads/main"))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let ls_remote = LsRemote::new(&listing, "main", &ParsePool::default());
+
+        assert_eq!(
+            ls_remote.get_hash().expect("Failed to get hash"),
+            format!("{:040x}", 0)
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parse_pool() {
+        let parse_pool = ParsePool::new(Some(2)).expect("Failed to build parse pool");
+        let threads = parse_pool.parse_lines("a\nb\nc", |_| {
+            std::thread::current().name().map(str::to_string)
+        });
+
+        assert_eq!(threads.len(), 3);
+        assert!(
+            threads.iter().all(|name| name.starts_with("cln-parse-")),
+            "{threads:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deterministic() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .deterministic(true)
+            .cln()
+            .await
+            .expect("Failed to cln deterministically");
+
+        assert!(target.path().join("README.md").exists());
+        assert!(target.path().join("src").join("main.rs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_export_ignore() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        std::fs::write(
+            fixture.path().join(".gitattributes"),
+            "nested export-ignore\n*.md export-ignore\n",
+        )
+        .expect("Failed to write .gitattributes");
+        std::fs::write(fixture.path().join("src").join("README.md"), "# Src\n")
+            .expect("Failed to write src README");
+        std::fs::write(
+            fixture.path().join("src").join(".gitattributes"),
+            "README.md -export-ignore\n",
+        )
+        .expect("Failed to write src .gitattributes");
+        run_git(fixture.path(), &["add", "."]);
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--message", "Ignore exports"],
+        );
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .export_ignore(true)
+            .cln()
+            .await
+            .expect("Failed to cln with export-ignore");
+
+        assert!(!target.path().join("README.md").exists());
+        assert!(target.path().join("src").join("README.md").exists());
+        assert!(target.path().join("src").join("main.rs").exists());
+        assert!(!target.path().join("src").join("nested").exists());
+    }
+
+    #[tokio::test]
+    async fn test_max_store_bytes() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("checkout"))
+            .store_path(store.path().to_path_buf())
+            .max_store_bytes(0)
+            .cln()
+            .await
+            .expect("Failed to cln with a maximum store size");
+
+        // Every object is evicted, but the checkout keeps its hard links to them.
+        assert!(store::stored_files(store.path())
+            .expect("Failed to list store")
+            .is_empty());
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("checkout").join("README.md"))
+                .expect("Failed to read README"),
+            "# Fixture\n"
+        );
+    }
+
+    // Counts the blobs read from a directory object cache.
+    #[derive(Debug)]
+    struct CountingCache {
+        inner: DirObjectCache,
+        hits: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ObjectCache for CountingCache {
+        async fn get(
+            &self,
+            hash: &str,
+            writer: &mut (dyn AsyncWrite + Send + Unpin),
+        ) -> Result<bool, Error> {
+            let hit = self.inner.get(hash, writer).await?;
+            if hit {
+                self.hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(hit)
+        }
+        async fn put(&self, hash: &str, content_path: &Path) -> Result<(), Error> {
+            self.inner.put(hash, content_path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_cache() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let cache = create_temp_dir(None).expect("Failed to create cache tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        for checkout in ["first", "second"] {
+            let store = create_temp_dir(None).expect("Failed to create store tempdir");
+            Builder::new(path_str(fixture.path()))
+                .dir(target.path().join(checkout))
+                .store_path(store.path().to_path_buf())
+                .object_cache(CountingCache {
+                    inner: DirObjectCache::new(cache.path().to_path_buf()),
+                    hits: Arc::clone(&hits),
+                })
+                .cln()
+                .await
+                .expect("Failed to cln with an object cache");
+        }
+
+        // The first checkout populates the cache, and the second reads every blob from it.
+        assert!(cache.path().join(&readme).exists());
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("second").join("README.md"))
+                .expect("Failed to read README"),
+            "# Fixture\n"
+        );
+    }
+
+    // An object cache that can't be used, like an `HttpObjectCache` without curl installed.
+    #[derive(Debug)]
+    struct UnusableCache;
+
+    #[async_trait]
+    impl ObjectCache for UnusableCache {
+        async fn get(
+            &self,
+            _hash: &str,
+            _writer: &mut (dyn AsyncWrite + Send + Unpin),
+        ) -> Result<bool, Error> {
+            Ok(false)
+        }
+        async fn put(&self, _hash: &str, _content_path: &Path) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn check(&self) -> Result<(), Error> {
+            Err(Error::CurlNotFound)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unusable_object_cache() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("checkout"))
+            .store_path(store.path().to_path_buf())
+            .object_cache(UnusableCache)
+            .cln()
+            .await
+            .expect_err("Checked out with an unusable object cache");
+
+        assert!(matches!(err, Error::CurlNotFound));
+        assert!(!target.path().join("checkout").exists());
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let missing = format!("file://{}", path_str(&target.path().join("missing")));
+        let mirror = format!("file://{}", path_str(fixture.path()));
+
+        Builder::new(&missing)
+            .dir(target.path().join("checkout"))
+            .store_path(store.path().to_path_buf())
+            .mirror(&format!(
+                "file://{}",
+                path_str(&target.path().join("also-missing"))
+            ))
+            .mirror(&mirror)
+            .write_metadata(true)
+            .branch("main")
+            .cln()
+            .await
+            .expect("Failed to cln from a mirror");
+
+        assert!(target.path().join("checkout").join("README.md").exists());
+        let metadata = read_metadata(&target.path().join("checkout"))
+            .await
+            .expect("Failed to read metadata");
+        assert_eq!(metadata.repo, missing);
+        assert_eq!(metadata.mirror, Some(mirror));
+    }
+
+    #[tokio::test]
+    async fn test_older_tree_listing_is_listed_again() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        // A listing written before tree listings had a format header.
+        std::fs::write(
+            store.path().join(&head),
+            run_git(fixture.path(), &["ls-tree", "-z", "HEAD"]),
+        )
+        .expect("Failed to write older tree listing");
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("checkout"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln over an older tree listing");
+
+        assert!(target
+            .path()
+            .join("checkout")
+            .join("src")
+            .join("main.rs")
+            .exists());
+        let listing =
+            std::fs::read_to_string(store.path().join(&head)).expect("Failed to read tree listing");
+        assert!(parse_tree_listing(&listing).is_some(), "{listing}");
+    }
+
+    #[tokio::test]
+    async fn test_large_blob() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        // Much larger than a pipe buffer, so that it's only written correctly if it's streamed in full.
+        let content = (0..48 * 1024 * 1024_u32)
+            .map(|i| (i.wrapping_mul(31) % 251) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(fixture.path().join("large.bin"), &content)
+            .expect("Failed to write large blob");
+        run_git(fixture.path(), &["add", "large.bin"]);
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--message", "Add large blob"],
+        );
+        let large_hash = run_git(fixture.path(), &["rev-parse", "HEAD:large.bin"]);
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("first"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln a large blob");
+        // The tree is already stored, so the missing blob is streamed from the source rather than the walk of the clone.
+        std::fs::remove_file(store.path().join(&large_hash)).expect("Failed to remove large blob");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("second"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln a large blob from the source");
+
+        for name in ["first", "second"] {
+            assert!(
+                std::fs::read(target.path().join(name).join("large.bin"))
+                    .expect("Failed to read large blob")
+                    == content,
+                "{name}"
+            );
+        }
+        assert!(store
+            .path()
+            .join(&large_hash)
+            .metadata()
+            .expect("Failed to read stored blob metadata")
+            .permissions()
+            .readonly());
+    }
+
+    #[tokio::test]
+    async fn test_writable() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        for (name, writable) in [("linked", false), ("writable", true)] {
+            Builder::new(path_str(fixture.path()))
+                .dir(target.path().join(name))
+                .store_path(store.path().to_path_buf())
+                .writable(writable)
+                .cln()
+                .await
+                .expect("Failed to cln");
+        }
+
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let stored = store.path().join(&readme_hash);
+        let linked = target.path().join("linked").join("README.md");
+        let writable = target.path().join("writable").join("README.md");
+        let inode = |path: &Path| path.metadata().expect("Failed to read metadata").ino();
+        assert_eq!(inode(&linked), inode(&stored));
+        assert!(linked
+            .metadata()
+            .expect("Failed to read metadata")
+            .permissions()
+            .readonly());
+        assert_ne!(inode(&writable), inode(&stored));
+
+        // Editing a writable checkout leaves the store, and every other checkout, alone.
+        std::fs::write(&writable, "# Edited\n").expect("Failed to edit writable checkout");
+        assert_eq!(
+            std::fs::read_to_string(&stored).expect("Failed to read stored blob"),
+            "# Fixture\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&linked).expect("Failed to read linked checkout"),
+            "# Fixture\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writable_if() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .writable_if(|entry| {
+                entry
+                    .path()
+                    .extension()
+                    .is_some_and(|extension| extension == "md")
+            })
+            .cln()
+            .await
+            .expect("Failed to cln");
+
+        let inode = |path: &Path| path.metadata().expect("Failed to read metadata").ino();
+        let stored = |path: &str| {
+            store.path().join(run_git(
+                fixture.path(),
+                &["rev-parse", &format!("HEAD:{path}")],
+            ))
+        };
+        let readme = target.path().join("README.md");
+        assert_ne!(inode(&readme), inode(&stored("README.md")));
+        assert!(!readme
+            .metadata()
+            .expect("Failed to read metadata")
+            .permissions()
+            .readonly());
+        assert_eq!(
+            inode(&target.path().join("src/main.rs")),
+            inode(&stored("src/main.rs"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_symlinked_target_dir() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let links = create_temp_dir(None).expect("Failed to create links tempdir");
+        // Another filesystem where there's one, like `~/work -> /mnt/work`, so objects can't be hard linked into it.
+        let real = TempBuilder::new()
+            .prefix("cln-real")
+            .tempdir_in("/dev/shm")
+            .or_else(|_| create_temp_dir(None))
+            .expect("Failed to create real tempdir");
+
+        for (name, atomic) in [("checkout", false), ("atomic", true)] {
+            std::fs::create_dir(real.path().join(name)).expect("Failed to create real target");
+            let link = links.path().join(name);
+            std::os::unix::fs::symlink(real.path().join(name), &link)
+                .expect("Failed to symlink target");
+
+            Builder::new(path_str(fixture.path()))
+                .dir(link.clone())
+                .store_path(store.path().to_path_buf())
+                .atomic(atomic)
+                .cln()
+                .await
+                .expect("Failed to cln into a symlinked target");
+
+            assert!(link
+                .symlink_metadata()
+                .expect("Failed to read link")
+                .is_symlink());
+            assert_eq!(
+                std::fs::read_to_string(real.path().join(name).join("README.md"))
+                    .expect("Failed to read README through the link"),
+                "# Fixture\n"
+            );
+        }
+
+        // A target inside a symlinked directory, that doesn't exist yet.
+        let nested = links.path().join("checkout").join("nested").join("repo");
+        Builder::new(path_str(fixture.path()))
+            .dir(nested)
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln below a symlinked directory");
+        assert!(real
+            .path()
+            .join("checkout")
+            .join("nested")
+            .join("repo")
+            .join("src")
+            .join("main.rs")
+            .exists());
+    }
+
+    #[test]
+    fn test_resolve_target_dir() {
+        let dir = create_temp_dir(None).expect("Failed to create tempdir");
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).expect("Failed to create real dir");
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).expect("Failed to symlink");
+        let real = real
+            .canonicalize()
+            .expect("Failed to canonicalize real dir");
+
+        assert_eq!(resolve_target_dir(&dir.path().join("link")), real);
+        assert_eq!(
+            resolve_target_dir(&dir.path().join("link").join("new").join("repo")),
+            real.join("new").join("repo")
+        );
+        assert!(is_same_filesystem(dir.path(), &real.join("new")));
+    }
+
+    #[tokio::test]
+    async fn test_reference_commit() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("first"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln");
+
+        // The commit is in the store, so the repo isn't listed, or even reachable.
+        Builder::new("file:///nonexistent/fixture.git")
+            .dir(target.path().join("second"))
+            .store_path(store.path().to_path_buf())
+            .reference(Reference::Commit(head.clone()))
+            .cln()
+            .await
+            .expect("Failed to cln a stored commit");
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("second").join("README.md"))
+                .expect("Failed to read README"),
+            "# Fixture\n"
+        );
+
+        // A commit that isn't stored is fetched on its own.
+        let clones = create_temp_dir(None).expect("Failed to create clone tempdir");
+        let clone_path = clone_reference(
+            &format!("file://{}", path_str(fixture.path())),
+            clones.path(),
+            None,
+            &head,
+            &[],
+        )
+        .await
+        .expect("Failed to fetch commit");
+        assert_eq!(clone_path, clones.path().join("commit"));
+        assert_eq!(
+            rev_parse(&clone_path, &head)
+                .await
+                .expect("Failed to resolve fetched commit"),
+            head
+        );
+    }
 
-        let content_path = store_path.join(&self.name);
+    #[tokio::test]
+    async fn test_reference_tag() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        if content_path.exists() {
-            return Ok(());
-        }
+        // A branch and a tag of the same name, on different commits.
+        let fixture = create_fixture_repo();
+        run_git(
+            fixture.path(),
+            &["tag", "--annotate", "v1", "--message", "v1"],
+        );
+        run_git(fixture.path(), &["checkout", "--quiet", "-b", "v1"]);
+        std::fs::write(fixture.path().join("README.md"), "# Branch\n")
+            .expect("Failed to change README");
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--all", "--message", "Change README"],
+        );
+        run_git(fixture.path(), &["checkout", "--quiet", "main"]);
 
-        File::create(&content_path)
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .reference(Reference::Tag("v1".to_string()))
+            .cln()
             .await
-            .map_err(|e| Error::WriteToStoreError(content_path.to_string_lossy().to_string(), e))?;
+            .expect("Failed to cln tag");
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md"))
+                .expect("Failed to read README"),
+            "# Fixture\n"
+        );
 
-        debug!(
-            "Writing blob {} to store path {}",
-            self.name,
-            content_path.display()
+        // Listed remotely, the tag is matched by its full name, rather than the branch listed first, and peeled to its commit.
+        let ls_remote = LsRemote::new(
+            "3333333333333333333333333333333333333333\trefs/heads/v1\n\
+             1111111111111111111111111111111111111111\trefs/tags/v1\n\
+             2222222222222222222222222222222222222222\trefs/tags/v1^{}",
+            "refs/tags/v1",
+            &ParsePool::default(),
         );
+        assert_eq!(
+            ls_remote.get_hash().expect("Failed to get hash"),
+            "2222222222222222222222222222222222222222"
+        );
+    }
 
-        let output = Command::new("git")
-            .args(["cat-file", "-p", &self.name])
-            .current_dir(repo_dir)
-            .output()
+    #[tokio::test]
+    async fn test_reference_abbreviated_commit() {
+        let fixture = create_fixture_repo();
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "--short", "HEAD"]);
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .reference(Reference::Commit(head))
+            .cln()
             .await
-            .map_err(Error::CommandSpawnError)?;
-        write(&content_path, &output.stdout)
+            .expect_err("Abbreviated commit was checked out");
+        assert!(matches!(err, Error::AbbreviatedCommitError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_reference_pull_request() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        // A pull request is a reference outside of branches and tags, to a commit that isn't on any branch.
+        let fixture = create_fixture_repo();
+        run_git(fixture.path(), &["checkout", "--quiet", "-b", "pr"]);
+        std::fs::write(fixture.path().join("README.md"), "# Pull request\n")
+            .expect("Failed to change README");
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--all", "--message", "Change README"],
+        );
+        let pr_head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        run_git(
+            fixture.path(),
+            &["update-ref", "refs/pull/1/head", &pr_head],
+        );
+        run_git(fixture.path(), &["checkout", "--quiet", "main"]);
+        run_git(fixture.path(), &["branch", "--quiet", "-D", "pr"]);
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .reference(Reference::Ref("refs/pull/1/head".to_string()))
+            .cln()
             .await
-            .map_err(|e| Error::WriteToStoreError(content_path.to_string_lossy().to_string(), e))?;
-        let mut stored_file_permissions =
-            std::fs::Permissions::from_mode(self.mode.parse().map_err(Error::ParseModeError)?);
-        stored_file_permissions.set_readonly(true);
-        File::open(&content_path)
+            .expect("Failed to cln pull request");
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md"))
+                .expect("Failed to read README"),
+            "# Pull request\n"
+        );
+
+        // A remote one can't be cloned with `--branch`, so it's fetched.
+        let clone = create_temp_dir(None).expect("Failed to create clone tempdir");
+        clone_repo(
+            &format!("file://{}", path_str(fixture.path())),
+            clone.path(),
+            Some("refs/pull/1/head"),
+            true,
+            None,
+            None,
+            &[],
+        )
+        .await
+        .expect("Failed to clone pull request");
+        assert_eq!(
+            rev_parse(clone.path(), "refs/pull/1/head")
+                .await
+                .expect("Failed to resolve pull request"),
+            pr_head
+        );
+    }
+
+    #[tokio::test]
+    async fn test_branch_glob() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        for (tag, readme) in [
+            ("v1.2.0", "# v1.2.0\n"),
+            ("v1.10.0", "# v1.10.0\n"),
+            ("v2.0.0", "# v2.0.0\n"),
+        ] {
+            std::fs::write(fixture.path().join("README.md"), readme)
+                .expect("Failed to change README");
+            run_git(
+                fixture.path(),
+                &["commit", "--quiet", "--all", "--message", tag],
+            );
+            run_git(
+                fixture.path(),
+                &["tag", "--annotate", "--message", tag, tag],
+            );
+        }
+
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .branch("v1.*")
+            .branch_glob(true)
+            .cln()
             .await
-            .map_err(Error::ReadTreeError)?
-            .set_permissions(stored_file_permissions)
+            .expect("Failed to cln the latest v1 tag");
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md"))
+                .expect("Failed to read README"),
+            "# v1.10.0\n"
+        );
+
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("none"))
+            .store_path(store.path().to_path_buf())
+            .branch("v3.*")
+            .branch_glob(true)
+            .cln()
             .await
-            .map_err(Error::ReadTreeError)?;
+            .expect_err("Matched a pattern with no references");
+        assert!(matches!(err, Error::NoMatchingPatternError(_)));
 
-        Ok(())
+        // Without globbing, the pattern is a reference name, which doesn't exist.
+        assert!(Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("exact"))
+            .store_path(store.path().to_path_buf())
+            .branch("v1.*")
+            .cln()
+            .await
+            .is_err());
     }
-}
 
-#[derive(Debug)]
-struct Tree {
-    rows: Vec<TreeRow>,
-    path: String,
-}
+    #[tokio::test]
+    async fn test_loose_objects() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-impl Tree {
-    fn new(tree: &str, path: String) -> Self {
-        let rows = tree
-            .lines()
-            .par_bridge()
-            .map(TreeRow::new)
-            .collect::<Vec<TreeRow>>();
-        Self { rows, path }
-    }
-    async fn from_path(store_path: &Path, path: String) -> Result<Self, Error> {
-        let tree = read_to_string(store_path)
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .loose_objects(true)
+            .cln()
             .await
-            .map_err(|e| Error::ReadFileError(store_path.display().to_string(), e))?;
-        let tree = tree.trim_end();
-        Ok(Self::new(tree, path))
-    }
-    async fn from_hash(hash: &str, path: String) -> Result<Self, Error> {
-        let store_path = STORE_PATH.lock().await.clone();
-        let content_path = store_path.join(hash);
+            .expect("Failed to cln with loose objects");
+
+        // A repo without any objects of its own reads them from the cln-store as an alternate.
+        let empty = create_temp_dir(None).expect("Failed to create repo tempdir");
+        run_git(empty.path(), &["init", "--quiet", "--bare"]);
+        std::fs::write(
+            empty.path().join("objects").join("info").join("alternates"),
+            format!("{}\n", store.path().join(LOOSE_OBJECTS_DIR).display()),
+        )
+        .expect("Failed to write alternates");
+        for (object, content) in [
+            ("HEAD:README.md", "# Fixture\n"),
+            ("HEAD:src/nested/file.txt", "nested\n"),
+        ] {
+            let hash = run_git(fixture.path(), &["rev-parse", object]);
+            assert_eq!(
+                run_git(empty.path(), &["cat-file", "blob", &hash]),
+                content.trim_end()
+            );
+        }
+        let root_tree = run_git(fixture.path(), &["rev-parse", "HEAD^{tree}"]);
+        assert_eq!(
+            run_git(empty.path(), &["ls-tree", "-r", "--name-only", &root_tree]),
+            "README.md\nsrc/main.rs\nsrc/nested/file.txt"
+        );
 
-        Self::from_path(&content_path, path).await
+        // Loose objects aren't store objects.
+        assert!(store::stored_files(store.path())
+            .expect("Failed to list store")
+            .iter()
+            .all(|path| !path.starts_with(store.path().join(LOOSE_OBJECTS_DIR))));
     }
-}
 
-type RepoPath = Path;
+    #[tokio::test]
+    async fn test_max_file_size() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-#[async_trait]
-trait Walkable {
-    async fn walk(&self, tree: &Tree, target_path: &Path) -> Result<(), Error>;
-    async fn write_blob(&self, tree: &Tree, row: &TreeRow, target_path: &Path)
-        -> Result<(), Error>;
-    async fn walk_tree(&self, tree: &Tree, row: &TreeRow, target_path: &Path) -> Result<(), Error>;
-}
+        let fixture = create_fixture_repo();
+        std::fs::write(fixture.path().join("large.bin"), vec![0u8; 4096])
+            .expect("Failed to write large file");
+        run_git(fixture.path(), &["add", "large.bin"]);
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--message", "Add large file"],
+        );
+        let large_hash = run_git(fixture.path(), &["rev-parse", "HEAD:large.bin"]);
 
-#[async_trait]
-impl Walkable for RepoPath {
-    async fn walk(&self, tree: &Tree, target_path: &Path) -> Result<(), Error> {
-        let mut blob_tasks = vec![];
-        let mut tree_tasks = vec![];
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("failed"))
+            .store_path(store.path().to_path_buf())
+            .max_file_size(1024)
+            .cln()
+            .await
+            .expect_err("Checked out a file over the maximum size");
+        assert!(
+            matches!(err, Error::FileTooLargeError { ref path, size: 4096, max_file_size: 1024 } if path == "large.bin"),
+            "{err}"
+        );
+        assert!(!store.path().join(&large_hash).exists());
 
-        for row in &tree.rows {
-            match row.otype.as_str() {
-                "blob" => {
-                    blob_tasks.push(async move { self.write_blob(tree, row, target_path).await });
-                }
-                "tree" => {
-                    tree_tasks.push(async move { self.walk_tree(tree, row, target_path).await });
-                }
-                _ => {}
-            }
-        }
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("skipped"))
+            .store_path(store.path().to_path_buf())
+            .max_file_size(1024)
+            .skip_large_files(true)
+            .cln()
+            .await
+            .expect("Failed to cln skipping large files");
+        assert!(!target.path().join("skipped").join("large.bin").exists());
+        assert!(target.path().join("skipped").join("README.md").exists());
+        assert!(!store.path().join(&large_hash).exists());
 
-        for task in blob_tasks {
-            task.await?;
-        }
+        // Files that are filtered out don't count.
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("filtered"))
+            .store_path(store.path().to_path_buf())
+            .max_file_size(1024)
+            .filter(|entry| entry.path() != Path::new("large.bin"))
+            .cln()
+            .await
+            .expect("Failed to cln filtering out large files");
+    }
 
-        for task in tree_tasks {
-            task.await?;
-        }
+    #[tokio::test]
+    async fn test_no_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        Ok(())
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let checkout = target.path().join("checkout");
+        Builder::new(path_str(fixture.path()))
+            .dir(checkout.clone())
+            .store_path(store.path().join("store"))
+            .no_store(true)
+            .cln()
+            .await
+            .expect("Failed to cln without a store");
+
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("src").join("nested").join("file.txt"))
+                .expect("Failed to read nested file"),
+            "nested\n"
+        );
+        assert!(!store.path().join("store").exists());
+        // The scratch store is gone, leaving the checkout as the only link to each file.
+        let entries = std::fs::read_dir(target.path())
+            .expect("Failed to read target")
+            .count();
+        assert_eq!(entries, 1);
+        assert_eq!(
+            checkout
+                .join("README.md")
+                .metadata()
+                .expect("Failed to read metadata")
+                .nlink(),
+            1
+        );
     }
-    async fn write_blob(
-        &self,
-        tree: &Tree,
-        row: &TreeRow,
-        target_path: &Path,
-    ) -> Result<(), Error> {
-        row.write_to_store(self).await?;
-        let cur_path = Self::new(tree.path.as_str());
-        let target_dir = target_path.join(cur_path);
-        if !target_dir.exists() {
-            create_dir_all(&target_dir)
-                .await
-                .map_err(Error::CreateDirAllError)?;
-        }
-        let target_file = target_dir.join(row.path.clone());
-        if target_file.exists() {
-            return Ok(());
-        }
 
-        let store_path = STORE_PATH.lock().await.clone();
-        let content_path = store_path.join(&row.name);
+    #[tokio::test]
+    async fn test_no_store_concurrent() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        hard_link(content_path.clone(), &target_file)
-            .await
-            .map_err(Error::HardLinkError)?;
+        let fixtures = (0..8).map(|_| create_fixture_repo()).collect::<Vec<_>>();
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let builders = || {
+            fixtures.iter().enumerate().map(|(index, fixture)| {
+                Builder::new(path_str(fixture.path()))
+                    .dir(target.path().join(index.to_string()))
+                    .no_store(true)
+                    .overwrite_policy(OverwritePolicy::Sync)
+            })
+        };
+        let results = cln_all(builders()).await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
 
-        debug!(
-            "Linked {} to {}",
-            content_path.display(),
-            target_file.display()
+        // Both scratch stores are gone, leaving only the checkouts.
+        assert_eq!(
+            std::fs::read_dir(target.path())
+                .expect("Failed to read target")
+                .count(),
+            fixtures.len()
+        );
+        let readme = target.path().join("1").join("README.md");
+        assert_eq!(
+            std::fs::read_to_string(&readme).expect("Failed to read README.md"),
+            "# Fixture\n"
         );
 
-        Ok(())
+        // Syncing replaces a file that was changed, as there's no object in a store to compare it to.
+        std::fs::remove_file(&readme).expect("Failed to remove README.md");
+        std::fs::write(&readme, "changed\n").expect("Failed to change README.md");
+        let results = cln_all(builders()).await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+        assert_eq!(
+            std::fs::read_to_string(&readme).expect("Failed to read README.md"),
+            "# Fixture\n"
+        );
     }
-    async fn walk_tree(&self, tree: &Tree, row: &TreeRow, target_path: &Path) -> Result<(), Error> {
-        let cur_path = Self::new(tree.path.as_str());
-        let new_path = cur_path.join(row.path.clone());
-        let next_tree = self
-            .ls_tree(&row.name, new_path.display().to_string())
-            .await?;
-        self.walk(&next_tree, target_path).await?;
 
-        Ok(())
-    }
-}
+    #[tokio::test]
+    async fn test_exclude() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-type Hash = String;
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
 
-#[async_trait]
-impl Walkable for Hash {
-    async fn walk(&self, tree: &Tree, target_path: &Path) -> Result<(), Error> {
-        let mut blob_tasks = vec![];
-        let mut tree_tasks = vec![];
+        // A whole directory.
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("dir"))
+            .store_path(store.path().to_path_buf())
+            .exclude("src/nested/**")
+            .cln()
+            .await
+            .expect("Failed to cln excluding a directory");
+        assert!(target
+            .path()
+            .join("dir")
+            .join("src")
+            .join("main.rs")
+            .exists());
+        assert!(!target
+            .path()
+            .join("dir")
+            .join("src")
+            .join("nested")
+            .exists());
+        let nested_hash = run_git(fixture.path(), &["rev-parse", "HEAD:src/nested/file.txt"]);
+        assert!(!store.path().join(nested_hash).exists());
 
-        for row in &tree.rows {
-            match row.otype.as_str() {
-                "blob" => {
-                    blob_tasks.push(async move { self.write_blob(tree, row, target_path).await });
-                }
-                "tree" => {
-                    tree_tasks.push(async move { self.walk_tree(tree, row, target_path).await });
-                }
-                _ => {}
-            }
-        }
+        // Files by extension, at any depth.
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("extension"))
+            .store_path(store.path().to_path_buf())
+            .exclude("*.rs")
+            .exclude("*.md")
+            .cln()
+            .await
+            .expect("Failed to cln excluding extensions");
+        let checkout = target.path().join("extension");
+        assert!(!checkout.join("README.md").exists());
+        assert!(!checkout.join("src").join("main.rs").exists());
+        assert!(checkout
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
 
-        for task in blob_tasks {
-            task.await?;
-        }
+        let err = Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("invalid"))
+            .store_path(store.path().to_path_buf())
+            .exclude("src/[")
+            .cln()
+            .await
+            .expect_err("Accepted an invalid glob");
+        assert!(matches!(err, Error::InvalidPatternError(_)));
+    }
 
-        for task in tree_tasks {
-            task.await?;
-        }
+    #[tokio::test]
+    async fn test_verify_reachable() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        Ok(())
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let nested_hash = run_git(fixture.path(), &["rev-parse", "HEAD:src/nested/file.txt"]);
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("first"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln");
+        let options = ClnOptions {
+            store: store.path().to_path_buf(),
+            ..ClnOptions::default()
+        };
+        assert!(verify_reachable(&head, &options)
+            .await
+            .expect("Failed to verify store"));
+
+        // As if it was evicted, leaving the root behind.
+        std::fs::remove_file(store.path().join(&nested_hash)).expect("Failed to remove blob");
+        assert!(!verify_reachable(&head, &options)
+            .await
+            .expect("Failed to verify store"));
+        let excluding = ClnOptions {
+            exclude: Exclude::new(&["src/nested/**".to_string()]).expect("Failed to parse exclude"),
+            ..ClnOptions::clone(&options)
+        };
+        assert!(verify_reachable(&head, &excluding)
+            .await
+            .expect("Failed to verify store"));
+
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("second"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln from an incomplete store");
+        assert!(target
+            .path()
+            .join("second")
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+        assert!(store.path().join(&nested_hash).exists());
+        assert!(verify_reachable(&head, &options)
+            .await
+            .expect("Failed to verify store"));
     }
-    async fn write_blob(
-        &self,
-        tree: &Tree,
-        row: &TreeRow,
-        target_path: &Path,
-    ) -> Result<(), Error> {
-        let cur_path = Path::new(tree.path.as_str());
-        let target_dir = target_path.join(cur_path);
-        if !target_dir.exists() {
-            create_dir_all(&target_dir)
-                .await
-                .map_err(Error::CreateDirAllError)?;
-        }
-        let target_file = target_dir.join(row.path.clone());
-        if target_file.exists() {
-            return Ok(());
-        }
 
-        let store_path = STORE_PATH.lock().await.clone();
-        let content_path = store_path.join(&row.name);
+    #[tokio::test]
+    async fn test_lockfile() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let project = create_temp_dir(None).expect("Failed to create project tempdir");
+        let lockfile = project.path().join("cln.lock");
+        let locked_head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
 
-        hard_link(content_path.clone(), &target_file)
+        Builder::new(path_str(fixture.path()))
+            .dir(project.path().join("vendor").join("fixture"))
+            .store_path(store.path().to_path_buf())
+            .write_lock(lockfile.clone())
+            .cln()
             .await
-            .map_err(Error::HardLinkError)?;
+            .expect("Failed to cln with a lockfile");
+        assert_eq!(
+            read_lockfile(&lockfile)
+                .await
+                .expect("Failed to read lockfile"),
+            vec![LockEntry {
+                repo: path_str(fixture.path()).to_string(),
+                dir: PathBuf::from("vendor/fixture"),
+                hash: locked_head.clone(),
+            }]
+        );
 
-        debug!(
-            "Linked {} to {}",
-            content_path.display(),
-            target_file.display()
+        // The checkout is pinned, so later commits aren't checked out.
+        std::fs::write(fixture.path().join("README.md"), "# Changed\n")
+            .expect("Failed to change README");
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--all", "--message", "Change README"],
         );
+        std::fs::remove_dir_all(project.path().join("vendor")).expect("Failed to remove checkout");
 
-        Ok(())
+        cln_locked(&lockfile, Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to cln from a lockfile");
+        assert_eq!(
+            std::fs::read_to_string(
+                project
+                    .path()
+                    .join("vendor")
+                    .join("fixture")
+                    .join("README.md")
+            )
+            .expect("Failed to read README"),
+            "# Fixture\n"
+        );
     }
-    async fn walk_tree(&self, tree: &Tree, row: &TreeRow, target_path: &Path) -> Result<(), Error> {
-        let cur_path = Path::new(tree.path.as_str());
-        let new_path = cur_path.join(row.path.clone());
-        let next_tree = Tree::from_hash(&row.name, new_path.display().to_string()).await?;
-        self.walk(&next_tree, target_path).await?;
 
-        Ok(())
+    #[tokio::test]
+    async fn test_prune_repo() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let old_head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let old_readme = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("old"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln the old commit");
+
+        std::fs::write(fixture.path().join("README.md"), "# Changed\n")
+            .expect("Failed to change README");
+        run_git(
+            fixture.path(),
+            &["commit", "--quiet", "--all", "--message", "Change README"],
+        );
+        let new_head = run_git(fixture.path(), &["rev-parse", "HEAD"]);
+        let main_rs = run_git(fixture.path(), &["rev-parse", "HEAD:src/main.rs"]);
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().join("new"))
+            .store_path(store.path().to_path_buf())
+            .cln()
+            .await
+            .expect("Failed to cln the new commit");
+
+        // Only the objects of the old commit that the new one doesn't share are pruned: its tree listing and README.
+        let (pruned, _) = prune_repo(&old_head, &[&new_head], Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to prune the old commit");
+        assert_eq!(pruned, 2);
+        assert!(!store.path().join(&old_head).exists());
+        assert!(!store.path().join(&old_readme).exists());
+        assert!(store.path().join(&main_rs).exists());
+        assert!(target.path().join("old").join("README.md").exists());
+
+        let (pruned, _) = prune_repo(&new_head, &[], Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to prune the new commit");
+        assert!(pruned > 0);
+        assert!(!store.path().join(&main_rs).exists());
+
+        // The tree of a pruned commit is gone, so it can't be kept or pruned again.
+        assert!(
+            prune_repo(&old_head, &[&new_head], Some(store.path().to_path_buf()))
+                .await
+                .is_err()
+        );
     }
-}
 
-trait Treevarsable {
-    async fn ls_tree(&self, reference: &str, path: String) -> Result<Tree, Error>;
-}
+    #[tokio::test]
+    async fn test_concurrency() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-const HEAD: &str = "HEAD";
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
 
-impl Treevarsable for RepoPath {
-    async fn ls_tree(&self, reference: &str, path: String) -> Result<Tree, Error> {
-        debug!("ls-tree: {}", reference);
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .concurrency(1)
+            .cln()
+            .await
+            .expect("Failed to cln one object at a time");
 
-        let store_path = STORE_PATH.lock().await.clone();
+        assert!(target.path().join("README.md").exists());
+        assert!(target
+            .path()
+            .join("src")
+            .join("nested")
+            .join("file.txt")
+            .exists());
+    }
 
-        let content_path = store_path.join(reference);
+    #[tokio::test]
+    async fn test_cln_all() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        if content_path.exists() {
-            return Ok(Tree::new(
-                &read_to_string(&content_path)
-                    .await
-                    .map_err(Error::ReadTreeError)?,
-                path,
-            ));
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let targets = create_temp_dir(None).expect("Failed to create targets tempdir");
+        let repo = format!("file://{}", path_str(fixture.path()));
+
+        let results = cln_all(["first", "second"].map(|name| {
+            Builder::new(&repo)
+                .dir(targets.path().join(name))
+                .store_path(store.path().to_path_buf())
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+        assert!(targets.path().join("first/README.md").exists());
+        assert!(targets.path().join("second/src/nested/file.txt").exists());
+
+        let results = cln_all([
+            Builder::new(&repo)
+                .dir(targets.path().join("third"))
+                .store_path(store.path().to_path_buf()),
+            Builder::new(&repo)
+                .dir(targets.path().join("fourth"))
+                .branch("missing")
+                .store_path(store.path().to_path_buf()),
+        ])
+        .await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(targets.path().join("third/README.md").exists());
+
+        // Builders with different store paths each write to their own.
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
+        let stores = [store.path().join("fifth"), store.path().join("sixth")];
+        for store_path in &stores {
+            std::fs::create_dir(store_path).expect("Failed to create store");
+        }
+        let results = cln_all(stores.iter().enumerate().map(|(index, store_path)| {
+            Builder::new(&repo)
+                .dir(targets.path().join(format!("separate-{index}")))
+                .store_path(store_path.clone())
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+        for store_path in &stores {
+            assert!(store_path.join(&readme_hash).exists());
         }
+    }
 
-        File::create(&content_path)
-            .await
-            .map_err(|e| Error::WriteToStoreError(content_path.to_string_lossy().to_string(), e))?;
+    #[tokio::test]
+    async fn test_namespace_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        let ls_tree_stdout = Command::new("git")
-            .args(["ls-tree", reference])
-            .current_dir(self)
-            .output()
-            .await
-            .map_err(Error::CommandSpawnError)?
-            .stdout;
-        let ls_tree_string = String::from_utf8_lossy(&ls_tree_stdout);
-        let ls_tree_trimmed = ls_tree_string.trim_end().to_string();
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+        let readme_hash = run_git(fixture.path(), &["rev-parse", "HEAD:README.md"]);
 
-        write(&content_path, &ls_tree_trimmed)
+        Builder::new(&format!("file://{}", path_str(fixture.path())))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .namespace_store(true)
+            .cln()
             .await
-            .map_err(|e| Error::WriteToStoreError(content_path.to_string_lossy().to_string(), e))?;
+            .expect("Failed to cln into a namespaced store");
 
-        debug!("Wrote to store: {}", content_path.display());
+        assert!(!store.path().join(&readme_hash).exists());
+        let namespaces = std::fs::read_dir(store.path().join("repos"))
+            .expect("Failed to read namespaces")
+            .map(|entry| entry.expect("Failed to read namespace").path())
+            .collect::<Vec<_>>();
+        assert_eq!(namespaces.len(), 1);
+        assert!(namespaces[0].join(&readme_hash).exists());
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("README.md"))
+                .expect("Failed to read README.md"),
+            "# Fixture\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cln_all_namespace_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        Ok(Tree::new(&ls_tree_trimmed, path))
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let targets = create_temp_dir(None).expect("Failed to create targets tempdir");
+        let fixtures = (0..4)
+            .map(|index| {
+                let fixture = create_fixture_repo();
+                std::fs::write(fixture.path().join("repo.txt"), format!("{index}\n"))
+                    .expect("Failed to write repo.txt");
+                run_git(fixture.path(), &["add", "."]);
+                run_git(
+                    fixture.path(),
+                    &["commit", "--quiet", "--message", "Add repo.txt"],
+                );
+                fixture
+            })
+            .collect::<Vec<_>>();
+        let blobs = fixtures
+            .iter()
+            .map(|fixture| run_git(fixture.path(), &["rev-parse", "HEAD:repo.txt"]))
+            .collect::<Vec<_>>();
+
+        let results = cln_all(fixtures.iter().enumerate().map(|(index, fixture)| {
+            Builder::new(&format!("file://{}", path_str(fixture.path())))
+                .dir(targets.path().join(index.to_string()))
+                .store_path(store.path().to_path_buf())
+                .namespace_store(true)
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+
+        // Each checkout writes to the namespace of its own repository, even though they ran at the same time.
+        let namespaces = std::fs::read_dir(store.path().join("repos"))
+            .expect("Failed to read namespaces")
+            .map(|entry| entry.expect("Failed to read namespace").path())
+            .collect::<Vec<_>>();
+        assert_eq!(namespaces.len(), fixtures.len());
+        for namespace in &namespaces {
+            assert_eq!(
+                blobs
+                    .iter()
+                    .filter(|blob| namespace.join(blob).exists())
+                    .count(),
+                1,
+                "{}",
+                namespace.display()
+            );
+        }
+        for (index, blob) in blobs.iter().enumerate() {
+            assert!(!store.path().join(blob).exists());
+            assert_eq!(
+                std::fs::read_to_string(targets.path().join(index.to_string()).join("repo.txt"))
+                    .expect("Failed to read repo.txt"),
+                format!("{index}\n")
+            );
+        }
     }
-}
 
-fn get_repo_name(repo: &str) -> PathBuf {
-    let repo_name = repo
-        .split('/')
-        .last()
-        .expect("Could not parse repo name. Check the URL.");
-    PathBuf::from(repo_name.replace(".git", ""))
-}
+    #[tokio::test]
+    async fn test_default_branch() {
+        let fixture = create_fixture_repo();
+        run_git(fixture.path(), &["branch", "--move", "main", "trunk"]);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert_eq!(
+            default_branch(path_str(fixture.path()))
+                .await
+                .expect("Failed to resolve default branch"),
+            "trunk"
+        );
 
-    #[test]
-    fn test_create_temp_dir() {
-        let tempdir = create_temp_dir().expect("Failed to create tempdir");
-        assert!(tempdir.path().exists());
-        tempdir.close().expect("Failed to close tempdir");
+        run_git(fixture.path(), &["checkout", "--quiet", "--detach"]);
+        assert_eq!(
+            default_branch(path_str(fixture.path()))
+                .await
+                .expect("Failed to resolve detached default branch"),
+            HEAD
+        );
     }
 
     #[tokio::test]
-    async fn test_run_ls_remote() {
-        let repo = "https://github.com/lua/lua.git";
-        let reference = "HEAD";
-        let ls_remote = run_ls_remote(repo, reference)
+    async fn test_follow_default_branch() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
+
+        Builder::new(&format!("file://{}", path_str(fixture.path())))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .follow_default_branch(true)
+            .write_metadata(true)
+            .cln()
             .await
-            .expect("Failed to run ls-remote");
-        assert!(!ls_remote.rows.is_empty());
+            .expect("Failed to cln the default branch");
+
+        let metadata = read_metadata(target.path())
+            .await
+            .expect("Failed to read metadata");
+        assert_eq!(metadata.reference, "main");
+        assert_eq!(metadata.default_branch, None);
+        assert!(target.path().join("README.md").exists());
     }
 
     #[tokio::test]
-    async fn test_clone_repo() {
-        let repo = "https://github.com/lua/lua.git";
-        let tmp_dir = create_temp_dir().expect("Failed to create tempdir");
-        let tmp_dir_path = tmp_dir.path();
-        clone_repo(repo, tmp_dir_path, None)
-            .await
-            .expect("Failed to clone repo");
-        assert!(tmp_dir_path.join("HEAD").exists());
+    async fn test_write_metadata_default_branch() {
+        let _lock = STORE_TEST_LOCK.lock().await;
 
-        for entry in tmp_dir_path.read_dir().expect("Failed to read tempdir") {
-            let entry = entry.expect("Failed to read entry");
-            let entry_path = entry.path();
-            let filesize = entry_path.metadata().expect("Failed to get metadata").len();
-            assert!(filesize > 0);
-            assert!(entry_path.exists());
-        }
+        let fixture = create_fixture_repo();
+        let store = create_temp_dir(None).expect("Failed to create store tempdir");
+        let target = create_temp_dir(None).expect("Failed to create target tempdir");
 
-        tmp_dir.close().expect("Failed to close tempdir");
+        Builder::new(path_str(fixture.path()))
+            .dir(target.path().to_path_buf())
+            .store_path(store.path().to_path_buf())
+            .write_metadata(true)
+            .cln()
+            .await
+            .expect("Failed to cln HEAD");
+
+        let metadata = read_metadata(target.path())
+            .await
+            .expect("Failed to read metadata");
+        assert_eq!(metadata.reference, HEAD);
+        assert_eq!(metadata.default_branch.as_deref(), Some("main"));
     }
 }
@@ -0,0 +1,246 @@
+use crate::Error;
+use log::debug;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use tokio::fs::{read_to_string, write};
+use tokio::sync::Mutex;
+
+const HEADER: &str =
+    "# Written by cln. Check every repo out again at its commit with `cln locked <lockfile>`.";
+const ENTRY: &str = "[[repo]]";
+
+// Serializes updates to lockfiles, as concurrent clones, e.g. from `cln_all`, can record themselves in the same one.
+static LOCKFILE_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// A repo pinned to a commit in a lockfile, e.g. `cln.lock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockEntry {
+    /// The repository to clone.
+    pub repo: String,
+    /// The directory to check it out into, relative to the directory of the lockfile unless it's absolute.
+    pub dir: PathBuf,
+    /// The commit to check out.
+    pub hash: String,
+}
+
+// Quotes a value as a TOML basic string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Reads a TOML basic or literal string, returning `None` if it isn't one.
+fn unquote(value: &str) -> Option<String> {
+    if let Some(literal) = value.strip_prefix('\'') {
+        return literal.strip_suffix('\'').map(str::to_string);
+    }
+
+    let mut chars = value.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut unquoted = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('\\' | '"') => unquoted.push(c),
+                't' => unquoted.push('\t'),
+                'n' => unquoted.push('\n'),
+                _ => return None,
+            },
+            '"' => return None,
+            c => unquoted.push(c),
+        }
+    }
+
+    Some(unquoted)
+}
+
+// Writes entries as an array of `[[repo]]` tables, the subset of TOML that `parse_lockfile` reads.
+pub fn format_lockfile(entries: &[LockEntry]) -> String {
+    let mut content = format!("{HEADER}\n");
+    for entry in entries {
+        // Writing to a `String` can't fail.
+        let _ = write!(
+            content,
+            "\n{ENTRY}\nrepo = {}\ndir = {}\nhash = {}\n",
+            quote(&entry.repo),
+            quote(&entry.dir.display().to_string()),
+            quote(&entry.hash)
+        );
+    }
+
+    content
+}
+
+// Parses an array of `[[repo]]` tables with `repo`, `dir` and `hash` strings, ignoring comments and blank lines.
+pub fn parse_lockfile(path: &Path, content: &str) -> Result<Vec<LockEntry>, Error> {
+    let invalid = |line: usize, message: &str| {
+        Error::InvalidLockfileError(
+            path.display().to_string(),
+            format!("line {line}: {message}"),
+        )
+    };
+
+    let mut tables: Vec<(usize, [Option<String>; 3])> = vec![];
+    for (number, line) in content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == ENTRY {
+            tables.push((number, Default::default()));
+            continue;
+        }
+
+        let Some((_, fields)) = tables.last_mut() else {
+            return Err(invalid(number, "expected `[[repo]]`"));
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid(number, "expected `key = \"value\"`"));
+        };
+        let value = unquote(value.trim()).ok_or_else(|| invalid(number, "expected a string"))?;
+        let field = match key.trim() {
+            "repo" => &mut fields[0],
+            "dir" => &mut fields[1],
+            "hash" => &mut fields[2],
+            key => {
+                debug!(
+                    "Ignoring unknown key {} in lockfile {}",
+                    key,
+                    path.display()
+                );
+                continue;
+            }
+        };
+        *field = Some(value);
+    }
+
+    tables
+        .into_iter()
+        .map(|(number, fields)| match fields {
+            [Some(repo), Some(dir), Some(hash)] => Ok(LockEntry {
+                repo,
+                dir: PathBuf::from(dir),
+                hash,
+            }),
+            _ => Err(invalid(number, "expected `repo`, `dir` and `hash`")),
+        })
+        .collect()
+}
+
+/// Reads the repos pinned in a lockfile written with `Builder::write_lock`.
+///
+/// # Errors
+/// Will return an error if the lockfile cannot be read, or isn't a valid lockfile.
+pub async fn read_lockfile(lockfile: &Path) -> Result<Vec<LockEntry>, Error> {
+    let content = read_to_string(lockfile)
+        .await
+        .map_err(|e| Error::ReadLockfileError(lockfile.display().to_string(), e))?;
+
+    parse_lockfile(lockfile, &content)
+}
+
+// Records a checkout in a lockfile, replacing any entry for the same directory, and creating the lockfile if it's missing.
+// The directory is written relative to the lockfile when it's below it, so that the lockfile can be committed with the project.
+pub async fn update_lockfile(
+    lockfile: &Path,
+    repo: &str,
+    target_dir: &Path,
+    hash: &str,
+) -> Result<(), Error> {
+    let _lock = LOCKFILE_LOCK.lock().await;
+    let mut entries = match read_to_string(lockfile).await {
+        Ok(content) => parse_lockfile(lockfile, &content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(Error::ReadLockfileError(lockfile.display().to_string(), e)),
+    };
+
+    let base = lockfile_base(lockfile);
+    let dir = target_dir
+        .strip_prefix(&base)
+        .map_or_else(|_| target_dir.to_path_buf(), Path::to_path_buf);
+    let entry = LockEntry {
+        repo: repo.to_string(),
+        dir,
+        hash: hash.to_string(),
+    };
+    match entries
+        .iter_mut()
+        .find(|existing| base.join(&existing.dir) == target_dir)
+    {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+
+    debug!("Locking {} at {} in {}", repo, hash, lockfile.display());
+    write(lockfile, format_lockfile(&entries))
+        .await
+        .map_err(|e| Error::WriteLockfileError(lockfile.display().to_string(), e))
+}
+
+// The directory that relative directories in a lockfile are relative to, with its symlinks resolved like target directories.
+pub fn lockfile_base(lockfile: &Path) -> PathBuf {
+    let parent = lockfile
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    parent
+        .canonicalize()
+        .unwrap_or_else(|_| parent.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockfile_format() {
+        let entries = vec![
+            LockEntry {
+                repo: "https://github.com/yhakbar/cln.git".to_string(),
+                dir: PathBuf::from("vendor/cln"),
+                hash: "0123456789012345678901234567890123456789".to_string(),
+            },
+            LockEntry {
+                repo: "/path/with \"quotes\" and \\".to_string(),
+                dir: PathBuf::from("/abs/dir"),
+                hash: "abcdefabcdefabcdefabcdefabcdefabcdefabcd".to_string(),
+            },
+        ];
+        let path = Path::new("cln.lock");
+
+        let content = format_lockfile(&entries);
+        assert_eq!(
+            parse_lockfile(path, &content).expect("Failed to parse lockfile"),
+            entries
+        );
+
+        let handwritten = "# Pinned repos\n[[repo]]\nrepo = 'https://example.com/a.git'\n\
+            hash = \"0123456789012345678901234567890123456789\" \ndir = \"a\"\nbranch = \"main\"\n";
+        assert_eq!(
+            parse_lockfile(path, handwritten).expect("Failed to parse lockfile"),
+            vec![LockEntry {
+                repo: "https://example.com/a.git".to_string(),
+                dir: PathBuf::from("a"),
+                hash: "0123456789012345678901234567890123456789".to_string(),
+            }]
+        );
+
+        for invalid in [
+            "repo = \"a\"\n",
+            "[[repo]]\nrepo = \"a\"\ndir = \"a\"\n",
+            "[[repo]]\nrepo = a\n",
+            "[[repo]]\nrepo\n",
+        ] {
+            assert!(
+                matches!(
+                    parse_lockfile(path, invalid),
+                    Err(Error::InvalidLockfileError(..))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+}
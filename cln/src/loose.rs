@@ -0,0 +1,178 @@
+use crate::git::git;
+use crate::store::parse_tree_listing;
+use crate::{flatten_stored_tree, Error, ParsePool};
+use log::debug;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::fs::{create_dir_all, read_to_string};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// The directory of the cln-store that `Builder::loose_objects` writes git loose objects to.
+pub const LOOSE_OBJECTS_DIR: &str = "objects";
+
+// Where git keeps a loose object in an object directory, e.g. `objects/ab/cdef...`.
+fn loose_object_path(objects_dir: &Path, hash: &str) -> PathBuf {
+    let (fanout, rest) = hash.split_at(2.min(hash.len()));
+    objects_dir.join(fanout).join(rest)
+}
+
+// Runs git against `objects_dir`, with a throwaway repository, as git won't write objects outside of one.
+async fn git_in_objects_dir(git_dir: &Path, objects_dir: &Path) -> Command {
+    let mut cmd = git().await;
+    cmd.env("GIT_DIR", git_dir)
+        .env("GIT_OBJECT_DIRECTORY", objects_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    cmd
+}
+
+// Feeds `input` to the git command, returning the hashes it prints, one per line.
+async fn run_with_input(mut cmd: Command, input: String) -> Result<Vec<String>, Error> {
+    let mut child = cmd.spawn().map_err(Error::CommandSpawnError)?;
+    let mut stdin = child.stdin.take().ok_or_else(|| {
+        Error::LooseObjectError("Failed to write to the stdin of git".to_string())
+    })?;
+    // Written while the output is read, so that a large input can't fill the pipes of both.
+    let writer = tokio::spawn(async move { stdin.write_all(input.as_bytes()).await });
+    let out = child
+        .wait_with_output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    writer
+        .await
+        .map_err(|e| Error::LooseObjectError(e.to_string()))?
+        .map_err(Error::CommandSpawnError)?;
+    if !out.status.success() {
+        return Err(Error::LooseObjectError(
+            String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+// Writes the blobs and trees reachable from a hash in the cln-store at `store_path` as git loose objects under
+// `objects/` in it, returning how many were written. Objects that are already there are skipped.
+pub async fn write_loose_objects(
+    store_path: &Path,
+    root_hash: &str,
+    parse_pool: &ParsePool,
+) -> Result<u64, Error> {
+    let objects_dir = store_path.join(LOOSE_OBJECTS_DIR);
+    let rows = flatten_stored_tree(store_path, root_hash, parse_pool).await?;
+    let missing = |hash: &String| !loose_object_path(&objects_dir, hash).exists();
+    let blobs = rows
+        .iter()
+        .filter(|(_, row)| row.otype == "blob")
+        .map(|(_, row)| row.name.clone())
+        .filter(missing)
+        .collect::<BTreeSet<_>>();
+    // The root is listed under the hash it was checked out by, e.g. a commit, so the hash of its tree is only known once it's written.
+    let trees = rows
+        .iter()
+        .filter(|(_, row)| row.otype == "tree")
+        .map(|(_, row)| row.name.clone())
+        .filter(missing)
+        .collect::<BTreeSet<_>>();
+
+    // git only treats the throwaway repository as one if its object directory exists.
+    create_dir_all(&objects_dir)
+        .await
+        .map_err(Error::CreateDirError)?;
+    let git_dir = tempfile::Builder::new()
+        .prefix("cln")
+        .tempdir()
+        .map_err(Error::TempDirError)?;
+    let init = git()
+        .await
+        .args(["init", "--quiet", "--bare"])
+        .arg(git_dir.path())
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+    if !init.status.success() {
+        return Err(Error::LooseObjectError(
+            String::from_utf8_lossy(&init.stderr).trim_end().to_string(),
+        ));
+    }
+
+    // Blobs are stored as their content, so hashing the stored file writes the same object git has.
+    let mut paths = String::new();
+    for hash in &blobs {
+        // Writing to a `String` can't fail.
+        let _ = writeln!(paths, "{}", store_path.join(hash).display());
+    }
+    let mut cmd = git_in_objects_dir(git_dir.path(), &objects_dir).await;
+    cmd.args(["hash-object", "-w", "--no-filters", "--stdin-paths"]);
+    let hashes = run_with_input(cmd, paths).await?;
+    if let Some((hash, _)) = blobs
+        .iter()
+        .zip(&hashes)
+        .find(|(hash, written)| hash != written)
+    {
+        return Err(Error::StoreCorruption { hash: hash.clone() });
+    }
+
+    // `git mktree --batch` reads one listing after another, separated by blank lines.
+    let mut listings = String::new();
+    let mut listed = vec![];
+    for hash in trees.iter().map(String::as_str).chain([root_hash]) {
+        let content_path = store_path.join(hash);
+        let content = read_to_string(&content_path)
+            .await
+            .map_err(|e| Error::ReadFileError(content_path.display().to_string(), e))?;
+        let Some(listing) = parse_tree_listing(&content).filter(|listing| !listing.is_empty())
+        else {
+            // The empty tree is built into git, and older listings are left for `cln store migrate`.
+            continue;
+        };
+        listings.push_str(listing.trim_end());
+        listings.push_str("\n\n");
+        listed.push(hash);
+    }
+    let mut cmd = git_in_objects_dir(git_dir.path(), &objects_dir).await;
+    cmd.args(["mktree", "--missing", "--batch"]);
+    let tree_hashes = run_with_input(cmd, listings).await?;
+    if let Some((hash, _)) = listed
+        .iter()
+        .zip(&tree_hashes)
+        .find(|(hash, written)| **hash != root_hash && *hash != written)
+    {
+        return Err(Error::StoreCorruption {
+            hash: (*hash).to_string(),
+        });
+    }
+
+    let written = (hashes.len() + tree_hashes.len()) as u64;
+    debug!(
+        "Wrote {} loose objects for {} to {}",
+        written,
+        root_hash,
+        objects_dir.display()
+    );
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loose_object_path() {
+        assert_eq!(
+            loose_object_path(
+                Path::new("objects"),
+                "45b983be36b73c0788dc9cbcb76cbb80fc7bb057"
+            ),
+            Path::new("objects/45/b983be36b73c0788dc9cbcb76cbb80fc7bb057")
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use crate::entry::{Filter, TreeEntry};
+use crate::{flatten_stored_tree, Error, ParsePool};
+use log::debug;
+use std::path::{Path, PathBuf};
+use tokio::fs::write;
+
+// Quotes a CSV field if it contains a delimiter, quote or newline.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes a CSV manifest of every file in the tree of `hash` to `manifest`,
+/// with the path of the file relative to the root of the tree, its blob hash and its mode.
+///
+/// Files that don't match `filter`, or for which `checkout_path` returns `None`, weren't checked out, so they're left out.
+/// The paths of the rest are mapped with `checkout_path`, as they were checked out.
+///
+/// The tree must already be in the cln-store at `store_path`.
+pub async fn write_manifest(
+    store_path: &Path,
+    manifest: &Path,
+    hash: &str,
+    parse_pool: &ParsePool,
+    filter: Option<&Filter>,
+    checkout_path: impl Fn(&Path) -> Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut rows = vec![];
+    for (path, row) in flatten_stored_tree(store_path, hash, parse_pool).await? {
+        if row.otype != "blob" {
+            continue;
+        }
+        if let Some(filter) = filter {
+            if !filter.matches(&TreeEntry::from_stored_row(store_path, path.clone(), &row).await?) {
+                continue;
+            }
+        }
+        if let Some(path) = checkout_path(&path) {
+            rows.push((path, row));
+        }
+    }
+    rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut contents = String::from("path,hash,mode\n");
+    for (path, row) in rows {
+        contents.push_str(&csv_field(&path.display().to_string()));
+        contents.push(',');
+        contents.push_str(&row.name);
+        contents.push(',');
+        contents.push_str(&row.mode);
+        contents.push('\n');
+    }
+
+    debug!("Writing manifest of {} to {}", hash, manifest.display());
+    write(manifest, contents)
+        .await
+        .map_err(|e| Error::WriteManifestError(manifest.display().to_string(), e))
+}
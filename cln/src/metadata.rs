@@ -0,0 +1,130 @@
+use crate::Error;
+use log::debug;
+use std::path::{Path, PathBuf};
+use tokio::fs::{create_dir_all, read_to_string, write};
+
+// Where the metadata is written, relative to the target directory.
+const METADATA_PATH: &str = ".cln/HEAD";
+
+/// What was checked out into a directory, as recorded with `Builder::write_metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The repository that was cloned.
+    pub repo: String,
+    /// The branch, tag or `HEAD` that was checked out.
+    pub reference: String,
+    /// The commit the reference resolved to.
+    pub hash: String,
+    /// The branch the `HEAD` of the repository pointed at, when `HEAD` was checked out rather than a branch or tag.
+    pub default_branch: Option<String>,
+    /// The mirror the checkout was cloned from, when the repository itself couldn't be.
+    pub mirror: Option<String>,
+}
+
+fn metadata_path(dir: &Path) -> PathBuf {
+    dir.join(METADATA_PATH)
+}
+
+/// Writes the metadata of a checkout into `.cln/HEAD` in the target directory.
+pub async fn write_metadata(target_dir: &Path, metadata: &Metadata) -> Result<(), Error> {
+    let path = metadata_path(target_dir);
+    debug!("Writing metadata to {}", path.display());
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .await
+            .map_err(Error::CreateDirAllError)?;
+    }
+    let default_branch = metadata
+        .default_branch
+        .as_ref()
+        .map_or_else(String::new, |branch| format!("default_branch={branch}\n"));
+    let mirror = metadata
+        .mirror
+        .as_ref()
+        .map_or_else(String::new, |mirror| format!("mirror={mirror}\n"));
+    write(
+        &path,
+        format!(
+            "repo={}\nref={}\nhash={}\n{default_branch}{mirror}",
+            metadata.repo, metadata.reference, metadata.hash
+        ),
+    )
+    .await
+    .map_err(|e| Error::WriteMetadataError(path.display().to_string(), e))
+}
+
+/// Reads back the metadata written into a directory by a `cln` with `Builder::write_metadata`.
+///
+/// # Errors
+/// Will return an error if the directory has no metadata, or it cannot be read or parsed.
+pub async fn read_metadata(dir: &Path) -> Result<Metadata, Error> {
+    let path = metadata_path(dir);
+    let contents = read_to_string(&path)
+        .await
+        .map_err(|e| Error::ReadFileError(path.display().to_string(), e))?;
+
+    let optional_field = |key: &str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .map(str::to_string)
+    };
+    let field = |key: &str| {
+        optional_field(key).ok_or_else(|| Error::InvalidMetadataError(path.display().to_string()))
+    };
+
+    Ok(Metadata {
+        repo: field("repo")?,
+        reference: field("ref")?,
+        hash: field("hash")?,
+        default_branch: optional_field("default_branch"),
+        mirror: optional_field("mirror"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    #[tokio::test]
+    async fn test_metadata_round_trip() {
+        let dir = Builder::new()
+            .prefix("cln")
+            .tempdir()
+            .expect("Failed to create tempdir");
+        let metadata = Metadata {
+            repo: "https://github.com/yhakbar/cln.git".to_string(),
+            reference: "HEAD".to_string(),
+            hash: "1111111111111111111111111111111111111111".to_string(),
+            default_branch: Some("main".to_string()),
+            mirror: Some("https://mirror.example.com/yhakbar/cln.git".to_string()),
+        };
+
+        write_metadata(dir.path(), &metadata)
+            .await
+            .expect("Failed to write metadata");
+
+        assert_eq!(
+            read_metadata(dir.path())
+                .await
+                .expect("Failed to read metadata"),
+            metadata
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_missing_metadata() {
+        let dir = Builder::new()
+            .prefix("cln")
+            .tempdir()
+            .expect("Failed to create tempdir");
+
+        let err = read_metadata(dir.path())
+            .await
+            .expect_err("Expected reading missing metadata to fail");
+
+        assert!(matches!(err, Error::ReadFileError(_, _)));
+    }
+}
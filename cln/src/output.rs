@@ -0,0 +1,80 @@
+use crate::git::git;
+use crate::Error;
+use log::debug;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What cln writes into the target directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only the working tree, linked to the cln-store.
+    #[default]
+    Tree,
+    /// The working tree, plus a minimal, shallow `.git` directory with `HEAD` at the resolved commit.
+    Git,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(Self::Tree),
+            "git" => Ok(Self::Git),
+            _ => Err(Error::InvalidOutputFormat(s.to_string())),
+        }
+    }
+}
+
+async fn run_git(dir: &Path, args: &[&str]) -> Result<(), Error> {
+    let out = git()
+        .await
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Err(Error::GitDirError(
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Writes a minimal `.git` directory into the target directory, so that it can be used as a shallow git repository.
+///
+/// The commit and its trees are fetched without blobs, as the blobs are already linked into the working tree.
+/// Git will fetch any blobs it needs later on demand from `origin`.
+pub async fn write_git_dir(
+    target_dir: &Path,
+    repo: &str,
+    remote_ref: &str,
+    hash: &str,
+) -> Result<(), Error> {
+    debug!("Writing .git to {} at {}", target_dir.display(), hash);
+
+    run_git(target_dir, &["init", "--quiet"]).await?;
+    run_git(target_dir, &["remote", "add", "--", "origin", repo]).await?;
+    run_git(
+        target_dir,
+        &[
+            "fetch",
+            "--quiet",
+            "--depth",
+            "1",
+            "--filter=blob:none",
+            "--",
+            "origin",
+            remote_ref,
+        ],
+    )
+    .await?;
+    run_git(target_dir, &["update-ref", "--no-deref", "HEAD", hash]).await?;
+    run_git(target_dir, &["read-tree", "HEAD"]).await?;
+    run_git(target_dir, &["update-index", "-q", "--refresh"]).await?;
+
+    Ok(())
+}
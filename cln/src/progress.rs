@@ -0,0 +1,112 @@
+use log::debug;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// How far along a phase of `git clone` is, e.g. 42% of `Receiving objects`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloneProgress {
+    /// The phase as git names it, e.g. `Counting objects`, `Receiving objects` or `Resolving deltas`.
+    pub phase: String,
+    /// How much of the phase is done, from 0 to 100.
+    pub percent: u8,
+}
+
+impl CloneProgress {
+    // Parses a progress line of `git clone --progress`, e.g. `Receiving objects:  42% (42/100), 1.00 MiB | 2.00 MiB/s`.
+    // Phases the server reports are prefixed with `remote: `.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_prefix("remote: ").unwrap_or(line);
+        let (phase, rest) = line.split_once(": ")?;
+        let (percent, _) = rest.trim_start().split_once('%')?;
+
+        Some(Self {
+            phase: phase.trim().to_string(),
+            percent: percent.parse().ok().filter(|percent| *percent <= 100)?,
+        })
+    }
+}
+
+// Reads the stderr of `git clone --progress`, sending every progress update to `progress` as it's read.
+// git redraws progress lines with carriage returns, so lines are split on those as well as newlines.
+// Returns the rest of stderr, e.g. for the error message if the clone fails.
+pub async fn read_progress<R>(
+    mut stderr: R,
+    progress: &UnboundedSender<CloneProgress>,
+) -> io::Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut messages = String::new();
+    let mut pending = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        let read = stderr.read(&mut buf).await?;
+        pending.extend_from_slice(&buf[..read]);
+
+        let end = if read == 0 {
+            pending.len()
+        } else {
+            match pending.iter().rposition(|b| matches!(b, b'\r' | b'\n')) {
+                Some(position) => position + 1,
+                None => continue,
+            }
+        };
+        for line in String::from_utf8_lossy(&pending[..end])
+            .split(['\r', '\n'])
+            .filter(|line| !line.trim().is_empty())
+        {
+            match CloneProgress::parse(line).map(|update| progress.send(update)) {
+                Some(Ok(())) => {}
+                // The receiver may have stopped listening, which doesn't stop the clone.
+                Some(Err(_)) => debug!("Clone progress receiver was dropped"),
+                None => {
+                    messages.push_str(line);
+                    messages.push('\n');
+                }
+            }
+        }
+        pending.drain(..end);
+
+        if read == 0 {
+            return Ok(messages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn test_read_progress() {
+        let stderr = "Cloning into bare repository 'repo'...\nremote: Counting objects: 100% (9/9), done.\n\
+            Receiving objects:  42% (4/9)\rReceiving objects: 100% (9/9), 6.65 KiB | 6.65 MiB/s, done.\n\
+            Resolving deltas: 100% (1/1), done.\nwarning: remote HEAD refers to nonexistent ref\n";
+        let (sender, mut receiver) = unbounded_channel();
+
+        let messages = read_progress(stderr.as_bytes(), &sender)
+            .await
+            .expect("Failed to read progress");
+        drop(sender);
+
+        let mut updates = vec![];
+        while let Some(update) = receiver.recv().await {
+            updates.push((update.phase, update.percent));
+        }
+        assert_eq!(
+            updates,
+            vec![
+                ("Counting objects".to_string(), 100),
+                ("Receiving objects".to_string(), 42),
+                ("Receiving objects".to_string(), 100),
+                ("Resolving deltas".to_string(), 100),
+            ]
+        );
+        assert_eq!(
+            messages,
+            "Cloning into bare repository 'repo'...\nwarning: remote HEAD refers to nonexistent ref\n"
+        );
+    }
+}
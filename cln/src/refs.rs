@@ -0,0 +1,171 @@
+use crate::git::ensure_git;
+use crate::{is_full_commit_hash, list_remote_refs, short_ref_name, Error};
+use globset::Glob;
+use semver::Version;
+use std::collections::BTreeMap;
+
+/// What to check out of a repository, as passed to `Builder::reference`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// A branch, e.g. `main`.
+    Branch(String),
+    /// A tag, e.g. `v1.0.0`. Annotated tags check out the commit they point at.
+    Tag(String),
+    /// A full commit hash, e.g. from `resolve_refs` or a lockfile. Abbreviated hashes are rejected.
+    ///
+    /// The hash is already resolved, so the references of the repository aren't listed with `git ls-remote`.
+    /// It's checked out straight from the cln-store when it's there, and otherwise fetched on its own,
+    /// falling back to a clone of the repository if the server doesn't allow fetching commits by hash.
+    Commit(String),
+    /// A full reference, matched exactly, e.g. `refs/pull/123/head` for a GitHub pull request
+    /// or `refs/merge-requests/123/head` for a GitLab merge request.
+    ///
+    /// The reference is fetched on its own, as `git clone` can only check out branches and tags.
+    Ref(String),
+}
+
+impl Reference {
+    /// The name of the branch or tag, or the commit hash.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Branch(name) | Self::Tag(name) | Self::Commit(name) | Self::Ref(name) => name,
+        }
+    }
+
+    // The reference as it's resolved and fetched.
+    // Tags are qualified, so that a branch of the same name isn't checked out in their place,
+    // and commits have to be full hashes, which are checked out without listing the references of the repository.
+    pub(crate) fn remote_ref(&self) -> Result<String, Error> {
+        match self {
+            Self::Branch(name) | Self::Ref(name) => Ok(name.clone()),
+            Self::Tag(name) => Ok(format!("refs/tags/{name}")),
+            Self::Commit(hash) if is_full_commit_hash(hash) => Ok(hash.clone()),
+            Self::Commit(hash) => Err(Error::AbbreviatedCommitError(hash.clone())),
+        }
+    }
+}
+
+/// Lists the branches and tags of a repository whose names match the glob `pattern`, e.g. `v*` or `release/*`,
+/// with the hash of the commit each of them points at.
+///
+/// Names are matched, and returned, without their `refs/heads/` or `refs/tags/` prefix, so they can be passed to `Builder::branch`.
+/// They're sorted by name, and annotated tags resolve to the commit they point at rather than the tag object.
+///
+/// ```rust,no_run
+/// use cln::{latest_version, resolve_refs, Builder};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), cln::Error> {
+///     let repo = "https://github.com/yhakbar/cln.git";
+///     let refs = resolve_refs(repo, "v*").await?;
+///     if let Some((tag, _)) = latest_version(&refs) {
+///         Builder::new(repo).branch(tag).cln().await?;
+///     }
+///     Ok(())
+/// }
+/// ```
+///
+/// # Errors
+/// Will return an error if:
+/// - The git binary cannot be run.
+/// - The pattern isn't a valid glob.
+/// - The references of the repository cannot be listed.
+pub async fn resolve_refs(repo: &str, pattern: &str) -> Result<Vec<(String, String)>, Error> {
+    ensure_git(None).await?;
+    matching_refs(repo, pattern, &[]).await
+}
+
+// Lists the branches and tags matching the pattern, as `resolve_refs` does, with the git config of the clone.
+pub async fn matching_refs(
+    repo: &str,
+    pattern: &str,
+    git_config: &[(String, String)],
+) -> Result<Vec<(String, String)>, Error> {
+    let matcher = Glob::new(pattern)
+        .map_err(Error::InvalidPatternError)?
+        .compile_matcher();
+
+    let mut refs = BTreeMap::new();
+    for row in list_remote_refs(repo, git_config).await? {
+        let (name, peeled) = row
+            .name
+            .strip_suffix("^{}")
+            .map_or((row.name.as_str(), false), |name| (name, true));
+        let name = short_ref_name(name);
+        if !matcher.is_match(name) {
+            continue;
+        }
+
+        // The peeled commit of an annotated tag replaces the tag object, whichever is listed first.
+        if peeled {
+            refs.insert(name.to_string(), row.hash);
+        } else {
+            refs.entry(name.to_string()).or_insert(row.hash);
+        }
+    }
+
+    Ok(refs.into_iter().collect())
+}
+
+/// Picks the reference with the highest semantic version from those returned by `resolve_refs`,
+/// e.g. `v1.10.0` over `v1.2.0`.
+///
+/// A leading `v` is ignored, and names that aren't semantic versions are skipped.
+#[must_use]
+pub fn latest_version(refs: &[(String, String)]) -> Option<&(String, String)> {
+    refs.iter()
+        .filter_map(|reference| {
+            let (name, _) = reference;
+            let version = Version::parse(name.strip_prefix('v').unwrap_or(name)).ok()?;
+            Some((version, reference))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, reference)| reference)
+}
+
+// Picks the branch or tag a glob checks out: the one with the highest semantic version,
+// or the last by name if none of them are versions.
+pub fn newest_ref(refs: &[(String, String)]) -> Option<&(String, String)> {
+    latest_version(refs).or_else(|| refs.last())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named_refs(names: &[&str]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|name| ((*name).to_string(), String::new()))
+            .collect()
+    }
+
+    #[test]
+    fn test_latest_version() {
+        let refs = named_refs(&["v1.2.0", "v1.10.0", "v2.0.0-rc.1", "nightly", "1.9.9"]);
+        assert_eq!(
+            latest_version(&refs).map(|(name, _)| name.as_str()),
+            Some("v2.0.0-rc.1")
+        );
+
+        let refs = named_refs(&["nightly", "main"]);
+        assert_eq!(latest_version(&refs), None);
+    }
+
+    #[test]
+    fn test_newest_ref() {
+        let refs = named_refs(&["v1.2.0", "v1.10.0", "v1.9.0"]);
+        assert_eq!(
+            newest_ref(&refs).map(|(name, _)| name.as_str()),
+            Some("v1.10.0")
+        );
+
+        let refs = named_refs(&["release/a", "release/b"]);
+        assert_eq!(
+            newest_ref(&refs).map(|(name, _)| name.as_str()),
+            Some("release/b")
+        );
+        assert_eq!(newest_ref(&[]), None);
+    }
+}
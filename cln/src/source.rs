@@ -0,0 +1,236 @@
+use crate::git::git_with_config;
+use crate::{
+    clone_repo, create_temp_dir, parse_blob_size, resolve_remote_ref, CloneProgress, Error,
+    ParsePool,
+};
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tempfile::TempDir;
+use tokio::io::{copy, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::OnceCell;
+use tokio::task::spawn_blocking;
+
+/// Where cln reads references and objects from when they aren't in the cln-store.
+///
+/// cln uses `GitCliSource` by default, which shells out to `git`.
+/// Other implementations can be passed to `Builder::source` to check out from somewhere else,
+/// e.g. a tarball, an HTTP API or an in-memory repository in tests.
+#[async_trait]
+pub trait ObjectSource: Debug + Send + Sync {
+    /// Resolves a branch, tag or `HEAD` to the hash of the commit it points at.
+    async fn resolve_ref(&self, reference: &str) -> Result<String, Error>;
+    /// Lists the entries of a tree, or of the root tree of a commit, in the format of `git ls-tree`.
+    async fn ls_tree(&self, hash: &str) -> Result<String, Error>;
+    /// Reads the contents of a blob.
+    async fn cat_blob(&self, hash: &str) -> Result<Vec<u8>, Error>;
+    /// Reads the size of a blob in bytes, e.g. to check it against `Builder::max_file_size` before it's read.
+    ///
+    /// By default this reads the whole blob with `cat_blob`.
+    /// Sources that can look the size up on its own should override it.
+    async fn blob_size(&self, hash: &str) -> Result<u64, Error> {
+        Ok(self.cat_blob(hash).await?.len() as u64)
+    }
+    /// Writes the contents of a blob to `writer`, e.g. a file in the cln-store.
+    ///
+    /// By default this reads the whole blob into memory with `cat_blob`.
+    /// Sources that can stream blobs should override it, so that blobs larger than memory can be checked out.
+    async fn copy_blob(
+        &self,
+        hash: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<(), Error> {
+        let content = self.cat_blob(hash).await?;
+        writer
+            .write_all(&content)
+            .await
+            .map_err(|e| Error::WriteToStoreError(hash.to_string(), e))
+    }
+}
+
+/// Reads objects with the `git` CLI.
+///
+/// References are resolved with `git ls-remote`,
+/// and objects are read from a shallow bare clone of the repository, created the first time one is needed.
+#[derive(Debug)]
+pub struct GitCliSource {
+    repo: String,
+    branch: Option<String>,
+    temp_dir: Option<PathBuf>,
+    git_config: Vec<(String, String)>,
+    filter: Option<String>,
+    progress: Option<UnboundedSender<CloneProgress>>,
+    clone: OnceCell<TempDir>,
+}
+
+impl GitCliSource {
+    #[must_use]
+    pub fn new(repo: &str, branch: Option<&str>) -> Self {
+        Self {
+            repo: repo.to_string(),
+            branch: branch.map(str::to_string),
+            temp_dir: None,
+            git_config: vec![],
+            filter: None,
+            progress: None,
+            clone: OnceCell::new(),
+        }
+    }
+
+    /// Create the bare clone in this directory, instead of the system temp directory.
+    #[must_use]
+    pub fn temp_dir(mut self, temp_dir: PathBuf) -> Self {
+        self.temp_dir = Some(temp_dir);
+        self
+    }
+
+    /// Pass each of `git_config` as `-c key=value` to every git command.
+    #[must_use]
+    pub fn git_config(mut self, git_config: Vec<(String, String)>) -> Self {
+        self.git_config = git_config;
+        self
+    }
+
+    /// Make the bare clone a partial clone with this filter, e.g. `blob:none`, fetching blobs as they're read.
+    #[must_use]
+    pub fn filter(mut self, filter: &str) -> Self {
+        self.filter = Some(filter.to_string());
+        self
+    }
+
+    /// Send the progress of creating the bare clone to `progress`.
+    #[must_use]
+    pub fn progress(mut self, progress: UnboundedSender<CloneProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    async fn clone_path(&self) -> Result<&Path, Error> {
+        let tmp_dir = self
+            .clone
+            .get_or_try_init(|| async {
+                let tmp_dir = create_temp_dir(self.temp_dir.as_deref())?;
+                debug!(
+                    "Cloning {} into {} to fetch objects missing from the store",
+                    self.repo,
+                    tmp_dir.path().display()
+                );
+                clone_repo(
+                    &self.repo,
+                    tmp_dir.path(),
+                    self.branch.as_deref(),
+                    true,
+                    self.filter.as_deref(),
+                    self.progress.as_ref(),
+                    &self.git_config,
+                )
+                .await?;
+                Ok::<_, Error>(tmp_dir)
+            })
+            .await?;
+
+        Ok(tmp_dir.path())
+    }
+
+    async fn run_in_clone(&self, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let out = git_with_config(&self.git_config)
+            .await
+            .args(args)
+            .current_dir(self.clone_path().await?)
+            .output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+
+        if !out.status.success() {
+            return Err(Error::ReadObjectError(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            ));
+        }
+
+        Ok(out.stdout)
+    }
+}
+
+// The bare clone can be large, so it's removed on the blocking thread pool rather than on the runtime, when there is one.
+// Nothing can await the removal from `drop`, so a failure to remove it is logged.
+impl Drop for GitCliSource {
+    fn drop(&mut self) {
+        let Some(tmp_dir) = self.clone.take() else {
+            return;
+        };
+        let close = move || {
+            let path = tmp_dir.path().to_path_buf();
+            if let Err(e) = tmp_dir.close() {
+                warn!("Failed to remove temporary clone {}: {}", path.display(), e);
+            }
+        };
+
+        if Handle::try_current().is_ok() {
+            spawn_blocking(close);
+        } else {
+            close();
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectSource for GitCliSource {
+    async fn resolve_ref(&self, reference: &str) -> Result<String, Error> {
+        resolve_remote_ref(
+            &self.repo,
+            reference,
+            &self.git_config,
+            &ParsePool::default(),
+        )
+        .await
+    }
+    async fn ls_tree(&self, hash: &str) -> Result<String, Error> {
+        let stdout = self.run_in_clone(&["ls-tree", hash]).await?;
+        Ok(String::from_utf8(stdout)?.trim_end().to_string())
+    }
+    async fn cat_blob(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        self.run_in_clone(&["cat-file", "blob", hash]).await
+    }
+    async fn blob_size(&self, hash: &str) -> Result<u64, Error> {
+        let stdout = self.run_in_clone(&["cat-file", "-s", hash]).await?;
+        parse_blob_size(&stdout)
+    }
+    // Streamed from `git cat-file`, as the blob may be larger than memory.
+    async fn copy_blob(
+        &self,
+        hash: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<(), Error> {
+        let mut child = git_with_config(&self.git_config)
+            .await
+            .args(["cat-file", "blob", hash])
+            .current_dir(self.clone_path().await?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::CommandSpawnError)?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::ReadObjectError(hash.to_string()))?;
+        copy(&mut stdout, writer)
+            .await
+            .map_err(|e| Error::WriteToStoreError(hash.to_string(), e))?;
+
+        let out = child
+            .wait_with_output()
+            .await
+            .map_err(Error::CommandSpawnError)?;
+        if !out.status.success() {
+            return Err(Error::ReadObjectError(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
@@ -1,41 +1,784 @@
+use crate::git::git;
+use crate::loose::LOOSE_OBJECTS_DIR;
 use crate::Error;
 use home::home_dir;
-use once_cell::sync::Lazy;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::fs::create_dir_all;
+use log::{debug, warn};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{FileTimes, Permissions};
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+use tempfile::Builder as TempBuilder;
+use tokio::fs::{
+    create_dir_all, hard_link, metadata, read, read_to_string, remove_file, set_permissions, write,
+    File,
+};
+use tokio::io::{copy, AsyncWriteExt};
 use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
 
-pub static STORE_PATH: Lazy<Arc<Mutex<PathBuf>>> =
-    Lazy::new(|| Arc::new(Mutex::new(PathBuf::from(".cln-store"))));
+pub static STORE_PATH: LazyLock<Arc<Mutex<PathBuf>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(PathBuf::from(".cln-store"))));
 
-pub async fn ensure_cln_store_path(store_path: Option<PathBuf>) -> Result<(), Error> {
-    if let Some(store_path) = store_path {
-        *STORE_PATH.lock().await = store_path;
+/// Serializes tests that point `STORE_PATH` at their own temporary store.
+#[cfg(test)]
+pub static STORE_TEST_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+pub const STORE_PATH_ENV: &str = "CLN_STORE_PATH";
+
+/// Environment variable that caps the size of the cln-store in bytes, when no maximum is set with `Builder::max_store_bytes`.
+pub const MAX_STORE_BYTES_ENV: &str = "CLN_MAX_STORE_BYTES";
+
+// Name of the directory that's used as the store of a project, when it's in or above the current directory.
+const PROJECT_STORE_DIR: &str = ".cln-store";
+
+/// Resolves the directory cln uses as its store when no store path is given, without creating it.
+///
+/// This is the first `.cln-store` directory in or above the current directory, so that a project can keep its own store,
+/// otherwise the `CLN_STORE_PATH` environment variable if it's set, otherwise `~/.cache/.cln-store`.
+///
+/// # Errors
+/// Will return an error if:
+/// - The current directory cannot be read.
+/// - There is no project store, `CLN_STORE_PATH` isn't set and the home directory cannot be found.
+pub fn default_store_path() -> Result<PathBuf, Error> {
+    let current_dir = std::env::current_dir().map_err(Error::CurrentDirError)?;
+    if let Some(store_path) = find_project_store(&current_dir) {
+        return Ok(store_path);
+    }
+
+    if let Some(store_path) = std::env::var_os(STORE_PATH_ENV) {
+        return resolve_store_path(PathBuf::from(store_path));
+    }
+
+    home_dir()
+        .map(|homedir| homedir.join(".cache").join(".cln-store"))
+        .ok_or(Error::HomeDirError)
+}
+
+// Walks up from `dir` to the first directory that has a project store in it.
+fn find_project_store(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|ancestor| ancestor.join(PROJECT_STORE_DIR))
+        .find(|store_path| store_path.is_dir())
+}
+
+/// Returns the directory currently configured as the cln-store.
+///
+/// This reflects the store path resolved by the last clone, or `.cln-store` if nothing has been cloned yet.
+/// Checkouts don't read it, so clones running at the same time with different store paths each use their own.
+pub async fn cln_store_path() -> PathBuf {
+    STORE_PATH.lock().await.clone()
+}
+
+// Expands a leading `~` to the home directory and makes the path absolute,
+// so that the store doesn't depend on the working directory at the time files are linked.
+fn resolve_store_path(store_path: PathBuf) -> Result<PathBuf, Error> {
+    let store_path = match store_path.strip_prefix("~") {
+        Ok(rest) => home_dir().ok_or(Error::HomeDirError)?.join(rest),
+        Err(_) => store_path,
+    };
+
+    let store_path = if store_path.is_absolute() {
+        store_path
+    } else {
+        std::env::current_dir()
+            .map_err(Error::CurrentDirError)?
+            .join(store_path)
+    };
+
+    Ok(store_path.canonicalize().unwrap_or(store_path))
+}
+
+/// Resolves the directory cln would use as its store for a given store path argument, without cloning anything.
+///
+/// A given store path has `~` expanded and is made absolute,
+/// otherwise this is the same as `default_store_path`. The directory may not exist yet.
+///
+/// # Errors
+/// Will return an error if the home directory or current directory needed to resolve the path cannot be found.
+#[allow(clippy::unused_async)]
+pub async fn store_path(store_path_arg: Option<PathBuf>) -> Result<PathBuf, Error> {
+    store_path_arg.map_or_else(default_store_path, resolve_store_path)
+}
+
+// Resolves, and checks the version of, the cln-store for a store path argument, returning it.
+// It's also recorded as the one returned by `cln_store_path`.
+pub async fn ensure_cln_store_path(store_path_arg: Option<PathBuf>) -> Result<PathBuf, Error> {
+    let is_default = store_path_arg.is_none();
+    let cln_store = store_path(store_path_arg).await?;
+    if is_default && !cln_store.exists() {
+        create_dir_all(&cln_store)
+            .await
+            .map_err(Error::CreateDirError)?;
+    }
+    if cln_store.is_dir() {
+        check_store_version(&cln_store).await?;
+    }
+
+    STORE_PATH.lock().await.clone_from(&cln_store);
+
+    Ok(cln_store)
+}
+
+/// The version of the layout of the cln-store, recorded in its `STORE_VERSION` file.
+///
+/// It changes whenever objects are stored in a way an older cln can't read, or that a newer cln has to migrate,
+/// so that a store is never used by a cln that would misread it.
+pub const STORE_VERSION: u32 = 1;
+
+// The file at the root of the cln-store that its layout version is written to, which isn't an object.
+pub const STORE_VERSION_FILE: &str = "STORE_VERSION";
+
+// Reads the layout version of a cln-store, `None` if it has no `STORE_VERSION` file.
+async fn read_store_version(store_path: &Path) -> Result<Option<u32>, Error> {
+    let version_path = store_path.join(STORE_VERSION_FILE);
+    match read_to_string(&version_path).await {
+        Ok(version) => version.trim().parse().map(Some).map_err(|_| {
+            Error::InvalidStoreVersionError(
+                version_path.display().to_string(),
+                version.trim().to_string(),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::ReadStoreError(e)),
+    }
+}
+
+// Writes the current layout version to a cln-store, replacing the file so that it's never read half written.
+async fn write_store_version(store_path: &Path) -> io::Result<()> {
+    let temp_file = TempBuilder::new()
+        .prefix(".cln-tmp")
+        .tempfile_in(store_path)?;
+    write(temp_file.path(), format!("{STORE_VERSION}\n")).await?;
+    temp_file.persist(store_path.join(STORE_VERSION_FILE))?;
+
+    Ok(())
+}
+
+// Whether a cln-store has any objects, at the root or in a namespace.
+async fn has_objects(store_path: &Path) -> Result<bool, Error> {
+    let store_path = store_path.to_path_buf();
+    spawn_blocking(move || stored_files(&store_path))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|files| files)
+        .map(|files| !files.is_empty())
+        .map_err(Error::ReadStoreError)
+}
+
+// Migrates a cln-store with an older layout, and refuses one with a newer layout.
+// A store without a version is either new, and gets the current version, or was written before versions were recorded.
+// Recording the version of a new store is best effort, as the store may be read-only to this user,
+// but a store that needs migrating is refused if it can't be, rather than misread.
+async fn check_store_version(store_path: &Path) -> Result<(), Error> {
+    let version = match read_store_version(store_path).await? {
+        Some(version) => version,
+        None if has_objects(store_path).await? => 0,
+        None => {
+            if let Err(e) = write_store_version(store_path).await {
+                debug!(
+                    "Failed to record the version of cln-store {}: {}",
+                    store_path.display(),
+                    e
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    let path = store_path.display().to_string();
+    match version.cmp(&STORE_VERSION) {
+        Ordering::Equal => Ok(()),
+        Ordering::Less => {
+            warn!("Migrating cln-store {path} from layout version {version} to {STORE_VERSION}");
+            match migrate(store_path, version).await {
+                Err(Error::MigrateStoreError(e)) => {
+                    debug!("Failed to migrate cln-store {path}: {e}");
+                    Err(Error::OutdatedStoreError { path, version })
+                }
+                result => result.map(|_| ()),
+            }
+        }
+        Ordering::Greater => Err(Error::UnsupportedStoreError { path, version }),
+    }
+}
 
-        return Ok(());
+/// Migrates a cln-store written by an older cln to the current layout, returning how many objects were rewritten.
+///
+/// A cln-store with an older layout is migrated the first time it's used, so this is for migrating it ahead of time,
+/// e.g. by someone who can write to a store that's read-only to the users of it, which is refused until it's migrated.
+/// Migrating a store that's already current does nothing.
+/// Checkouts keep working while their objects are rewritten, as objects are replaced rather than changed in place.
+///
+/// Layouts:
+/// - 0, before versions were recorded: tree listings may be missing their format header.
+/// - 1: every tree listing has a format header. Listings without one are removed, to be listed again when they're needed.
+///
+/// # Errors
+/// Will return an error if:
+/// - The cln-store was written by a newer cln.
+/// - The cln-store cannot be read, or an object or its version cannot be written.
+pub async fn migrate_store(store_path_arg: Option<PathBuf>) -> Result<u64, Error> {
+    let store_path = store_path(store_path_arg).await?;
+    let version = read_store_version(&store_path).await?.unwrap_or(0);
+    if version > STORE_VERSION {
+        return Err(Error::UnsupportedStoreError {
+            path: store_path.display().to_string(),
+            version,
+        });
     }
 
-    if let Some(homedir) = home_dir() {
-        let cln_store = homedir.join(".cache").join(".cln-store");
-        if !cln_store.exists() {
-            create_dir_all(&cln_store)
+    migrate(&store_path, version).await
+}
+
+// Migrates a cln-store from the layout `version` to the current one.
+async fn migrate(store_path: &Path, version: u32) -> Result<u64, Error> {
+    let mut migrated = 0;
+    if version < 1 {
+        let objects = {
+            let store_path = store_path.to_path_buf();
+            spawn_blocking(move || stored_files(&store_path))
                 .await
-                .map_err(Error::CreateDirError)?;
+                .map_err(io::Error::other)
+                .and_then(|files| files)
+                .map_err(Error::ReadStoreError)?
+        };
+        for object in objects {
+            if remove_old_tree_listing(&object).await? {
+                migrated += 1;
+            }
         }
+    }
 
-        *STORE_PATH.lock().await = cln_store;
+    write_store_version(store_path)
+        .await
+        .map_err(Error::MigrateStoreError)?;
+    debug!(
+        "Migrated cln-store {} from version {} to {}",
+        store_path.display(),
+        version,
+        STORE_VERSION
+    );
 
-        Ok(())
-    } else {
-        Err(Error::HomeDirError)
+    Ok(migrated)
+}
+
+// Removes a tree listing written before listings had a format header, returning whether the object was removed.
+// Tree listings are only a cache of `git ls-tree`, so the tree is listed again the next time it's needed.
+// Blobs can look like tree listings, e.g. the empty blob, so only objects that don't hash to their name are removed.
+// The access time of an object that's kept is put back, so that migrating doesn't change the eviction order.
+async fn remove_old_tree_listing(object: &Path) -> Result<bool, Error> {
+    let accessed = metadata(object)
+        .await
+        .and_then(|metadata| metadata.accessed())
+        .map_err(Error::ReadStoreError)?;
+    let content = read(object).await.map_err(Error::ReadStoreError)?;
+    let hash = object.file_name().map(|name| name.to_string_lossy());
+    if !is_tree_listing(&content)
+        || parse_tree_listing(&String::from_utf8_lossy(&content)).is_some()
+        || hash_blob(object).await?.as_deref() == hash.as_deref()
+    {
+        let object = object.to_path_buf();
+        let restored = spawn_blocking(move || {
+            std::fs::File::open(&object)?.set_times(FileTimes::new().set_accessed(accessed))
+        })
+        .await;
+        if let Ok(Err(e)) = restored {
+            debug!("Failed to restore the access time of an object: {e}");
+        }
+        return Ok(false);
     }
+
+    debug!("Removing older tree listing {}", object.display());
+    remove_file(object)
+        .await
+        .map_err(Error::MigrateStoreError)?;
+
+    Ok(true)
 }
 
-pub async fn is_content_stored(hash: &str) -> Result<bool, Error> {
-    let store_path = STORE_PATH.lock().await.clone();
+// Directory in the cln-store that the namespaces of individual repos are created in.
+pub const NAMESPACES_DIR: &str = "repos";
+
+// Prefix of the files and directories in the cln-store that are still being written, rather than objects.
+const IN_PROGRESS_PREFIX: &str = ".cln-";
+
+// Lists the files in a cln-store, including those in namespaces,
+// leaving out any that are still being written, the store's version, and git loose objects.
+pub fn stored_files(store_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![store_path.to_path_buf()];
+    let version_path = store_path.join(STORE_VERSION_FILE);
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(IN_PROGRESS_PREFIX)
+                || entry.path() == version_path
+                || entry.file_name() == LOOSE_OBJECTS_DIR
+            {
+                continue;
+            }
+
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+// FNV-1a, rather than `DefaultHasher`, as the hash names a directory that has to be the same across builds of cln.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Subdirectory of the cln-store that objects of `repo` are kept in when the store is namespaced.
+fn namespace_path(store_path: &Path, repo: &str) -> PathBuf {
+    store_path
+        .join(NAMESPACES_DIR)
+        .join(format!("{:016x}", fnv1a(repo.as_bytes())))
+}
+
+/// Creates the namespace of `repo` in the cln-store at `shared_store`, if needed, returning it.
+pub async fn ensure_namespaced_store_path(
+    shared_store: &Path,
+    repo: &str,
+) -> Result<PathBuf, Error> {
+    let namespace = namespace_path(shared_store, repo);
+    debug!(
+        "Using namespace {} of the cln-store for {}",
+        namespace.display(),
+        repo
+    );
+    create_dir_all(&namespace)
+        .await
+        .map_err(Error::CreateDirError)?;
+
+    Ok(namespace)
+}
+
+/// Writes `content` to the cln-store at `store_path` under `hash`, with `permissions` if given.
+pub async fn write_object(
+    store_path: &Path,
+    hash: &str,
+    content: &[u8],
+    permissions: Option<Permissions>,
+) -> Result<(), Error> {
+    write_object_with(
+        store_path,
+        hash,
+        permissions,
+        |mut file, content_path| async move {
+            let write_error = |e| Error::WriteToStoreError(content_path.clone(), e);
+            file.write_all(content).await.map_err(write_error)?;
+            file.flush().await.map_err(write_error)
+        },
+    )
+    .await
+}
+
+/// Writes an object to the cln-store at `store_path` under `hash`, with `permissions` if given, by passing `write_content` the file to write it to.
+///
+/// `write_content` writes to a temporary file in the store, which is only renamed into place once it succeeds,
+/// so that concurrent clones never see a partially written object.
+/// It's also passed the path the object will have, for reporting errors.
+/// It must flush the file before returning, as a tokio `File` may still be writing in the background.
+pub async fn write_object_with<F, Fut>(
+    store_path: &Path,
+    hash: &str,
+    permissions: Option<Permissions>,
+    write_content: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(File, String) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    write_object_if(
+        store_path,
+        hash,
+        permissions,
+        |file, content_path| async move { write_content(file, content_path).await.map(|()| true) },
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Like `write_object_with`, but `write_content` returns whether it wrote the object, which is only stored if it did.
+/// Returns whether the object was stored.
+pub async fn write_object_if<F, Fut>(
+    store_path: &Path,
+    hash: &str,
+    permissions: Option<Permissions>,
+    write_content: F,
+) -> Result<bool, Error>
+where
+    F: FnOnce(File, String) -> Fut,
+    Fut: Future<Output = Result<bool, Error>>,
+{
     let content_path = store_path.join(hash);
-    Ok(content_path.exists())
+    let write_error = |e| Error::WriteToStoreError(content_path.display().to_string(), e);
+
+    let temp_file = TempBuilder::new()
+        .prefix(".cln-tmp")
+        .tempfile_in(store_path)
+        .map_err(write_error)?;
+    let file = File::from_std(temp_file.reopen().map_err(write_error)?);
+    if !write_content(file, content_path.display().to_string()).await? {
+        return Ok(false);
+    }
+    if let Some(permissions) = permissions {
+        set_permissions(temp_file.path(), permissions)
+            .await
+            .map_err(write_error)?;
+    }
+    temp_file
+        .persist(&content_path)
+        .map_err(|e| write_error(e.error))?;
+
+    Ok(true)
+}
+
+pub fn is_content_stored(store_path: &Path, hash: &str) -> bool {
+    store_path.join(hash).exists()
+}
+
+/// Checks whether `hash` is in the cln-store at `store_path`, seeding it from the first of `reference_stores` that has it if it isn't.
+///
+/// Objects are hard linked from the reference store, or copied if it's on a different filesystem.
+/// Copies are written like any other object, so they only appear in the cln-store once they're complete.
+pub async fn is_content_stored_or_seeded(
+    store_path: &Path,
+    hash: &str,
+    reference_stores: &[PathBuf],
+) -> Result<bool, Error> {
+    if is_content_stored(store_path, hash) {
+        return Ok(true);
+    }
+
+    let content_path = store_path.join(hash);
+    for reference_store in reference_stores {
+        let reference_path = reference_store.join(hash);
+        if !reference_path.exists() {
+            continue;
+        }
+
+        debug!(
+            "Seeding {} from reference store {}",
+            hash,
+            reference_store.display()
+        );
+        if hard_link(&reference_path, &content_path).await.is_err() {
+            let read_error = |e| Error::ReadFileError(reference_path.display().to_string(), e);
+            let permissions = metadata(&reference_path)
+                .await
+                .map_err(read_error)?
+                .permissions();
+            let mut reference = File::open(&reference_path).await.map_err(read_error)?;
+            write_object_with(
+                store_path,
+                hash,
+                Some(permissions),
+                |mut file, content_path| async move {
+                    let write_error = |e| Error::WriteToStoreError(content_path.clone(), e);
+                    copy(&mut reference, &mut file).await.map_err(write_error)?;
+                    file.flush().await.map_err(write_error)
+                },
+            )
+            .await?;
+        }
+
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+// Marks an object as used now, so that it's the last to be evicted when the cln-store is over its maximum size.
+// The access time is set explicitly, as filesystems mounted with `relatime` or `noatime` don't update it on every read.
+// The modification time is left alone, as it's shared with the checkouts the object is hard linked into.
+// This is best effort, as an object can't be touched in a store that's read-only or owned by someone else.
+pub async fn touch_object(store_path: &Path, hash: &str) {
+    let content_path = store_path.join(hash);
+    let touched = spawn_blocking(move || {
+        std::fs::File::open(&content_path)?
+            .set_times(FileTimes::new().set_accessed(SystemTime::now()))
+    })
+    .await;
+    if let Ok(Err(e)) = touched {
+        debug!("Failed to mark {hash} as used: {e}");
+    }
+}
+
+/// The maximum size of the cln-store in bytes, from `max_store_bytes` if it's set, otherwise `CLN_MAX_STORE_BYTES`.
+///
+/// # Errors
+/// Will return an error if `CLN_MAX_STORE_BYTES` isn't a number of bytes.
+pub fn max_store_bytes(max_store_bytes: Option<u64>) -> Result<Option<u64>, Error> {
+    if max_store_bytes.is_some() {
+        return Ok(max_store_bytes);
+    }
+    let Some(env) = std::env::var_os(MAX_STORE_BYTES_ENV) else {
+        return Ok(None);
+    };
+
+    let env = env.to_string_lossy();
+    env.trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::InvalidMaxStoreBytesError(env.to_string()))
+}
+
+// Picks the least recently used objects to evict, until the rest add up to at most `max_bytes`.
+fn select_evictions(
+    mut objects: Vec<(PathBuf, u64, SystemTime)>,
+    max_bytes: u64,
+) -> Vec<(PathBuf, u64)> {
+    let mut total = objects.iter().map(|(_, size, _)| size).sum::<u64>();
+    objects.sort_by(|(a_path, _, a_used), (b_path, _, b_used)| {
+        a_used.cmp(b_used).then_with(|| a_path.cmp(b_path))
+    });
+
+    objects
+        .into_iter()
+        .take_while(|(_, size, _)| {
+            let over = total > max_bytes;
+            if over {
+                total -= size;
+            }
+            over
+        })
+        .map(|(path, size, _)| (path, size))
+        .collect()
+}
+
+/// Removes the least recently used objects from the cln-store, including those in namespaces,
+/// until the objects left add up to at most `max_bytes`.
+///
+/// An object is used when it's linked into a checkout, or read from the cln-store for one.
+/// Checkouts that an evicted object is linked into keep their files, as the hard link keeps the content on disk,
+/// so evicting an object only frees space once nothing links it any more.
+///
+/// Returns how many objects were evicted, and how many bytes they added up to.
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be read, or an object cannot be removed.
+pub async fn evict_store(store_path: Option<PathBuf>, max_bytes: u64) -> Result<(u64, u64), Error> {
+    let store_path = ensure_cln_store_path(store_path).await?;
+
+    evict(&store_path, max_bytes).await
+}
+
+// Evicts objects from the cln-store at `store_path`, which may not be the one currently in use, e.g. when it's namespaced.
+pub async fn evict(store_path: &Path, max_bytes: u64) -> Result<(u64, u64), Error> {
+    let store_path = store_path.to_path_buf();
+    let objects = spawn_blocking(move || {
+        stored_files(&store_path)?
+            .into_iter()
+            .map(|path| {
+                let metadata = path.metadata()?;
+                Ok((path, metadata.len(), metadata.accessed()?))
+            })
+            .collect::<io::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|objects| objects)
+    .map_err(Error::ReadStoreError)?;
+
+    let mut evicted = 0;
+    let mut evicted_bytes = 0;
+    for (path, size) in select_evictions(objects, max_bytes) {
+        debug!("Evicting {} from the store", path.display());
+        remove_file(&path).await.map_err(Error::EvictError)?;
+        evicted += 1;
+        evicted_bytes += size;
+    }
+
+    Ok((evicted, evicted_bytes))
+}
+
+/// Checks that the content stored under `hash` in the cln-store at `store_path` still hashes to it as a git blob.
+///
+/// # Errors
+/// Will return `Error::StoreCorruption` if the stored content doesn't match its hash.
+pub async fn verify_stored_blob(store_path: &Path, hash: &str) -> Result<(), Error> {
+    verify_object(&store_path.join(hash), hash).await
+}
+
+// Checks that the object at `content_path` still hashes to `hash` as a git blob.
+async fn verify_object(content_path: &Path, hash: &str) -> Result<(), Error> {
+    if hash_blob(content_path).await?.as_deref() != Some(hash) {
+        return Err(Error::StoreCorruption {
+            hash: hash.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Hashes a file as a git blob, returning `None` if git fails to hash it.
+pub async fn hash_blob(path: &Path) -> Result<Option<String>, Error> {
+    let out = git()
+        .await
+        .args(["hash-object", "--no-filters", "--"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(Error::CommandSpawnError)?;
+
+    if !out.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(out.stdout)?.trim_end().to_string()))
+}
+
+/// How much disk the cln-store saves by hard linking objects into checkouts, rather than copying them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Number of objects in the cln-store.
+    pub objects: u64,
+    /// Bytes used by the objects in the cln-store, which is all the disk they use, however many checkouts link them.
+    pub stored_bytes: u64,
+    /// Bytes the checkouts linking objects in the cln-store would use if every file were a full copy.
+    pub checkout_bytes: u64,
+    /// Number of objects and their stored bytes, keyed by how many checkouts link each object.
+    pub by_checkouts: BTreeMap<u64, (u64, u64)>,
+}
+
+impl DedupStats {
+    /// Bytes saved by sharing objects between checkouts, compared to every checkout being a full copy.
+    #[must_use]
+    pub const fn saved_bytes(&self) -> u64 {
+        self.checkout_bytes.saturating_sub(self.stored_bytes)
+    }
+}
+
+/// Walks the cln-store, including its namespaces, reporting how much disk is saved by hard linking its objects into checkouts.
+///
+/// Every link to an object other than the cln-store's own is counted as a checkout of it.
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be found or read.
+pub async fn dedup_stats(store_path: Option<PathBuf>) -> Result<DedupStats, Error> {
+    let store_path = ensure_cln_store_path(store_path).await?;
+    let objects = spawn_blocking(move || {
+        stored_files(&store_path)?
+            .into_iter()
+            .map(|path| path.metadata())
+            .collect::<io::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|objects| objects)
+    .map_err(Error::ReadStoreError)?;
+
+    let mut stats = DedupStats::default();
+    for metadata in objects {
+        let checkouts = metadata.nlink().saturating_sub(1);
+        stats.objects += 1;
+        stats.stored_bytes += metadata.len();
+        stats.checkout_bytes += metadata.len() * checkouts;
+        let by_checkouts = stats.by_checkouts.entry(checkouts).or_default();
+        by_checkouts.0 += 1;
+        by_checkouts.1 += metadata.len();
+    }
+
+    Ok(stats)
+}
+
+// The first line of every tree listing in the cln-store, naming the format the rest of it is in.
+// It changes whenever the format does, so that listings written by an older cln are listed again rather than misparsed.
+const TREE_FORMAT: &str = "cln-tree 1";
+
+// The content a tree listing is stored with, `listing` being the output of `git ls-tree`.
+pub fn format_tree_listing(listing: &str) -> String {
+    format!("{TREE_FORMAT}\n{listing}")
+}
+
+// The `git ls-tree` listing in stored content, or `None` if it isn't in the current format, e.g. written by an older cln.
+pub fn parse_tree_listing(content: &str) -> Option<&str> {
+    content.strip_prefix(TREE_FORMAT)?.strip_prefix('\n')
+}
+
+// Whether the content is a tree listing written by `ls_tree`, rather than a blob.
+// Listings in an older format are still tree listings, they're just listed again when they're read.
+pub fn is_tree_listing(content: &[u8]) -> bool {
+    let content = String::from_utf8_lossy(content);
+    parse_tree_listing(&content)
+        .unwrap_or(&content)
+        .lines()
+        .all(|line| {
+            let Some((meta, _name)) = line.split_once('\t') else {
+                return false;
+            };
+            let mut meta = meta.split(' ');
+            matches!(
+                (meta.next(), meta.next(), meta.next(), meta.next()),
+                (Some(mode), Some("blob" | "tree" | "commit"), Some(hash), None)
+                    if mode.bytes().all(|b| b.is_ascii_digit())
+                        && hash.bytes().all(|b| b.is_ascii_hexdigit())
+            )
+        })
+}
+
+/// Re-hashes up to `sample` objects in the cln-store, including those in its namespaces,
+/// returning how many objects were checked and the hashes of those that don't match their content.
+///
+/// Tree listings in the cln-store aren't blobs, so they're checked for being well formed instead.
+///
+/// # Errors
+/// Will return an error if the cln-store cannot be found or read.
+pub async fn verify_store(
+    store_path: Option<PathBuf>,
+    sample: usize,
+) -> Result<(u64, Vec<String>), Error> {
+    let store_path = ensure_cln_store_path(store_path).await?;
+    let objects = spawn_blocking(move || stored_files(&store_path))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|files| files)
+        .map_err(Error::ReadStoreError)?;
+
+    let mut checked = 0;
+    let mut corrupt = vec![];
+    for path in objects.into_iter().take(sample) {
+        let Some(hash) = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            continue;
+        };
+
+        checked += 1;
+        match verify_object(&path, &hash).await {
+            Ok(()) => {}
+            Err(Error::StoreCorruption { hash }) => {
+                let content = read(&path).await.map_err(Error::ReadStoreError)?;
+                if !is_tree_listing(&content) {
+                    corrupt.push(hash);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((checked, corrupt))
 }
 
 #[cfg(test)]
@@ -45,6 +788,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_cln_store_path() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
         let tempdir = Builder::new()
             .prefix("cln")
             .tempdir()
@@ -54,8 +799,347 @@ mod tests {
             .await
             .expect("Failed to ensure cln-store path");
 
-        let store_path = STORE_PATH.lock().await.clone();
+        let store_path = cln_store_path().await;
 
+        assert_eq!(
+            store_path,
+            tempdir
+                .path()
+                .canonicalize()
+                .expect("Failed to canonicalize tempdir")
+        );
         assert!(store_path.exists());
     }
+
+    #[tokio::test]
+    async fn test_relative_cln_store_path() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        ensure_cln_store_path(Some(PathBuf::from("relative-store")))
+            .await
+            .expect("Failed to ensure cln-store path");
+
+        let current_dir = std::env::current_dir().expect("Failed to get current dir");
+        assert_eq!(cln_store_path().await, current_dir.join("relative-store"));
+    }
+
+    #[tokio::test]
+    async fn test_tilde_cln_store_path() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        ensure_cln_store_path(Some(PathBuf::from("~/tilde-store")))
+            .await
+            .expect("Failed to ensure cln-store path");
+
+        let homedir = home_dir().expect("Failed to find home dir");
+        assert_eq!(cln_store_path().await, homedir.join("tilde-store"));
+    }
+
+    #[tokio::test]
+    async fn test_store_path_doesnt_change_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let before = cln_store_path().await;
+        let resolved = store_path(Some(PathBuf::from("~/other-store")))
+            .await
+            .expect("Failed to resolve store path");
+
+        let homedir = home_dir().expect("Failed to find home dir");
+        assert_eq!(resolved, homedir.join("other-store"));
+        assert!(!resolved.exists());
+        assert_eq!(cln_store_path().await, before);
+    }
+
+    #[test]
+    fn test_find_project_store() {
+        let project = Builder::new()
+            .prefix("cln-project")
+            .tempdir()
+            .expect("Failed to create project tempdir");
+        let nested = project.path().join("packages").join("app");
+        std::fs::create_dir_all(&nested).expect("Failed to create nested dir");
+
+        assert_eq!(find_project_store(&nested), None);
+
+        let project_store = project.path().join(PROJECT_STORE_DIR);
+        std::fs::create_dir(&project_store).expect("Failed to create project store");
+        assert_eq!(find_project_store(&nested), Some(project_store.clone()));
+        assert_eq!(find_project_store(project.path()), Some(project_store));
+    }
+
+    #[test]
+    fn test_select_evictions() {
+        let at = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let objects = vec![
+            (PathBuf::from("recent"), 40, at(30)),
+            (PathBuf::from("oldest"), 30, at(10)),
+            (PathBuf::from("older"), 20, at(20)),
+            (PathBuf::from("newest"), 10, at(40)),
+        ];
+
+        // Already under the maximum.
+        assert!(select_evictions(objects.clone(), 100).is_empty());
+        // 100 bytes, less the 30 of the oldest, is 70.
+        assert_eq!(
+            select_evictions(objects.clone(), 70),
+            vec![(PathBuf::from("oldest"), 30)]
+        );
+        // Evicting the oldest isn't enough to get to 69.
+        assert_eq!(
+            select_evictions(objects.clone(), 69),
+            vec![(PathBuf::from("oldest"), 30), (PathBuf::from("older"), 20)]
+        );
+        assert_eq!(select_evictions(objects, 0).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_evict_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        let namespace = store.path().join(NAMESPACES_DIR).join("0123456789abcdef");
+        std::fs::create_dir_all(&namespace).expect("Failed to create namespace");
+        for (path, secs) in [
+            (store.path().join("old"), 10),
+            (namespace.join("older"), 5),
+            (store.path().join("new"), 20),
+        ] {
+            std::fs::write(&path, "12345").expect("Failed to write object");
+            std::fs::File::open(&path)
+                .and_then(|file| {
+                    file.set_times(FileTimes::new().set_accessed(
+                        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs),
+                    ))
+                })
+                .expect("Failed to set access time");
+        }
+        std::fs::write(store.path().join(".cln-tmp1234"), "partial").expect("Failed to write");
+
+        let (evicted, evicted_bytes) = evict_store(Some(store.path().to_path_buf()), 5)
+            .await
+            .expect("Failed to evict store");
+
+        assert_eq!((evicted, evicted_bytes), (2, 10));
+        assert!(!namespace.join("older").exists());
+        assert!(!store.path().join("old").exists());
+        assert!(store.path().join("new").exists());
+        assert!(store.path().join(".cln-tmp1234").exists());
+    }
+
+    #[test]
+    fn test_tree_listing_format() {
+        let listing = format!("100644 blob {}\thello.txt", "a".repeat(40));
+
+        let content = format_tree_listing(&listing);
+        assert_eq!(parse_tree_listing(&content), Some(listing.as_str()));
+        assert!(is_tree_listing(content.as_bytes()));
+        assert_eq!(parse_tree_listing(&format_tree_listing("")), Some(""));
+
+        // Listings without the header were written by an older cln.
+        assert_eq!(parse_tree_listing(&listing), None);
+        assert!(is_tree_listing(listing.as_bytes()));
+        assert_eq!(parse_tree_listing("cln-tree 0\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_stats() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        let checkouts = Builder::new()
+            .prefix("cln")
+            .tempdir()
+            .expect("Failed to create checkouts tempdir");
+
+        std::fs::write(store.path().join("shared"), "12345").expect("Failed to write object");
+        std::fs::write(store.path().join("unshared"), "123").expect("Failed to write object");
+        for checkout in ["a", "b"] {
+            std::fs::hard_link(store.path().join("shared"), checkouts.path().join(checkout))
+                .expect("Failed to link object");
+        }
+
+        let stats = dedup_stats(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to get dedup stats");
+
+        assert_eq!(stats.objects, 2);
+        assert_eq!(stats.stored_bytes, 8);
+        assert_eq!(stats.checkout_bytes, 10);
+        assert_eq!(stats.saved_bytes(), 2);
+        assert_eq!(stats.by_checkouts.get(&2), Some(&(1, 5)));
+        assert_eq!(stats.by_checkouts.get(&0), Some(&(1, 3)));
+
+        // Objects in namespaces are counted, while files still being written aren't.
+        let namespace = store.path().join(NAMESPACES_DIR).join("0123456789abcdef");
+        std::fs::create_dir_all(&namespace).expect("Failed to create namespace");
+        std::fs::write(namespace.join("namespaced"), "1234567").expect("Failed to write object");
+        std::fs::write(store.path().join(".cln-tmp-unshared"), "1234")
+            .expect("Failed to write in-progress object");
+
+        let stats = dedup_stats(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to get dedup stats");
+
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.stored_bytes, 15);
+        assert_eq!(stats.by_checkouts.get(&0), Some(&(2, 10)));
+    }
+
+    #[tokio::test]
+    async fn test_store_version() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        let version_path = store.path().join(STORE_VERSION_FILE);
+
+        ensure_cln_store_path(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to ensure a new store");
+        assert_eq!(
+            std::fs::read_to_string(&version_path).expect("Failed to read store version"),
+            format!("{STORE_VERSION}\n")
+        );
+
+        // A store written before versions were recorded, with an older tree listing and an empty blob that looks like one.
+        std::fs::remove_file(&version_path).expect("Failed to remove store version");
+        let listing = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let empty_blob = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        std::fs::write(
+            store.path().join(listing),
+            "100644 blob e69de29bb2d1d6434b8b29ae775ad8c2e48c5391\tempty.txt\n",
+        )
+        .expect("Failed to write tree listing");
+        std::fs::write(store.path().join(empty_blob), "").expect("Failed to write blob");
+        ensure_cln_store_path(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to migrate an older store");
+        assert!(!store.path().join(listing).exists());
+        assert!(store.path().join(empty_blob).exists());
+        assert_eq!(
+            read_store_version(store.path())
+                .await
+                .expect("Failed to read store version"),
+            Some(STORE_VERSION)
+        );
+        assert_eq!(
+            migrate_store(Some(store.path().to_path_buf()))
+                .await
+                .expect("Failed to migrate a current store"),
+            0
+        );
+
+        std::fs::write(&version_path, format!("{}\n", STORE_VERSION + 1))
+            .expect("Failed to write store version");
+        for result in [
+            ensure_cln_store_path(Some(store.path().to_path_buf()))
+                .await
+                .map(|_| ()),
+            migrate_store(Some(store.path().to_path_buf()))
+                .await
+                .map(|_| ()),
+        ] {
+            assert!(
+                matches!(result, Err(Error::UnsupportedStoreError { .. })),
+                "{result:?}"
+            );
+        }
+
+        std::fs::write(&version_path, "latest\n").expect("Failed to write store version");
+        assert!(matches!(
+            ensure_cln_store_path(Some(store.path().to_path_buf())).await,
+            Err(Error::InvalidStoreVersionError(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+
+        let blob = "1d6a3d1b4e0e31cd4e37e4a4b3d1c3cbd1b0a9f5";
+        std::fs::write(store.path().join(blob), "tampered\n").expect("Failed to write object");
+        std::fs::write(
+            store
+                .path()
+                .join("4b825dc642cb6eb9a060e54bf8d69288fbee4904"),
+            format_tree_listing(
+                "100644 blob 1d6a3d1b4e0e31cd4e37e4a4b3d1c3cbd1b0a9f5\tREADME.md\n",
+            ),
+        )
+        .expect("Failed to write tree listing");
+
+        let (checked, corrupt) = verify_store(Some(store.path().to_path_buf()), 64)
+            .await
+            .expect("Failed to verify store");
+
+        assert_eq!(checked, 2);
+        assert_eq!(corrupt, vec![blob.to_string()]);
+
+        // Objects in namespaces are checked, while files still being written aren't.
+        let namespace = store.path().join(NAMESPACES_DIR).join("0123456789abcdef");
+        std::fs::create_dir_all(&namespace).expect("Failed to create namespace");
+        std::fs::write(namespace.join(blob), "tampered\n").expect("Failed to write object");
+        std::fs::write(store.path().join(format!(".cln-tmp-{blob}")), "partial")
+            .expect("Failed to write in-progress object");
+
+        let (checked, corrupt) = verify_store(Some(store.path().to_path_buf()), 64)
+            .await
+            .expect("Failed to verify store");
+
+        assert_eq!(checked, 3);
+        assert_eq!(corrupt, vec![blob.to_string(), blob.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_seed_from_reference_store() {
+        let _lock = STORE_TEST_LOCK.lock().await;
+
+        let store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create store tempdir");
+        let empty_store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create reference store tempdir");
+        let reference_store = Builder::new()
+            .prefix("cln-store")
+            .tempdir()
+            .expect("Failed to create reference store tempdir");
+        std::fs::write(reference_store.path().join("object"), "object")
+            .expect("Failed to write object");
+
+        ensure_cln_store_path(Some(store.path().to_path_buf()))
+            .await
+            .expect("Failed to ensure cln-store path");
+        let reference_stores = [
+            empty_store.path().to_path_buf(),
+            reference_store.path().to_path_buf(),
+        ];
+
+        assert!(
+            is_content_stored_or_seeded(store.path(), "object", &reference_stores)
+                .await
+                .expect("Failed to seed object")
+        );
+        assert!(store.path().join("object").exists());
+        assert!(
+            !is_content_stored_or_seeded(store.path(), "missing", &reference_stores)
+                .await
+                .expect("Failed to check missing object")
+        );
+    }
 }